@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE users
+    DROP CONSTRAINT users_phone_number_e164;
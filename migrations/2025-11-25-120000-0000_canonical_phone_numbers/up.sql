@@ -0,0 +1,41 @@
+-- Your SQL goes here
+-- Numbers are now stored in E.164 only; strip the separators older rows may still contain.
+-- Two spellings of one number would collide on the UNIQUE constraint, and numbers that still
+-- aren't E.164 would fail the CHECK constraint, so refuse to migrate until those accounts have
+-- been merged or fixed by hand rather than aborting halfway with a constraint error.
+DO $$
+DECLARE
+    duplicates TEXT;
+    invalid TEXT;
+BEGIN
+    SELECT string_agg(canonical, ', ') INTO duplicates
+        FROM (
+            SELECT regexp_replace(phone_number, '[^0-9+]', '', 'g') AS canonical
+                FROM users
+                GROUP BY 1
+                HAVING count(*) > 1
+        ) d;
+    IF duplicates IS NOT NULL THEN
+        RAISE EXCEPTION 'Several users share a phone number once separators are removed: %', duplicates
+            USING HINT = 'Merge or delete the duplicate accounts, then run the migration again.';
+    END IF;
+
+    SELECT string_agg(id::TEXT || ' (' || phone_number || ')', ', ') INTO invalid
+        FROM users
+        WHERE regexp_replace(phone_number, '[^0-9+]', '', 'g') !~ '^\+[1-9][0-9]{6,14}$';
+    IF invalid IS NOT NULL THEN
+        RAISE EXCEPTION 'Users with a phone number that is not E.164: %', invalid
+            USING HINT = 'Fix or delete these accounts, then run the migration again.';
+    END IF;
+END $$;
+
+UPDATE users
+    SET phone_number = regexp_replace(phone_number, '[^0-9+]', '', 'g')
+    WHERE phone_number ~ '[^0-9+]';
+
+-- Pending codes are short-lived, simply drop the ones keyed by a non-canonical number.
+DELETE FROM verification_codes
+    WHERE phone_number !~ '^\+[1-9][0-9]{6,14}$';
+
+ALTER TABLE users
+    ADD CONSTRAINT users_phone_number_e164 CHECK (phone_number ~ '^\+[1-9][0-9]{6,14}$');
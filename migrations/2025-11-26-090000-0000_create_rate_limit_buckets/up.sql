@@ -0,0 +1,8 @@
+-- Your SQL goes here
+CREATE TABLE rate_limit_buckets (
+    key TEXT PRIMARY KEY,
+    tokens DOUBLE PRECISION NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX ON rate_limit_buckets (updated_at);
@@ -0,0 +1,14 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE messages
+    DROP CONSTRAINT messages_sender_user_id_fkey,
+    DROP CONSTRAINT messages_sender_device_id_fkey,
+    DROP CONSTRAINT messages_recipient_user_id_fkey,
+    DROP CONSTRAINT messages_recipient_device_id_fkey,
+    ADD CONSTRAINT messages_sender_user_id_fkey
+        FOREIGN KEY (sender_user_id) REFERENCES users(id),
+    ADD CONSTRAINT messages_sender_device_id_fkey
+        FOREIGN KEY (sender_device_id) REFERENCES devices(id),
+    ADD CONSTRAINT messages_recipient_user_id_fkey
+        FOREIGN KEY (recipient_user_id) REFERENCES users(id),
+    ADD CONSTRAINT messages_recipient_device_id_fkey
+        FOREIGN KEY (recipient_device_id) REFERENCES devices(id);
@@ -0,0 +1,15 @@
+-- Your SQL goes here
+-- Queued messages go away with the account (or device) they were sent to or by.
+ALTER TABLE messages
+    DROP CONSTRAINT messages_sender_user_id_fkey,
+    DROP CONSTRAINT messages_sender_device_id_fkey,
+    DROP CONSTRAINT messages_recipient_user_id_fkey,
+    DROP CONSTRAINT messages_recipient_device_id_fkey,
+    ADD CONSTRAINT messages_sender_user_id_fkey
+        FOREIGN KEY (sender_user_id) REFERENCES users(id) ON DELETE CASCADE,
+    ADD CONSTRAINT messages_sender_device_id_fkey
+        FOREIGN KEY (sender_device_id) REFERENCES devices(id) ON DELETE CASCADE,
+    ADD CONSTRAINT messages_recipient_user_id_fkey
+        FOREIGN KEY (recipient_user_id) REFERENCES users(id) ON DELETE CASCADE,
+    ADD CONSTRAINT messages_recipient_device_id_fkey
+        FOREIGN KEY (recipient_device_id) REFERENCES devices(id) ON DELETE CASCADE;
@@ -0,0 +1,20 @@
+-- Your SQL goes here
+-- Profile fields encrypted with the owner's profile key; the server only sees ciphertext.
+CREATE TABLE profiles (
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    -- Derived by the client from the profile key, changes whenever the key is rotated
+    version VARCHAR(64) NOT NULL,
+
+    name BYTEA NOT NULL,
+    about BYTEA,
+    -- SHA-256 of the encrypted avatar blob
+    avatar_hash VARCHAR(64),
+
+    -- SHA-256 of the access key derived from the profile key
+    access_key_hash BYTEA NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT now(),
+
+    PRIMARY KEY (user_id, version)
+);
+
+CREATE INDEX ON profiles (avatar_hash);
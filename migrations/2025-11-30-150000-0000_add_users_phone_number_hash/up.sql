@@ -0,0 +1,7 @@
+-- Your SQL goes here
+-- First 10 bytes of SHA-256(E.164 number), matched against the hashes sent for contact discovery.
+ALTER TABLE users
+    ADD COLUMN phone_number_hash BYTEA
+        GENERATED ALWAYS AS (substring(sha256(phone_number::bytea) FROM 1 FOR 10)) STORED;
+
+CREATE INDEX ON users (phone_number_hash);
@@ -0,0 +1,14 @@
+-- Your SQL goes here
+CREATE TABLE usernames (
+    -- HMAC-SHA256 of the canonical username, so the table can't be enumerated without the server key
+    username_hash BYTEA PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    -- Reserved usernames expire at reserved_until unless confirmed
+    confirmed BOOLEAN NOT NULL DEFAULT FALSE,
+    reserved_until TIMESTAMPTZ,
+    created_at TIMESTAMPTZ DEFAULT now()
+);
+
+-- At most one confirmed username and one pending reservation per user
+CREATE UNIQUE INDEX usernames_confirmed_user_id ON usernames (user_id) WHERE confirmed;
+CREATE UNIQUE INDEX usernames_reserved_user_id ON usernames (user_id) WHERE NOT confirmed;
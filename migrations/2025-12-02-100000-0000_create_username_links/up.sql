@@ -0,0 +1,9 @@
+-- Your SQL goes here
+CREATE TABLE username_links (
+    user_id UUID PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    -- Public part of the link; replaced when the link is reset
+    handle UUID UNIQUE NOT NULL,
+    -- Username encrypted with a key only present in the link itself
+    encrypted_username BYTEA NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT now()
+);
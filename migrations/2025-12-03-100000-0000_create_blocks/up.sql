@@ -0,0 +1,8 @@
+-- Your SQL goes here
+CREATE TABLE blocks (
+    blocker_user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    blocked_user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    created_at TIMESTAMPTZ DEFAULT now(),
+
+    PRIMARY KEY (blocker_user_id, blocked_user_id)
+);
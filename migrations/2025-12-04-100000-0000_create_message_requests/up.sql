@@ -0,0 +1,19 @@
+-- Your SQL goes here
+CREATE TABLE accepted_conversations (
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    peer_user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    created_at TIMESTAMPTZ DEFAULT now(),
+
+    PRIMARY KEY (user_id, peer_user_id)
+);
+
+ALTER TABLE messages ADD COLUMN is_request BOOLEAN NOT NULL DEFAULT false;
+
+-- Conversations that already exchanged messages stay in the inbox.
+INSERT INTO accepted_conversations (user_id, peer_user_id)
+SELECT DISTINCT recipient_user_id, sender_user_id
+FROM messages
+WHERE recipient_user_id IS NOT NULL
+  AND sender_user_id IS NOT NULL
+  AND recipient_user_id <> sender_user_id
+ON CONFLICT DO NOTHING;
@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE reports;
+
+ALTER TABLE messages
+    DROP COLUMN franking_commitment,
+    DROP COLUMN franking_tag;
@@ -0,0 +1,18 @@
+-- Your SQL goes here
+ALTER TABLE messages
+    ADD COLUMN franking_commitment BYTEA,
+    ADD COLUMN franking_tag BYTEA;
+
+CREATE TABLE reports (
+    id BIGSERIAL PRIMARY KEY,
+    -- Reports outlive both accounts, for moderation.
+    reporter_user_id UUID REFERENCES users(id) ON DELETE SET NULL,
+    reported_user_id UUID REFERENCES users(id) ON DELETE SET NULL,
+    plaintext BYTEA NOT NULL,
+    reason TEXT,
+    message_sent_at TIMESTAMPTZ NOT NULL,
+    status TEXT NOT NULL DEFAULT 'pending',
+    created_at TIMESTAMPTZ DEFAULT now()
+);
+
+CREATE INDEX reports_status_created_at_idx ON reports (status, created_at);
@@ -0,0 +1,21 @@
+-- Your SQL goes here
+CREATE TABLE groups (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    -- Group title, avatar, keys... encrypted by the members; the server only sees ciphertext.
+    state BYTEA NOT NULL,
+    -- Bumped on every change, compared-and-swapped by clients
+    version BIGINT NOT NULL DEFAULT 1,
+    created_at TIMESTAMPTZ DEFAULT now(),
+    updated_at TIMESTAMPTZ DEFAULT now()
+);
+
+CREATE TABLE group_members (
+    group_id UUID NOT NULL REFERENCES groups(id) ON DELETE CASCADE,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    role TEXT NOT NULL DEFAULT 'member' CHECK (role IN ('admin', 'member')),
+    joined_at TIMESTAMPTZ DEFAULT now(),
+
+    PRIMARY KEY (group_id, user_id)
+);
+
+CREATE INDEX ON group_members (user_id);
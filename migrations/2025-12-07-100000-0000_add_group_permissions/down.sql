@@ -0,0 +1,9 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE group_join_requests;
+
+ALTER TABLE groups
+    DROP COLUMN add_members_access,
+    DROP COLUMN edit_state_access,
+    DROP COLUMN send_access,
+    DROP COLUMN invite_link_token_hash,
+    DROP COLUMN invite_link_requires_approval;
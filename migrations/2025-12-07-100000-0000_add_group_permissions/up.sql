@@ -0,0 +1,17 @@
+-- Your SQL goes here
+-- Minimum role required for each action
+ALTER TABLE groups
+    ADD COLUMN add_members_access TEXT NOT NULL DEFAULT 'member' CHECK (add_members_access IN ('admin', 'member')),
+    ADD COLUMN edit_state_access TEXT NOT NULL DEFAULT 'member' CHECK (edit_state_access IN ('admin', 'member')),
+    ADD COLUMN send_access TEXT NOT NULL DEFAULT 'member' CHECK (send_access IN ('admin', 'member')),
+    -- SHA-256 of the current invite link token, NULL when there is no link
+    ADD COLUMN invite_link_token_hash BYTEA UNIQUE,
+    ADD COLUMN invite_link_requires_approval BOOLEAN NOT NULL DEFAULT false;
+
+CREATE TABLE group_join_requests (
+    group_id UUID NOT NULL REFERENCES groups(id) ON DELETE CASCADE,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    created_at TIMESTAMPTZ DEFAULT now(),
+
+    PRIMARY KEY (group_id, user_id)
+);
@@ -0,0 +1,14 @@
+-- Your SQL goes here
+-- Sender-key ciphertexts of group messages, stored once and referenced by the per-device messages
+CREATE TABLE group_payloads (
+    id BIGSERIAL PRIMARY KEY,
+    group_id UUID NOT NULL REFERENCES groups(id) ON DELETE CASCADE,
+    sender_user_id UUID REFERENCES users(id) ON DELETE CASCADE,
+    sender_device_id UUID REFERENCES devices(id) ON DELETE SET NULL,
+    ciphertext BYTEA NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT now()
+);
+
+ALTER TABLE messages ADD COLUMN group_payload_id BIGINT REFERENCES group_payloads(id) ON DELETE CASCADE;
+
+CREATE INDEX ON messages (group_payload_id);
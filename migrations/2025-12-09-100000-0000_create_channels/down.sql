@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE channel_posts;
+DROP TABLE channel_subscribers;
+DROP TABLE channel_admins;
+DROP TABLE channels;
@@ -0,0 +1,40 @@
+-- Your SQL goes here
+CREATE TABLE channels (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    owner_user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    -- Title, description... encrypted with the channel key; the server only sees ciphertext.
+    state BYTEA NOT NULL,
+    -- Posts older than this are pruned
+    retention_days INT NOT NULL DEFAULT 30 CHECK (retention_days > 0),
+    created_at TIMESTAMPTZ DEFAULT now()
+);
+
+CREATE TABLE channel_admins (
+    channel_id UUID NOT NULL REFERENCES channels(id) ON DELETE CASCADE,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    created_at TIMESTAMPTZ DEFAULT now(),
+
+    PRIMARY KEY (channel_id, user_id)
+);
+
+CREATE TABLE channel_subscribers (
+    channel_id UUID NOT NULL REFERENCES channels(id) ON DELETE CASCADE,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    subscribed_at TIMESTAMPTZ DEFAULT now(),
+
+    PRIMARY KEY (channel_id, user_id)
+);
+
+CREATE INDEX ON channel_subscribers (user_id);
+
+-- Stored once per post, fetched by subscribers by cursor (the post id)
+CREATE TABLE channel_posts (
+    id BIGSERIAL PRIMARY KEY,
+    channel_id UUID NOT NULL REFERENCES channels(id) ON DELETE CASCADE,
+    author_user_id UUID REFERENCES users(id) ON DELETE SET NULL,
+    ciphertext BYTEA NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX ON channel_posts (channel_id, id);
+CREATE INDEX ON channel_posts (created_at);
@@ -0,0 +1,12 @@
+-- Your SQL goes here
+-- Encrypted attachment blobs, stored under `attachments/<id>` in the blob store
+CREATE TABLE attachments (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    uploader_user_id UUID REFERENCES users(id) ON DELETE SET NULL,
+    size BIGINT NOT NULL,
+    -- SHA-256 of the download token handed to the uploader
+    download_token_hash BYTEA NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT now()
+);
+
+CREATE INDEX ON attachments (uploader_user_id);
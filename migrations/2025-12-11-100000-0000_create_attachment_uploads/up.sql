@@ -0,0 +1,14 @@
+-- Your SQL goes here
+-- Resumable uploads in progress, staged on local disk until finalized into `attachments`
+CREATE TABLE attachment_uploads (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    -- Declared total size
+    size BIGINT NOT NULL CHECK (size > 0),
+    -- Bytes received so far, where the next chunk starts
+    received BIGINT NOT NULL DEFAULT 0,
+    expires_at TIMESTAMPTZ NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT now()
+);
+
+CREATE INDEX ON attachment_uploads (expires_at);
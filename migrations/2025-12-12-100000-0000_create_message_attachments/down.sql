@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX attachments_created_at_idx;
+ALTER TABLE attachments DROP COLUMN referenced;
+DROP TABLE message_attachments;
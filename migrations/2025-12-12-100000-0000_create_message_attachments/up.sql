@@ -0,0 +1,15 @@
+-- Your SQL goes here
+-- Which messages carry which attachment, so attachments can go once every message is acknowledged
+CREATE TABLE message_attachments (
+    message_id BIGINT NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+    attachment_id UUID NOT NULL REFERENCES attachments(id) ON DELETE CASCADE,
+
+    PRIMARY KEY (message_id, attachment_id)
+);
+
+CREATE INDEX ON message_attachments (attachment_id);
+
+-- Set once the attachment was sent in a message
+ALTER TABLE attachments ADD COLUMN referenced BOOLEAN NOT NULL DEFAULT false;
+
+CREATE INDEX ON attachments (created_at);
@@ -0,0 +1,4 @@
+-- Your SQL goes here
+-- Service the push token belongs to. Tokens registered before this column can't be routed,
+-- so they stay unused until the device registers again.
+ALTER TABLE devices ADD COLUMN push_platform TEXT CHECK (push_platform IN ('fcm', 'apns'));
@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+UPDATE devices SET push_platform = NULL, push_token = NULL
+    WHERE push_platform IN ('unifiedpush', 'webpush');
+ALTER TABLE devices DROP CONSTRAINT devices_push_platform_check;
+ALTER TABLE devices ADD CONSTRAINT devices_push_platform_check CHECK (push_platform IN ('fcm', 'apns'));
@@ -0,0 +1,5 @@
+-- Your SQL goes here
+-- unifiedpush tokens are endpoint URLs, webpush tokens JSON subscriptions.
+ALTER TABLE devices DROP CONSTRAINT devices_push_platform_check;
+ALTER TABLE devices ADD CONSTRAINT devices_push_platform_check
+    CHECK (push_platform IN ('fcm', 'apns', 'unifiedpush', 'webpush'));
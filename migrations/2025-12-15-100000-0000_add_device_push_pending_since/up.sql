@@ -0,0 +1,3 @@
+-- Your SQL goes here
+-- Set when a wake-up push is sent, cleared when the device fetches its messages.
+ALTER TABLE devices ADD COLUMN push_pending_since TIMESTAMPTZ;
@@ -0,0 +1,7 @@
+-- This file should undo anything in `up.sql`
+DELETE FROM verification_codes;
+
+ALTER TABLE verification_codes
+    DROP CONSTRAINT verification_codes_pkey,
+    DROP COLUMN purpose,
+    ADD PRIMARY KEY (phone_number);
@@ -0,0 +1,10 @@
+-- Your SQL goes here
+-- A code is only valid for the action it was requested for, so a registration code can't
+-- delete the account or move it to another number. Pending codes are short-lived, drop them.
+DELETE FROM verification_codes;
+
+ALTER TABLE verification_codes
+    ADD COLUMN purpose TEXT NOT NULL
+        CHECK (purpose IN ('register', 'delete_account', 'change_phone')),
+    DROP CONSTRAINT verification_codes_pkey,
+    ADD PRIMARY KEY (phone_number, purpose);
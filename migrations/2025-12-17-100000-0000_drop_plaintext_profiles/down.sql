@@ -0,0 +1,9 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE users
+    ADD COLUMN name TEXT NOT NULL DEFAULT 'New user',
+    ADD COLUMN avatar_hash VARCHAR(64) UNIQUE;
+
+ALTER TABLE users
+    ALTER COLUMN name DROP DEFAULT;
+
+DROP TABLE legacy_avatars;
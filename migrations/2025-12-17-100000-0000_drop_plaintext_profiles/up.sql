@@ -0,0 +1,13 @@
+-- Your SQL goes here
+-- Names and avatars now only live in the encrypted `profiles`, drop the plaintext copies.
+-- The avatar blobs they referenced are deleted by the server from this list at startup.
+CREATE TABLE legacy_avatars (
+    hash VARCHAR(64) PRIMARY KEY
+);
+
+INSERT INTO legacy_avatars (hash)
+SELECT DISTINCT avatar_hash FROM users WHERE avatar_hash IS NOT NULL;
+
+ALTER TABLE users
+    DROP COLUMN name,
+    DROP COLUMN avatar_hash;
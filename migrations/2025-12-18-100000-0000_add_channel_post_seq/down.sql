@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE channel_posts DROP CONSTRAINT channel_posts_channel_id_seq_key;
+ALTER TABLE channel_posts DROP COLUMN seq;
+ALTER TABLE channels DROP COLUMN last_post_seq;
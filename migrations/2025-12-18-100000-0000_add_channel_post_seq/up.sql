@@ -0,0 +1,19 @@
+-- Your SQL goes here
+-- Posts are paged by a per-channel sequence, assigned under the channel row lock so a post is
+-- never visible before an earlier one of the same channel commits (unlike BIGSERIAL ids).
+ALTER TABLE channels ADD COLUMN last_post_seq BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE channel_posts ADD COLUMN seq BIGINT;
+
+UPDATE channel_posts p
+SET seq = numbered.seq
+FROM (
+    SELECT id, row_number() OVER (PARTITION BY channel_id ORDER BY id) AS seq
+    FROM channel_posts
+) numbered
+WHERE p.id = numbered.id;
+
+UPDATE channels c
+SET last_post_seq = COALESCE((SELECT max(seq) FROM channel_posts p WHERE p.channel_id = c.id), 0);
+
+ALTER TABLE channel_posts ALTER COLUMN seq SET NOT NULL;
+ALTER TABLE channel_posts ADD CONSTRAINT channel_posts_channel_id_seq_key UNIQUE (channel_id, seq);
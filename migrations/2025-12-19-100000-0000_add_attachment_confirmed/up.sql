@@ -0,0 +1,6 @@
+-- Your SQL goes here
+-- Presigned uploads start unconfirmed, until the server checked the blob the client uploaded;
+-- unconfirmed attachments can't be downloaded nor sent, and are swept soon.
+ALTER TABLE attachments ADD COLUMN confirmed BOOLEAN NOT NULL DEFAULT true;
+
+CREATE INDEX ON attachments (created_at) WHERE NOT confirmed;
@@ -0,0 +1,167 @@
+use crate::schema::{attachment_uploads, attachments, message_attachments, messages};
+use crate::storage::BlobStore;
+use chrono::{Duration, Utc};
+use diesel::dsl::{exists, not, sql};
+use diesel::pg::Pg;
+use diesel::prelude::*;
+use diesel::r2d2::{ConnectionManager, Pool};
+use diesel::sql_types::{BigInt, Text};
+use uuid::Uuid;
+
+/// Attachments a single message may reference.
+pub const MAX_ATTACHMENTS_PER_MESSAGE: usize = 32;
+/// Attachments deleted per batch, so one transaction never holds the database for long.
+const SWEEP_BATCH_SIZE: i64 = 1000;
+/// Time an upload has to be confirmed before it is swept.
+const UNCONFIRMED_TTL_MINUTES: i64 = 60;
+
+pub fn attachment_key(id: Uuid) -> String {
+    format!("attachments/{id}")
+}
+
+/// Serializes quota checks of `user_id` until the end of the current transaction, so concurrent
+/// uploads can't each see room for themselves and overrun the quota together.
+pub fn lock_quota(conn: &mut PgConnection, user_id: Uuid) -> QueryResult<()> {
+    diesel::sql_query("SELECT pg_advisory_xact_lock(hashtext($1))")
+        .bind::<Text, _>(format!("storage_quota:{user_id}"))
+        .execute(conn)
+        .map(|_| ())
+}
+
+/// Bytes counted against the user's quota: their attachments, plus the full size of their
+/// uploads in progress.
+pub fn storage_used(conn: &mut PgConnection, user_id: Uuid) -> QueryResult<i64> {
+    let stored = attachments::table
+        .filter(attachments::uploader_user_id.eq(user_id))
+        .select(sql::<BigInt>("COALESCE(SUM(size), 0)::BIGINT"))
+        .first::<i64>(conn)?;
+    let uploading = attachment_uploads::table
+        .filter(attachment_uploads::user_id.eq(user_id))
+        .select(sql::<BigInt>("COALESCE(SUM(size), 0)::BIGINT"))
+        .first::<i64>(conn)?;
+
+    Ok(stored + uploading)
+}
+
+/// Whether every id in `attachment_ids` names a stored, confirmed attachment, locking them until
+/// the end of the transaction so the sweeper can't delete them before they are linked.
+pub fn lock_all(conn: &mut PgConnection, attachment_ids: &[Uuid]) -> QueryResult<bool> {
+    if attachment_ids.is_empty() {
+        return Ok(true);
+    }
+
+    let found = attachments::table
+        .filter(attachments::id.eq_any(attachment_ids))
+        .filter(attachments::confirmed.eq(true))
+        .select(attachments::id)
+        .for_share()
+        .load::<Uuid>(conn)?;
+    Ok(found.len() == attachment_ids.iter().collect::<std::collections::HashSet<_>>().len())
+}
+
+/// Records that `message_ids` carry `attachment_ids`, which must have been locked by [`lock_all`]
+/// in the same transaction.
+pub fn link_to_messages(conn: &mut PgConnection, message_ids: &[i64], attachment_ids: &[Uuid]) -> QueryResult<()> {
+    if message_ids.is_empty() || attachment_ids.is_empty() {
+        return Ok(());
+    }
+
+    let rows: Vec<_> = message_ids
+        .iter()
+        .flat_map(|message_id| attachment_ids.iter().map(move |attachment_id| (
+            message_attachments::message_id.eq(*message_id),
+            message_attachments::attachment_id.eq(*attachment_id),
+        )))
+        .collect();
+    diesel::insert_into(message_attachments::table)
+        .values(&rows)
+        .on_conflict_do_nothing()
+        .execute(conn)?;
+    diesel::update(attachments::table.filter(attachments::id.eq_any(attachment_ids)))
+        .set(attachments::referenced.eq(true))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Attachments to delete: older than `retention`, sent and no longer referenced by any
+/// unacknowledged message, or uploads left unconfirmed.
+fn expired(retention: Duration) -> attachments::BoxedQuery<'static, Pg> {
+    let pending_messages = message_attachments::table
+        .inner_join(messages::table)
+        .filter(message_attachments::attachment_id.eq(attachments::id))
+        .filter(messages::delivered_at.is_null());
+
+    attachments::table
+        .filter(
+            attachments::created_at.lt(Utc::now() - retention)
+                .or(attachments::referenced.eq(true).and(not(exists(pending_messages))))
+                .or(attachments::confirmed.eq(false)
+                    .and(attachments::created_at.lt(Utc::now() - Duration::minutes(UNCONFIRMED_TTL_MINUTES)))),
+        )
+        .into_boxed()
+}
+
+/// Returns a batch of the attachments to delete, see [`expired`].
+pub fn expired_attachments(conn: &mut PgConnection, retention: Duration) -> QueryResult<Vec<Uuid>> {
+    expired(retention)
+        .select(attachments::id)
+        .limit(SWEEP_BATCH_SIZE)
+        .load::<Uuid>(conn)
+}
+
+/// Deletes the rows of a batch of [`expired_attachments`], returning the ids of the deleted ones.
+///
+/// The candidates are locked first, skipping those a sender is linking, then deleted only if
+/// still expired: the check runs after the locks are held, so it sees every message linked before.
+pub fn delete_expired(conn: &mut PgConnection, retention: Duration) -> QueryResult<Vec<Uuid>> {
+    let candidates = expired_attachments(conn, retention)?;
+    if candidates.is_empty() {
+        return Ok(candidates);
+    }
+
+    conn.transaction(|conn| {
+        let locked = attachments::table
+            .filter(attachments::id.eq_any(&candidates))
+            .select(attachments::id)
+            .for_update()
+            .skip_locked()
+            .load::<Uuid>(conn)?;
+        diesel::delete(attachments::table
+            .filter(attachments::id.eq_any(&locked))
+            .filter(attachments::id.eq_any(expired(retention).select(attachments::id))))
+            .returning(attachments::id)
+            .get_results::<Uuid>(conn)
+    })
+}
+
+/// Deletes the rows of [`expired_attachments`], then their blobs. Returns how many were deleted.
+///
+/// The database work of each batch runs on a blocking thread, without holding a connection
+/// while blobs are deleted.
+pub async fn sweep(
+    pool: &Pool<ConnectionManager<PgConnection>>,
+    storage: &dyn BlobStore,
+    retention: Duration,
+) -> Result<usize, String> {
+    let mut deleted = 0;
+    loop {
+        let pool = pool.clone();
+        let ids = tokio::task::spawn_blocking(move || {
+            let mut conn = pool.get().map_err(|e| e.to_string())?;
+            delete_expired(&mut conn, retention).map_err(|e| e.to_string())
+        })
+            .await
+            .map_err(|e| e.to_string())??;
+
+        for id in &ids {
+            // The row is gone, so a blob that fails to delete now is only logged.
+            if let Err(e) = storage.delete(&attachment_key(*id)).await {
+                tracing::error!("Failed to delete attachment {id}: {e}");
+            }
+        }
+        deleted += ids.len();
+        if (ids.len() as i64) < SWEEP_BATCH_SIZE {
+            return Ok(deleted);
+        }
+    }
+}
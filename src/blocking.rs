@@ -0,0 +1,12 @@
+use crate::schema::blocks;
+use diesel::dsl::exists;
+use diesel::prelude::*;
+use uuid::Uuid;
+
+/// Whether `blocker` has blocked `blocked`.
+pub fn is_blocked(conn: &mut PgConnection, blocker: Uuid, blocked: Uuid) -> QueryResult<bool> {
+    diesel::select(exists(
+        blocks::table.find((blocker, blocked)),
+    ))
+        .get_result(conn)
+}
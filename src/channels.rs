@@ -0,0 +1,10 @@
+use diesel::prelude::*;
+
+/// Deletes the posts older than their channel's retention. Returns the number of deleted posts.
+pub fn prune_expired_posts(conn: &mut PgConnection) -> QueryResult<usize> {
+    diesel::sql_query(
+        "DELETE FROM channel_posts p USING channels c \
+         WHERE p.channel_id = c.id AND p.created_at < now() - make_interval(days => c.retention_days)",
+    )
+        .execute(conn)
+}
@@ -0,0 +1,69 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+use uuid::Uuid;
+
+/// Size of commitments and tags (HMAC-SHA256 outputs).
+pub const FRANKING_SIZE: usize = 32;
+
+/// Server tag binding a sender's commitment to the sender, the recipient and the time the message
+/// was queued, so only the recipient can report it.
+pub fn tag(secret: &[u8], commitment: &[u8], sender: Uuid, recipient: Uuid, timestamp_ms: i64) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(commitment);
+    mac.update(sender.as_bytes());
+    mac.update(recipient.as_bytes());
+    mac.update(&timestamp_ms.to_be_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+pub fn verify_tag(
+    secret: &[u8],
+    commitment: &[u8],
+    sender: Uuid,
+    recipient: Uuid,
+    timestamp_ms: i64,
+    tag_bytes: &[u8],
+) -> bool {
+    tag(secret, commitment, sender, recipient, timestamp_ms).ct_eq(tag_bytes).into()
+}
+
+/// Checks that `commitment` is `HMAC-SHA256(franking_key, plaintext)`, as computed by the sender.
+pub fn verify_commitment(franking_key: &[u8], plaintext: &[u8], commitment: &[u8]) -> bool {
+    let mut mac = Hmac::<Sha256>::new_from_slice(franking_key).expect("HMAC accepts any key length");
+    mac.update(plaintext);
+    mac.verify_slice(commitment).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"franking secret";
+
+    #[test]
+    fn tag_binds_sender_recipient_and_time() {
+        let (sender, recipient, other) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let commitment = [1u8; FRANKING_SIZE];
+        let tag = tag(SECRET, &commitment, sender, recipient, 1000);
+
+        assert_eq!(tag.len(), FRANKING_SIZE);
+        assert!(verify_tag(SECRET, &commitment, sender, recipient, 1000, &tag));
+        assert!(!verify_tag(SECRET, &commitment, sender, other, 1000, &tag));
+        assert!(!verify_tag(SECRET, &commitment, other, recipient, 1000, &tag));
+        assert!(!verify_tag(SECRET, &commitment, sender, recipient, 1001, &tag));
+        assert!(!verify_tag(SECRET, &[2u8; FRANKING_SIZE], sender, recipient, 1000, &tag));
+        assert!(!verify_tag(b"other secret", &commitment, sender, recipient, 1000, &tag));
+    }
+
+    #[test]
+    fn verifies_commitments() {
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"franking key").unwrap();
+        mac.update(b"hello");
+        let commitment = mac.finalize().into_bytes();
+
+        assert!(verify_commitment(b"franking key", b"hello", &commitment));
+        assert!(!verify_commitment(b"franking key", b"hell0", &commitment));
+        assert!(!verify_commitment(b"other key", b"hello", &commitment));
+    }
+}
@@ -0,0 +1,13 @@
+use diesel::prelude::*;
+
+/// Deletes the sender-key payloads every recipient device has acknowledged, along with their
+/// delivered fan-out messages. Returns the number of deleted payloads.
+pub fn prune_delivered_payloads(conn: &mut PgConnection) -> QueryResult<usize> {
+    // A payload and its fan-out are inserted in one transaction, so a payload is never seen
+    // here before its messages are.
+    diesel::sql_query(
+        "DELETE FROM group_payloads p \
+         WHERE NOT EXISTS (SELECT 1 FROM messages m WHERE m.group_payload_id = p.id AND m.delivered_at IS NULL)",
+    )
+        .execute(conn)
+}
@@ -1,2 +1,16 @@
+pub mod attachments;
+pub mod blocking;
+pub mod channels;
+pub mod franking;
+pub mod groups;
 pub mod models;
+pub mod otp;
+pub mod phone;
+pub mod pow;
+pub mod push;
+pub mod rate_limit;
 pub mod schema;
+pub mod storage;
+pub mod system_messages;
+pub mod uploads;
+pub mod username;
@@ -1,15 +1,31 @@
 mod routes;
+#[cfg(test)]
+mod tests;
+mod verification;
 
 use crate::routes::v1::register::Claims;
 use axum::extract::FromRequestParts;
+use axum::middleware::from_fn_with_state;
 use axum::http::request::Parts;
 use axum::http::StatusCode;
-use axum::{routing::{get, post}, Extension, Router};
-use diesel::{r2d2::{self, ConnectionManager}, PgConnection};
+use axum::extract::DefaultBodyLimit;
+use axum::{routing::{delete, get, post, put}, Extension, Router};
+use diesel::prelude::*;
+use diesel::r2d2::{self, ConnectionManager};
 use dotenvy::dotenv;
+use e2ee_back::{attachments, channels, groups};
+use e2ee_back::otp::{self, OtpSender};
+use e2ee_back::pow::ProofOfWork;
+use e2ee_back::push::{self, PushDispatcher};
+use e2ee_back::storage::{self, BlobStore};
+use e2ee_back::uploads::UploadStaging;
+use e2ee_back::schema::{devices, users};
+use e2ee_back::rate_limit::{self, rate_limit, Limit, RateLimitStore, RateLimiter, RouteLimits};
+use std::net::SocketAddr;
 use jsonwebtoken::{decode, DecodingKey, Validation};
+use std::sync::Arc;
+use std::time::Duration;
 use tower_http::trace::TraceLayer;
-use tracing_subscriber;
 use uuid::Uuid;
 
 type DbPool = r2d2::Pool<ConnectionManager<PgConnection>>;
@@ -18,16 +34,41 @@ type DbPool = r2d2::Pool<ConnectionManager<PgConnection>>;
 pub struct AppState {
     pub db: DbPool,
     pub jwt_secret: String,
+    pub otp_sender: Arc<dyn OtpSender>,
+    pub rate_limits: Arc<dyn RateLimitStore>,
+    pub pow: Arc<ProofOfWork>,
+    pub storage: Arc<dyn BlobStore>,
+    /// Key of the HMAC stored in place of usernames.
+    pub username_secret: Vec<u8>,
+    /// Key of the HMAC binding message franking commitments to their sender.
+    pub franking_secret: Vec<u8>,
+    /// Number of phone number hashes an account may look up per day.
+    pub discovery_quota: u32,
+    /// Number of reverse proxies appending to `X-Forwarded-For` in front of the server.
+    pub proxy_hops: usize,
+    pub uploads: Arc<UploadStaging>,
+    /// Largest attachment accepted, in bytes.
+    pub attachment_max_size: usize,
+    /// Bytes of attachments each user may store.
+    pub storage_quota: i64,
+    pub push: Arc<PushDispatcher>,
+}
+
+/// Reads a secret that must be set and must not be shared with any other use.
+fn required_secret(name: &str) -> Vec<u8> {
+    std::env::var(name)
+        .ok()
+        .filter(|s| !s.is_empty())
+        .unwrap_or_else(|| panic!("{name} must be set"))
+        .into_bytes()
 }
 
 fn establish_connection() -> DbPool {
     let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
     let manager = ConnectionManager::<PgConnection>::new(database_url);
-    let pool = r2d2::Pool::builder()
+    r2d2::Pool::builder()
         .build(manager)
-        .expect("Failed to create pool.");
-
-    pool
+        .expect("Failed to create pool.")
 }
 
 #[tokio::main]
@@ -40,30 +81,291 @@ async fn main() {
     let pool = establish_connection();
     let jwt_secret = std::env::var("JWT_SECRET")
         .expect("JWT_SECRET must be set");
+    let rate_limits = rate_limit::store_from_env(pool.clone());
+    let proxy_hops = match std::env::var("RATE_LIMIT_PROXY_HOPS") {
+        Ok(v) => v.parse().unwrap_or_else(|_| panic!("Invalid RATE_LIMIT_PROXY_HOPS: {v}")),
+        Err(_) => 0,
+    };
     let state = AppState {
+        push: push::from_env(pool.clone()),
         db: pool,
+        pow: Arc::new(ProofOfWork::from_env()),
+        username_secret: required_secret("USERNAME_SECRET"),
+        franking_secret: required_secret("FRANKING_SECRET"),
         jwt_secret,
+        otp_sender: otp::from_env(),
+        rate_limits: rate_limits.clone(),
+        storage: storage::from_env(),
+        discovery_quota: match std::env::var("DISCOVERY_QUOTA") {
+            Ok(v) => v.parse().ok().filter(|quota| *quota > 0)
+                .unwrap_or_else(|| panic!("Invalid DISCOVERY_QUOTA: {v}")),
+            Err(_) => 5000,
+        },
+        proxy_hops,
+        uploads: Arc::new(UploadStaging::from_env()),
+        attachment_max_size: std::env::var("ATTACHMENT_MAX_SIZE")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(100 * 1024 * 1024),
+        storage_quota: std::env::var("STORAGE_QUOTA")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(1024 * 1024 * 1024),
     };
-    let app = Router::new()
-        .route("/v1/register", post(routes::v1::register::register_phone))
-        .route("/v1/register/confirm", post(routes::v1::register::register_confirm))
+
+    tokio::spawn({
+        let rate_limits = rate_limits.clone();
+        async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(3600));
+            loop {
+                interval.tick().await;
+                rate_limits.purge(Duration::from_secs(24 * 3600)).await;
+            }
+        }
+    });
+
+    tokio::spawn({
+        let pool = state.db.clone();
+        let uploads = state.uploads.clone();
+        async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(3600));
+            loop {
+                interval.tick().await;
+                let (pool, uploads) = (pool.clone(), uploads.clone());
+                let cleanup = tokio::task::spawn_blocking(move || {
+                    let mut conn = match pool.get() {
+                        Ok(conn) => conn,
+                        Err(e) => {
+                            tracing::error!("Failed to get a database connection for cleanup: {e}");
+                            return;
+                        }
+                    };
+                    if let Err(e) = channels::prune_expired_posts(&mut conn) {
+                        tracing::error!("Failed to prune channel posts: {e}");
+                    }
+                    if let Err(e) = uploads.purge_expired(&mut conn) {
+                        tracing::error!("Failed to purge expired uploads: {e}");
+                    }
+                    if let Err(e) = groups::prune_delivered_payloads(&mut conn) {
+                        tracing::error!("Failed to prune group payloads: {e}");
+                    }
+                });
+                // A failed run is retried on the next tick rather than stopping the loop.
+                if let Err(e) = cleanup.await {
+                    tracing::error!("Cleanup task failed: {e}");
+                }
+            }
+        }
+    });
+
+    tokio::spawn({
+        let pool = state.db.clone();
+        let storage = state.storage.clone();
+        async move {
+            match routes::v1::profile::delete_legacy_avatars(&pool, storage.as_ref()).await {
+                Ok(0) => {}
+                Ok(n) => tracing::info!("Deleted {n} plaintext avatars"),
+                Err(e) => tracing::error!("Failed to delete plaintext avatars, retrying on next start: {e}"),
+            }
+        }
+    });
+
+    tokio::spawn({
+        let pool = state.db.clone();
+        let storage = state.storage.clone();
+        let retention = chrono::Duration::days(std::env::var("ATTACHMENT_RETENTION_DAYS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(30));
+        async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(3600));
+            loop {
+                interval.tick().await;
+                match attachments::sweep(&pool, storage.as_ref(), retention).await {
+                    Ok(0) => {}
+                    Ok(n) => tracing::info!("Deleted {n} expired attachments"),
+                    Err(e) => tracing::error!("Failed to sweep attachments: {e}"),
+                }
+            }
+        }
+    });
+
+    let app = app(state);
+    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
+    println!("[Server] Started on http://0.0.0.0:3000");
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await.unwrap();
+}
+
+/// Builds the routes, with the rate limiters configured from the environment.
+fn app(state: AppState) -> Router {
+    let limiter = |route: &'static str, defaults: RouteLimits| RateLimiter {
+        store: state.rate_limits.clone(),
+        route,
+        limits: RouteLimits::from_env(route, defaults),
+        prefix_length: std::env::var("RATE_LIMIT_PREFIX_LENGTH")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(6),
+        proxy_hops: state.proxy_hops,
+    };
+    let register_limiter = limiter("register", RouteLimits {
+        ip: Some(Limit::new(10, 3600)),
+        phone: Some(Limit::new(3, 900)),
+        prefix: Some(Limit::new(100, 3600)),
+    });
+    let confirm_limiter = limiter("register_confirm", RouteLimits {
+        ip: Some(Limit::new(30, 3600)),
+        phone: Some(Limit::new(10, 3600)),
+        prefix: None,
+    });
+    let change_phone_limiter = limiter("change_phone", RouteLimits {
+        ip: Some(Limit::new(10, 3600)),
+        phone: Some(Limit::new(3, 900)),
+        prefix: Some(Limit::new(100, 3600)),
+    });
+    let change_phone_confirm_limiter = limiter("change_phone_confirm", RouteLimits {
+        ip: Some(Limit::new(30, 3600)),
+        phone: Some(Limit::new(10, 3600)),
+        prefix: None,
+    });
+
+    let avatar_max_size = std::env::var("AVATAR_MAX_SIZE")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(5 * 1024 * 1024);
+
+    Router::new()
+        .route("/v1/register", post(routes::v1::register::register_phone)
+            .layer(from_fn_with_state(register_limiter, rate_limit)))
+        .route("/v1/register/challenge", post(routes::v1::register::register_challenge))
+        .route("/v1/register/confirm", post(routes::v1::register::register_confirm)
+            .layer(from_fn_with_state(confirm_limiter, rate_limit)))
+        .route("/v1/account", delete(routes::v1::account::delete_account))
+        .route("/v1/account/delete", post(routes::v1::account::request_account_deletion))
+        .route("/v1/account/phone", post(routes::v1::account::request_phone_change)
+            .layer(from_fn_with_state(change_phone_limiter, rate_limit)))
+        .route("/v1/account/phone/confirm", post(routes::v1::account::confirm_phone_change)
+            .layer(from_fn_with_state(change_phone_confirm_limiter, rate_limit)))
+        .route("/v1/contacts/discover", post(routes::v1::contacts::discover_contacts))
         .route("/v1/keys/upload", post(routes::v1::keys::upload_keys))
+        .route("/v1/keys/{user_id}", get(routes::v1::keys::get_prekey_bundle))
+        .route("/v1/blocks", get(routes::v1::blocks::get_blocks))
+        .route("/v1/blocks/{user_id}", put(routes::v1::blocks::block_user)
+            .delete(routes::v1::blocks::unblock_user))
         .route("/v1/devices", get(routes::v1::devices::get_devices))
-        .route("/v1/messages", get(routes::v1::messages::get_messages))
+        .route("/v1/devices/push", put(routes::v1::devices::set_push_token)
+            .delete(routes::v1::devices::delete_push_token))
+        .route("/v1/devices/push/vapid", get(routes::v1::devices::get_vapid_public_key))
+        .route("/v1/profile/versions/{version}", put(routes::v1::profile::set_encrypted_profile)
+            .layer(DefaultBodyLimit::max(avatar_max_size * 2)))
+        .route("/v1/profile/{user_id}/{version}", get(routes::v1::profile::get_encrypted_profile))
+        .route("/v1/profile/{user_id}/{version}/avatar", get(routes::v1::profile::get_encrypted_profile_avatar))
+        .route("/v1/attachments", post(routes::v1::attachments::upload_attachment)
+            .layer(DefaultBodyLimit::max(state.attachment_max_size)))
+        .route("/v1/attachments/{attachment_id}", get(routes::v1::attachments::get_attachment))
+        .route("/v1/storage/usage", get(routes::v1::attachments::get_storage_usage))
+        .route("/v1/attachments/presigned", post(routes::v1::attachments::create_presigned_upload))
+        .route("/v1/attachments/{attachment_id}/confirm", post(routes::v1::attachments::confirm_presigned_upload))
+        .route("/v1/attachments/uploads", post(routes::v1::attachments::create_upload))
+        .route("/v1/attachments/uploads/{upload_id}", get(routes::v1::attachments::get_upload)
+            .patch(routes::v1::attachments::append_upload_chunk)
+            .delete(routes::v1::attachments::delete_upload)
+            .layer(DefaultBodyLimit::max(state.attachment_max_size)))
+        .route("/v1/attachments/uploads/{upload_id}/finalize", post(routes::v1::attachments::finalize_upload))
+        .route("/v1/username", delete(routes::v1::username::delete_username))
+        .route("/v1/username/reserve", post(routes::v1::username::reserve_username))
+        .route("/v1/username/confirm", post(routes::v1::username::confirm_username))
+        .route("/v1/username/lookup/{username}", get(routes::v1::username::lookup_username))
+        .route("/v1/username/link", put(routes::v1::username::set_username_link)
+            .delete(routes::v1::username::delete_username_link))
+        .route("/v1/username/link/{handle}", get(routes::v1::username::get_username_link))
+        .route("/v1/messages", get(routes::v1::messages::get_messages)
+            .post(routes::v1::messages::send_messages))
+        .route("/v1/messages/ack", post(routes::v1::messages::ack_messages))
+        .route("/v1/messages/requests", get(routes::v1::messages::get_message_requests))
+        .route("/v1/messages/requests/accept", post(routes::v1::messages::accept_message_requests))
+        .route("/v1/messages/requests/reject", post(routes::v1::messages::reject_message_requests))
+        .route("/v1/reports", post(routes::v1::reports::report_message))
+        .route("/v1/channels", get(routes::v1::channels::get_subscriptions)
+            .post(routes::v1::channels::create_channel))
+        .route("/v1/channels/{channel_id}", get(routes::v1::channels::get_channel)
+            .patch(routes::v1::channels::update_channel)
+            .delete(routes::v1::channels::delete_channel))
+        .route("/v1/channels/{channel_id}/admins/{user_id}", put(routes::v1::channels::add_channel_admin)
+            .delete(routes::v1::channels::remove_channel_admin))
+        .route("/v1/channels/{channel_id}/subscription", put(routes::v1::channels::subscribe)
+            .delete(routes::v1::channels::unsubscribe))
+        .route("/v1/channels/{channel_id}/posts", get(routes::v1::channels::get_posts)
+            .post(routes::v1::channels::create_post))
+        .route("/v1/channels/{channel_id}/posts/{post_id}", delete(routes::v1::channels::delete_post))
+        .route("/v1/groups", post(routes::v1::groups::create_group))
+        .route("/v1/groups/{group_id}", get(routes::v1::groups::get_group))
+        .route("/v1/groups/{group_id}/state", put(routes::v1::groups::update_group_state))
+        .route("/v1/groups/{group_id}/members", post(routes::v1::groups::add_group_member))
+        .route("/v1/groups/{group_id}/members/{user_id}", delete(routes::v1::groups::remove_group_member))
+        .route("/v1/groups/{group_id}/leave", post(routes::v1::groups::leave_group))
+        .route("/v1/groups/{group_id}/messages", post(routes::v1::groups::send_group_message))
+        .route("/v1/groups/{group_id}/members/{user_id}/role", put(routes::v1::groups::set_member_role))
+        .route("/v1/groups/{group_id}/permissions", put(routes::v1::groups::set_group_permissions))
+        .route("/v1/groups/{group_id}/invite_link", put(routes::v1::groups::set_invite_link)
+            .delete(routes::v1::groups::delete_invite_link))
+        .route("/v1/groups/{group_id}/join_requests", get(routes::v1::groups::get_join_requests))
+        .route("/v1/groups/{group_id}/join_requests/{user_id}", delete(routes::v1::groups::delete_join_request))
+        .route("/v1/groups/{group_id}/join_requests/{user_id}/approve", post(routes::v1::groups::approve_join_request))
+        .route("/v1/groups/invite/{token}", get(routes::v1::groups::get_invite_link_group))
+        .route("/v1/groups/invite/{token}/join", post(routes::v1::groups::join_group))
         .layer(TraceLayer::new_for_http())
         .layer(Extension(state))
-    ;
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    println!("[Server] Started on http://0.0.0.0:3000");
-    axum::serve(listener, app).await.unwrap();
 }
 
+/// A request authenticated by a token whose device is registered and not revoked.
 pub struct AuthUser {
     pub user_id: Uuid,
     pub device_id: Uuid,
 }
 
+/// A request authenticated by a token of an existing user, whose device may not be registered
+/// yet. Only for uploading the keys that register the device.
+pub struct PendingDeviceAuth {
+    pub user_id: Uuid,
+    pub device_id: Uuid,
+}
+
+fn unauthorized() -> (StatusCode, String) {
+    (StatusCode::UNAUTHORIZED, "Unauthorized".into())
+}
+
+fn server_error() -> (StatusCode, String) {
+    (StatusCode::INTERNAL_SERVER_ERROR, "Something went wrong".into())
+}
+
+fn decode_token(parts: &Parts) -> Result<(AppState, Claims), (StatusCode, String)> {
+    let auth_header = parts
+        .headers
+        .get("Authorization")
+        .and_then(|v| v.to_str().ok())
+        .ok_or_else(unauthorized)?;
+
+    let token = auth_header
+        .strip_prefix("Bearer ")
+        .ok_or_else(unauthorized)?;
+
+    let state = parts
+        .extensions
+        .get::<AppState>()
+        .ok_or_else(server_error)?;
+
+    let decoded = decode::<Claims>(
+        token,
+        &DecodingKey::from_secret(state.jwt_secret.as_bytes()),
+        &Validation::default(),
+    )
+        .map_err(|_| unauthorized())?;
+
+    Ok((state.clone(), decoded.claims))
+}
+
 impl<S> FromRequestParts<S> for AuthUser
 where
     S: Send + Sync,
@@ -71,31 +373,47 @@ where
     type Rejection = (StatusCode, String);
 
     async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
-        let auth_header = parts
-            .headers
-            .get("Authorization")
-            .and_then(|v| v.to_str().ok())
-            .ok_or((StatusCode::UNAUTHORIZED, "Unauthorized".into()))?;
-
-        let token = auth_header
-            .strip_prefix("Bearer ")
-            .ok_or((StatusCode::UNAUTHORIZED, "Unauthorized".into()))?;
-
-        let state = parts
-            .extensions
-            .get::<AppState>()
-            .ok_or((StatusCode::INTERNAL_SERVER_ERROR, "Something went wrong".into()))?;
-
-        let decoded = decode::<Claims>(
-            token,
-            &DecodingKey::from_secret(state.jwt_secret.as_bytes()),
-            &Validation::default(),
-        )
-            .map_err(|_| (StatusCode::UNAUTHORIZED, "Unauthorized".into()))?;
+        let (state, claims) = decode_token(parts)?;
+
+        let mut conn = state.db.get().map_err(|_| server_error())?;
+        devices::table
+            .find(claims.device)
+            .filter(devices::user_id.eq(claims.sub))
+            .filter(devices::is_revoked.is_distinct_from(true))
+            .select(devices::id)
+            .first::<Uuid>(&mut conn)
+            .optional()
+            .map_err(|_| server_error())?
+            .ok_or_else(unauthorized)?;
 
         Ok(AuthUser {
-            user_id: decoded.claims.sub,
-            device_id: decoded.claims.device,
+            user_id: claims.sub,
+            device_id: claims.device,
+        })
+    }
+}
+
+impl<S> FromRequestParts<S> for PendingDeviceAuth
+where
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let (state, claims) = decode_token(parts)?;
+
+        let mut conn = state.db.get().map_err(|_| server_error())?;
+        users::table
+            .find(claims.sub)
+            .select(users::id)
+            .first::<Uuid>(&mut conn)
+            .optional()
+            .map_err(|_| server_error())?
+            .ok_or_else(unauthorized)?;
+
+        Ok(PendingDeviceAuth {
+            user_id: claims.sub,
+            device_id: claims.device,
         })
     }
 }
@@ -2,7 +2,7 @@ use diesel::prelude::*;
 use chrono::{DateTime, NaiveDateTime, Utc};
 use uuid::Uuid;
 use serde::Serialize;
-use crate::schema::{devices, messages, one_time_prekeys, users, verification_codes};
+use crate::schema::{devices, group_members, groups, messages, one_time_prekeys, profiles, users, verification_codes};
 
 #[derive(Debug, Queryable, Identifiable, Associations, Serialize)]
 #[diesel(table_name = devices)]
@@ -18,6 +18,8 @@ pub struct Device {
     pub signed_prekey_pub: Vec<u8>,
     pub signed_prekey_signature: Vec<u8>,
     pub push_token: Option<String>,
+    pub push_platform: Option<String>,
+    pub push_pending_since: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, Insertable)]
@@ -46,6 +48,10 @@ pub struct Message {
     pub protocol_version: i16,
     pub delivered_at: Option<NaiveDateTime>,
     pub created_at: Option<NaiveDateTime>,
+    pub is_request: bool,
+    pub franking_commitment: Option<Vec<u8>>,
+    pub franking_tag: Option<Vec<u8>>,
+    pub group_payload_id: Option<i64>,
 }
 
 #[derive(Debug, Insertable)]
@@ -58,6 +64,11 @@ pub struct NewMessage<'a> {
     pub ciphertext: &'a [u8],
     pub message_type: i16,
     pub protocol_version: i16,
+    pub is_request: bool,
+    pub franking_commitment: Option<&'a [u8]>,
+    pub franking_tag: Option<Vec<u8>>,
+    pub created_at: NaiveDateTime,
+    pub group_payload_id: Option<i64>,
 }
 
 #[derive(Debug, Queryable, Identifiable, Associations)]
@@ -78,34 +89,73 @@ pub struct NewOneTimePrekey<'a> {
     pub prekey_pub: &'a [u8],
 }
 
+#[derive(Debug, Queryable, Identifiable, Associations)]
+#[diesel(table_name = profiles)]
+#[diesel(primary_key(user_id, version))]
+#[diesel(belongs_to(User))]
+pub struct Profile {
+    pub user_id: Uuid,
+    pub version: String,
+    pub name: Vec<u8>,
+    pub about: Option<Vec<u8>>,
+    pub avatar_hash: Option<String>,
+    pub access_key_hash: Vec<u8>,
+    pub created_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Queryable, Identifiable)]
+#[diesel(table_name = groups)]
+pub struct Group {
+    pub id: Uuid,
+    pub state: Vec<u8>,
+    pub version: i64,
+    pub created_at: Option<NaiveDateTime>,
+    pub updated_at: Option<NaiveDateTime>,
+    pub add_members_access: String,
+    pub edit_state_access: String,
+    pub send_access: String,
+    pub invite_link_token_hash: Option<Vec<u8>>,
+    pub invite_link_requires_approval: bool,
+}
+
+#[derive(Debug, Queryable, Identifiable, Associations, Serialize)]
+#[diesel(table_name = group_members)]
+#[diesel(primary_key(group_id, user_id))]
+#[diesel(belongs_to(Group))]
+pub struct GroupMember {
+    pub group_id: Uuid,
+    pub user_id: Uuid,
+    pub role: String,
+    pub joined_at: Option<NaiveDateTime>,
+}
+
 #[derive(Debug, Queryable, Identifiable)]
 #[diesel(table_name = users)]
 pub struct User {
     pub id: Uuid,
-    pub name: String,
     pub phone_number: String,
-    pub avatar_hash: Option<String>,
     pub last_seen: Option<NaiveDateTime>,
     pub created_at: Option<NaiveDateTime>,
+    pub phone_number_hash: Option<Vec<u8>>,
 }
 
 #[derive(Debug, Insertable)]
 #[diesel(table_name = users)]
 pub struct NewUser<'a> {
-    pub name: &'a str,
     pub phone_number: &'a str,
-    pub avatar_hash: Option<&'a str>,
     pub created_at: Option<NaiveDateTime>,
 }
 
 #[derive(Debug, Queryable, Identifiable)]
 #[diesel(table_name = verification_codes)]
-#[diesel(primary_key(phone_number))]
+#[diesel(primary_key(phone_number, purpose))]
 pub struct VerificationCode {
     pub phone_number: String,
     pub code_hash: String,
     pub expires_at: DateTime<Utc>,
     pub attempt_count: Option<i32>,
+    /// Action the code was requested for: `register`, `delete_account` or `change_phone`.
+    pub purpose: String,
 }
 
 #[derive(Debug, Insertable)]
@@ -115,4 +165,5 @@ pub struct NewVerificationCode<'a> {
     pub code_hash: &'a str,
     pub expires_at: NaiveDateTime,
     pub attempt_count: Option<i32>,
+    pub purpose: &'a str,
 }
@@ -0,0 +1,224 @@
+use async_trait::async_trait;
+use serde_json::json;
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[derive(Debug)]
+pub struct OtpError(pub String);
+
+impl std::fmt::Display for OtpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Delivers verification codes to a phone number.
+#[async_trait]
+pub trait OtpSender: Send + Sync {
+    async fn send(&self, phone_number: &str, code: &str) -> Result<(), OtpError>;
+}
+
+/// Sends codes through an HTTP SMS gateway.
+///
+/// The gateway receives a JSON body `{"to": "...", "message": "..."}`, so any provider (or a local
+/// mock server) accepting that shape can be plugged in with `OTP_HTTP_URL`.
+pub struct HttpOtpSender {
+    client: reqwest::Client,
+    url: String,
+    api_key: Option<String>,
+    sender_id: Option<String>,
+}
+
+impl HttpOtpSender {
+    pub fn new(url: String, api_key: Option<String>, sender_id: Option<String>) -> Self {
+        Self {
+            // A gateway that hangs must not hold up registration.
+            client: reqwest::Client::builder()
+                .connect_timeout(Duration::from_secs(5))
+                .timeout(Duration::from_secs(10))
+                .build()
+                .expect("The OTP client configuration is valid"),
+            url,
+            api_key,
+            sender_id,
+        }
+    }
+}
+
+#[async_trait]
+impl OtpSender for HttpOtpSender {
+    async fn send(&self, phone_number: &str, code: &str) -> Result<(), OtpError> {
+        let mut request = self.client
+            .post(&self.url)
+            .json(&json!({
+                "to": phone_number,
+                "from": self.sender_id,
+                "message": format!("Your verification code is {code}"),
+            }));
+        if let Some(api_key) = &self.api_key {
+            request = request.bearer_auth(api_key);
+        }
+
+        let response = request
+            .send()
+            .await
+            .map_err(|e| OtpError(format!("SMS gateway unreachable: {e}")))?;
+        if !response.status().is_success() {
+            return Err(OtpError(format!("SMS gateway returned {}", response.status())));
+        }
+
+        Ok(())
+    }
+}
+
+/// Development sink: writes codes to the debug log, or appends them to a file if a path is set.
+pub struct LogOtpSender {
+    path: Option<PathBuf>,
+}
+
+impl LogOtpSender {
+    pub fn new(path: Option<PathBuf>) -> Self {
+        Self { path }
+    }
+}
+
+#[async_trait]
+impl OtpSender for LogOtpSender {
+    async fn send(&self, phone_number: &str, code: &str) -> Result<(), OtpError> {
+        match &self.path {
+            Some(path) => {
+                let mut file = std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map_err(|e| OtpError(format!("Failed to open OTP file: {e}")))?;
+                writeln!(file, "{phone_number} {code}")
+                    .map_err(|e| OtpError(format!("Failed to write OTP file: {e}")))?;
+            }
+            None => tracing::debug!("OTP code for {phone_number}: {code}"),
+        }
+
+        Ok(())
+    }
+}
+
+/// Keeps every sent code in memory, so tests can read back what a phone number received.
+#[derive(Clone, Default)]
+pub struct MemoryOtpSender {
+    sent: Arc<Mutex<HashMap<String, Vec<String>>>>,
+}
+
+impl MemoryOtpSender {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the most recent code sent to `phone_number`.
+    pub fn last_code(&self, phone_number: &str) -> Option<String> {
+        self.sent
+            .lock()
+            .unwrap()
+            .get(phone_number)
+            .and_then(|codes| codes.last().cloned())
+    }
+
+    /// Returns every code sent to `phone_number`, oldest first.
+    pub fn codes(&self, phone_number: &str) -> Vec<String> {
+        self.sent
+            .lock()
+            .unwrap()
+            .get(phone_number)
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+#[async_trait]
+impl OtpSender for MemoryOtpSender {
+    async fn send(&self, phone_number: &str, code: &str) -> Result<(), OtpError> {
+        self.sent
+            .lock()
+            .unwrap()
+            .entry(phone_number.to_string())
+            .or_default()
+            .push(code.to_string());
+
+        Ok(())
+    }
+}
+
+/// Builds the sender selected by `OTP_PROVIDER` (`http`, `log` or `file`).
+///
+/// There is no default, so a deployment can't end up writing codes to the log by accident.
+/// Tests inject a [`MemoryOtpSender`] directly instead.
+pub fn from_env() -> Arc<dyn OtpSender> {
+    let provider = std::env::var("OTP_PROVIDER").expect("OTP_PROVIDER must be set (http, log or file)");
+
+    match provider.as_str() {
+        "http" => Arc::new(HttpOtpSender::new(
+            std::env::var("OTP_HTTP_URL").expect("OTP_HTTP_URL must be set when OTP_PROVIDER=http"),
+            std::env::var("OTP_HTTP_API_KEY").ok(),
+            std::env::var("OTP_HTTP_SENDER_ID").ok(),
+        )),
+        "file" => Arc::new(LogOtpSender::new(Some(
+            std::env::var("OTP_FILE_PATH").unwrap_or_else(|_| "otp_codes.txt".into()).into(),
+        ))),
+        "log" => Arc::new(LogOtpSender::new(None)),
+        other => panic!("Unknown OTP_PROVIDER: {other}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::routing::post;
+    use axum::{Json, Router};
+    use serde_json::Value;
+
+    /// Serves a gateway answering `status` on `/sms`, recording the auth header and body it receives.
+    async fn mock_gateway(status: StatusCode) -> (String, Arc<Mutex<Vec<(Option<String>, Value)>>>) {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let recorded = received.clone();
+        let router = Router::new().route("/sms", post(async move |headers: HeaderMap, Json(body): Json<Value>| {
+            let auth = headers.get("authorization").map(|v| v.to_str().unwrap().to_string());
+            recorded.lock().unwrap().push((auth, body));
+            status
+        }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/sms", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+        (url, received)
+    }
+
+    #[tokio::test]
+    async fn http_sender_posts_the_code() {
+        let (url, received) = mock_gateway(StatusCode::OK).await;
+        let sender = HttpOtpSender::new(url, Some("api key".into()), Some("E2EE".into()));
+        sender.send("+15551234567", "123456").await.unwrap();
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].0.as_deref(), Some("Bearer api key"));
+        assert_eq!(received[0].1, json!({
+            "to": "+15551234567",
+            "from": "E2EE",
+            "message": "Your verification code is 123456",
+        }));
+    }
+
+    #[tokio::test]
+    async fn http_sender_reports_gateway_errors() {
+        let (url, _) = mock_gateway(StatusCode::BAD_GATEWAY).await;
+        let error = HttpOtpSender::new(url, None, None).send("+15551234567", "123456").await.unwrap_err();
+        assert_eq!(error.0, "SMS gateway returned 502 Bad Gateway");
+
+        // Nothing listens on the discard port.
+        let sender = HttpOtpSender::new("http://127.0.0.1:9/sms".into(), None, None);
+        let error = sender.send("+15551234567", "123456").await.unwrap_err();
+        assert!(error.0.starts_with("SMS gateway unreachable"), "{error}");
+    }
+}
@@ -0,0 +1,87 @@
+use phonenumber::Mode;
+
+/// A phone number validated against the libphonenumber metadata.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PhoneNumber {
+    /// Canonical E.164 form (e.g. `+33612345678`), the only form stored in the database.
+    pub e164: String,
+    pub country_code: u16,
+    /// CLDR region (e.g. `FR`), if the number maps to a single one.
+    pub region: Option<String>,
+}
+
+/// Parses a user-supplied phone number in international format.
+///
+/// Spaces, dashes, dots and parentheses are accepted as separators, but letters, extensions
+/// and numbers with an unknown country code or an invalid length for their region are rejected.
+pub fn parse_phone(input: &str) -> Option<PhoneNumber> {
+    let input = input.trim();
+
+    if !input.starts_with('+') {
+        return None;
+    }
+    if !input
+        .chars()
+        .all(|c| c.is_ascii_digit() || matches!(c, '+' | ' ' | '-' | '.' | '(' | ')'))
+    {
+        return None;
+    }
+
+    let parsed = phonenumber::parse(None, input).ok()?;
+    if parsed.extension().is_some() || !phonenumber::is_valid(&parsed) {
+        return None;
+    }
+
+    Some(PhoneNumber {
+        e164: parsed.format().mode(Mode::E164).to_string(),
+        country_code: parsed.code().value(),
+        region: parsed.country().id().map(|id| id.as_ref().to_string()),
+    })
+}
+
+/// Returns the canonical E.164 form of `input`, or `None` if it isn't a valid phone number.
+pub fn normalize_phone(input: &str) -> Option<String> {
+    parse_phone(input).map(|p| p.e164)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_separators() {
+        for input in ["+33612345678", "+33 6 12 34 56 78", " +33 (6) 12-34.56.78 "] {
+            assert_eq!(normalize_phone(input).as_deref(), Some("+33612345678"), "{input}");
+        }
+    }
+
+    #[test]
+    fn reports_country_and_region() {
+        let phone = parse_phone("+1 415 555 2671").unwrap();
+        assert_eq!(phone.e164, "+14155552671");
+        assert_eq!(phone.country_code, 1);
+        assert_eq!(phone.region.as_deref(), Some("US"));
+    }
+
+    #[test]
+    fn rejects_national_format() {
+        assert_eq!(normalize_phone("0612345678"), None);
+    }
+
+    #[test]
+    fn rejects_letters_and_extensions() {
+        assert_eq!(normalize_phone("+33 6 12 34 56 7a"), None);
+        assert_eq!(normalize_phone("+1 415 555 2671 ext. 12"), None);
+        assert_eq!(normalize_phone("+1 415 555 2671;ext=12"), None);
+    }
+
+    #[test]
+    fn rejects_invalid_numbers() {
+        // Unassigned country code, too short and too long for the region.
+        assert_eq!(normalize_phone("+999 12345678"), None);
+        assert_eq!(normalize_phone("+33 6 12"), None);
+        assert_eq!(normalize_phone("+33 6 12 34 56 78 90"), None);
+        assert_eq!(normalize_phone("+"), None);
+        assert_eq!(normalize_phone(""), None);
+    }
+}
@@ -0,0 +1,319 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PowError {
+    /// The token wasn't issued by this server, or was issued for another phone number.
+    InvalidChallenge,
+    Expired,
+    /// The token was issued with a lower difficulty than the one currently required.
+    DifficultyTooLow,
+    InvalidSolution,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Challenge {
+    pub challenge: String,
+    pub difficulty: u8,
+    pub expires_at: i64,
+}
+
+/// Stateless proof-of-work challenges for unauthenticated endpoints.
+///
+/// A challenge is `base64(payload).base64(hmac(payload))`, where the payload binds a random nonce to
+/// the phone number, expiry and difficulty, so the server doesn't need to remember issued challenges.
+/// A solution is any string such that `SHA-256(challenge || solution)` starts with `difficulty`
+/// zero bits.
+///
+/// The difficulty starts at `base_difficulty` and rises by one bit every time the number of requests
+/// from the same phone prefix (first `prefix_length` characters of the E.164 number) or IP range
+/// doubles past `abuse_threshold` within `abuse_window`.
+pub struct ProofOfWork {
+    secret: Vec<u8>,
+    pub base_difficulty: u8,
+    pub max_difficulty: u8,
+    pub ttl: Duration,
+    pub abuse_threshold: u32,
+    pub abuse_window: Duration,
+    pub prefix_length: usize,
+    requests: Mutex<HashMap<String, (u32, Instant)>>,
+}
+
+impl ProofOfWork {
+    pub fn new(secret: &[u8], base_difficulty: u8, max_difficulty: u8) -> Self {
+        Self {
+            secret: secret.to_vec(),
+            base_difficulty,
+            max_difficulty,
+            ttl: Duration::from_secs(300),
+            abuse_threshold: 20,
+            abuse_window: Duration::from_secs(3600),
+            prefix_length: 6,
+            requests: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Reads `POW_SECRET` (required, and distinct from the other secrets), `POW_DIFFICULTY`,
+    /// `POW_MAX_DIFFICULTY`, `POW_ABUSE_THRESHOLD` and `POW_PREFIX_LENGTH`.
+    pub fn from_env() -> Self {
+        let secret = std::env::var("POW_SECRET")
+            .ok()
+            .filter(|s| !s.is_empty())
+            .expect("POW_SECRET must be set");
+        // Parsed as their own type, so an out of range value fails instead of wrapping around.
+        fn read<T: std::str::FromStr>(name: &str, default: T) -> T {
+            std::env::var(name)
+                .ok()
+                .map(|v| v.parse().unwrap_or_else(|_| panic!("Invalid {name}: {v}")))
+                .unwrap_or(default)
+        }
+
+        let mut pow = Self::new(
+            secret.as_bytes(),
+            read("POW_DIFFICULTY", 0),
+            read("POW_MAX_DIFFICULTY", 24),
+        );
+        pow.abuse_threshold = read("POW_ABUSE_THRESHOLD", 20);
+        pow.prefix_length = read("POW_PREFIX_LENGTH", 6);
+        pow
+    }
+
+    /// Counts a request for `phone_number` from `ip` towards the abuse counters.
+    pub fn record_request(&self, phone_number: &str, ip: Option<IpAddr>) {
+        let now = Instant::now();
+        let mut requests = self.requests.lock().unwrap();
+
+        requests.retain(|_, (_, started_at)| now - *started_at < self.abuse_window);
+        for key in self.abuse_keys(phone_number, ip) {
+            requests.entry(key).or_insert((0, now)).0 += 1;
+        }
+    }
+
+    /// Returns the difficulty currently required for a request for `phone_number` from `ip`.
+    pub fn difficulty_for(&self, phone_number: &str, ip: Option<IpAddr>) -> u8 {
+        let now = Instant::now();
+        let requests = self.requests.lock().unwrap();
+        let count = self.abuse_keys(phone_number, ip)
+            .iter()
+            .filter_map(|key| requests.get(key))
+            .filter(|(_, started_at)| now - *started_at < self.abuse_window)
+            .map(|(count, _)| *count)
+            .max()
+            .unwrap_or(0);
+
+        let extra = if count > self.abuse_threshold {
+            (count / self.abuse_threshold.max(1)).ilog2() as u8 + 1
+        } else {
+            0
+        };
+        self.base_difficulty.saturating_add(extra).min(self.max_difficulty)
+    }
+
+    pub fn issue(&self, phone_number: &str, difficulty: u8) -> Challenge {
+        let nonce: [u8; 16] = rand::rng().random();
+        let expires_at = Utc::now().timestamp() + self.ttl.as_secs() as i64;
+        let payload = format!(
+            "{}:{}:{}:{}",
+            URL_SAFE_NO_PAD.encode(nonce),
+            expires_at,
+            difficulty,
+            URL_SAFE_NO_PAD.encode(Sha256::digest(phone_number.as_bytes())),
+        );
+        let signature = self.mac(payload.as_bytes()).finalize().into_bytes();
+
+        Challenge {
+            challenge: format!("{}.{}", URL_SAFE_NO_PAD.encode(&payload), URL_SAFE_NO_PAD.encode(signature)),
+            difficulty,
+            expires_at,
+        }
+    }
+
+    /// Checks that `solution` solves `challenge`, issued for `phone_number` with at least
+    /// `required_difficulty`.
+    pub fn verify(
+        &self,
+        challenge: &str,
+        solution: &str,
+        phone_number: &str,
+        required_difficulty: u8,
+    ) -> Result<(), PowError> {
+        let (payload, signature) = challenge.split_once('.').ok_or(PowError::InvalidChallenge)?;
+        let payload = URL_SAFE_NO_PAD.decode(payload).map_err(|_| PowError::InvalidChallenge)?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| PowError::InvalidChallenge)?;
+        self.mac(&payload)
+            .verify_slice(&signature)
+            .map_err(|_| PowError::InvalidChallenge)?;
+
+        let payload = String::from_utf8(payload).map_err(|_| PowError::InvalidChallenge)?;
+        let [_, expires_at, difficulty, phone_hash] = payload
+            .split(':')
+            .collect::<Vec<_>>()
+            .try_into()
+            .map_err(|_| PowError::InvalidChallenge)?;
+        let expires_at: i64 = expires_at.parse().map_err(|_| PowError::InvalidChallenge)?;
+        let difficulty: u8 = difficulty.parse().map_err(|_| PowError::InvalidChallenge)?;
+
+        if phone_hash != URL_SAFE_NO_PAD.encode(Sha256::digest(phone_number.as_bytes())) {
+            return Err(PowError::InvalidChallenge);
+        }
+        if Utc::now().timestamp() > expires_at {
+            return Err(PowError::Expired);
+        }
+        if difficulty < required_difficulty {
+            return Err(PowError::DifficultyTooLow);
+        }
+
+        let hash = Sha256::new()
+            .chain_update(challenge.as_bytes())
+            .chain_update(solution.as_bytes())
+            .finalize();
+        if leading_zero_bits(&hash) < difficulty as u32 {
+            return Err(PowError::InvalidSolution);
+        }
+
+        Ok(())
+    }
+
+    fn abuse_keys(&self, phone_number: &str, ip: Option<IpAddr>) -> Vec<String> {
+        let prefix: String = phone_number.chars().take(self.prefix_length).collect();
+        let mut keys = vec![format!("prefix:{prefix}")];
+
+        match ip {
+            Some(IpAddr::V4(v4)) => {
+                let o = v4.octets();
+                keys.push(format!("ip:{}.{}.{}.0/24", o[0], o[1], o[2]));
+            }
+            Some(IpAddr::V6(v6)) => {
+                let s = v6.segments();
+                keys.push(format!("ip:{:x}:{:x}:{:x}::/48", s[0], s[1], s[2]));
+            }
+            None => {}
+        }
+
+        keys
+    }
+
+    fn mac(&self, payload: &[u8]) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.secret).expect("HMAC accepts any key length");
+        mac.update(payload);
+        mac
+    }
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        if *byte == 0 {
+            bits += 8;
+        } else {
+            bits += byte.leading_zeros();
+            break;
+        }
+    }
+
+    bits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PHONE: &str = "+33612345678";
+
+    fn zero_bits(challenge: &str, solution: &str) -> u32 {
+        leading_zero_bits(&Sha256::new()
+            .chain_update(challenge.as_bytes())
+            .chain_update(solution.as_bytes())
+            .finalize())
+    }
+
+    fn solve(challenge: &str, difficulty: u8) -> String {
+        (0u64..)
+            .map(|n| n.to_string())
+            .find(|solution| zero_bits(challenge, solution) >= difficulty as u32)
+            .unwrap()
+    }
+
+    #[test]
+    fn counts_leading_zero_bits() {
+        assert_eq!(leading_zero_bits(&[0xff]), 0);
+        assert_eq!(leading_zero_bits(&[0x00, 0x10]), 11);
+        assert_eq!(leading_zero_bits(&[0x00, 0x00]), 16);
+    }
+
+    #[test]
+    fn accepts_a_solution() {
+        let pow = ProofOfWork::new(b"secret", 8, 24);
+        let challenge = pow.issue(PHONE, 8).challenge;
+        let solution = solve(&challenge, 8);
+
+        assert_eq!(pow.verify(&challenge, &solution, PHONE, 8), Ok(()));
+    }
+
+    #[test]
+    fn rejects_a_wrong_solution() {
+        let pow = ProofOfWork::new(b"secret", 8, 24);
+        let challenge = pow.issue(PHONE, 8).challenge;
+        let solution = (0u64..)
+            .map(|n| n.to_string())
+            .find(|solution| zero_bits(&challenge, solution) < 8)
+            .unwrap();
+
+        assert_eq!(pow.verify(&challenge, &solution, PHONE, 8), Err(PowError::InvalidSolution));
+    }
+
+    #[test]
+    fn rejects_challenges_for_another_phone_or_server() {
+        let pow = ProofOfWork::new(b"secret", 4, 24);
+        let challenge = pow.issue(PHONE, 4).challenge;
+        let solution = solve(&challenge, 4);
+
+        assert_eq!(pow.verify(&challenge, &solution, "+33698765432", 4), Err(PowError::InvalidChallenge));
+        let other = ProofOfWork::new(b"other secret", 4, 24);
+        assert_eq!(other.verify(&challenge, &solution, PHONE, 4), Err(PowError::InvalidChallenge));
+        assert_eq!(pow.verify("garbage", &solution, PHONE, 4), Err(PowError::InvalidChallenge));
+    }
+
+    #[test]
+    fn rejects_expired_and_easier_challenges() {
+        let mut pow = ProofOfWork::new(b"secret", 4, 24);
+        let easy = pow.issue(PHONE, 4).challenge;
+        assert_eq!(pow.verify(&easy, &solve(&easy, 4), PHONE, 5), Err(PowError::DifficultyTooLow));
+
+        pow.ttl = Duration::ZERO;
+        let expired = pow.issue(PHONE, 4).challenge;
+        std::thread::sleep(Duration::from_millis(1100));
+        assert_eq!(pow.verify(&expired, &solve(&expired, 4), PHONE, 4), Err(PowError::Expired));
+    }
+
+    #[test]
+    fn raises_difficulty_with_abuse() {
+        let mut pow = ProofOfWork::new(b"secret", 0, 6);
+        pow.abuse_threshold = 2;
+        let ip = Some("192.0.2.1".parse().unwrap());
+
+        assert_eq!(pow.difficulty_for(PHONE, ip), 0);
+        for _ in 0..3 {
+            pow.record_request(PHONE, ip);
+        }
+        assert_eq!(pow.difficulty_for(PHONE, ip), 1);
+        // Same /24 and phone prefix share the counters.
+        assert_eq!(pow.difficulty_for("+33611111111", Some("192.0.2.200".parse().unwrap())), 1);
+        for _ in 0..100 {
+            pow.record_request(PHONE, ip);
+        }
+        assert_eq!(pow.difficulty_for(PHONE, ip), 6);
+        assert_eq!(pow.difficulty_for("+14155552671", Some("198.51.100.1".parse().unwrap())), 0);
+    }
+}
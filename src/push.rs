@@ -0,0 +1,1301 @@
+use crate::schema::devices;
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes128Gcm, Nonce};
+use async_trait::async_trait;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use diesel::prelude::*;
+use diesel::r2d2::{ConnectionManager, Pool};
+use hkdf::Hkdf;
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use p256::pkcs8::DecodePrivateKey;
+use p256::{PublicKey, SecretKey};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use sha2::Sha256;
+use chrono::Utc;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+#[derive(Debug)]
+pub enum PushError {
+    /// The provider reported the token as expired or unknown; it should be forgotten.
+    InvalidToken,
+    /// Worth retrying: network errors, rate limiting, provider outages.
+    Transient(String),
+    Failed(String),
+}
+
+impl std::fmt::Display for PushError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PushError::InvalidToken => write!(f, "Invalid push token"),
+            PushError::Transient(e) | PushError::Failed(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+/// Service a device's push token belongs to, as stored in `devices.push_platform`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PushPlatform {
+    Fcm,
+    Apns,
+    /// The token is the endpoint URL handed out by the device's UnifiedPush distributor.
+    UnifiedPush,
+    /// The token is a [`WebPushSubscription`], as JSON.
+    WebPush,
+}
+
+/// FCM registration tokens are around 160 characters; this leaves room for them to grow.
+const MAX_FCM_TOKEN_LENGTH: usize = 4096;
+/// APNs device tokens are 32 bytes, and Apple reserves up to 100, in hex.
+const MAX_APNS_TOKEN_LENGTH: usize = 200;
+
+impl PushPlatform {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PushPlatform::Fcm => "fcm",
+            PushPlatform::Apns => "apns",
+            PushPlatform::UnifiedPush => "unifiedpush",
+            PushPlatform::WebPush => "webpush",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "fcm" => Some(PushPlatform::Fcm),
+            "apns" => Some(PushPlatform::Apns),
+            "unifiedpush" => Some(PushPlatform::UnifiedPush),
+            "webpush" => Some(PushPlatform::WebPush),
+            _ => None,
+        }
+    }
+
+    /// Whether `token` has the shape this platform expects.
+    pub fn is_valid_token(self, token: &str) -> bool {
+        match self {
+            PushPlatform::Fcm => (1..=MAX_FCM_TOKEN_LENGTH).contains(&token.len())
+                && token.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_:".contains(&b)),
+            // Sent in the request path, so anything but hex could change the URL.
+            PushPlatform::Apns => (1..=MAX_APNS_TOKEN_LENGTH).contains(&token.len())
+                && token.bytes().all(|b| b.is_ascii_hexdigit()),
+            PushPlatform::UnifiedPush => is_push_endpoint(token),
+            PushPlatform::WebPush => serde_json::from_str::<WebPushSubscription>(token)
+                .is_ok_and(|subscription| subscription.keys().is_some()),
+        }
+    }
+}
+
+impl PushPlatform {
+    /// Checks that the endpoint of a UnifiedPush or Web Push `token` only resolves to public
+    /// addresses, so devices can't make the server post to its own network.
+    pub async fn check_endpoint(self, token: &str) -> Result<(), PushError> {
+        let endpoint = match self {
+            PushPlatform::Fcm | PushPlatform::Apns => return Ok(()),
+            PushPlatform::UnifiedPush => token.to_string(),
+            PushPlatform::WebPush => serde_json::from_str::<WebPushSubscription>(token)
+                .map_err(|_| PushError::InvalidToken)?
+                .endpoint,
+        };
+        check_endpoint(&endpoint).await
+    }
+}
+
+/// Endpoints are only ever called over HTTPS, and never at a non-public IP address.
+fn is_push_endpoint(url: &str) -> bool {
+    reqwest::Url::parse(url).is_ok_and(|url| url.scheme() == "https" && match url.host_str() {
+        Some(host) => host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+            .map_or(true, is_public_ip),
+        None => false,
+    })
+}
+
+/// Whether `ip` is reachable on the public internet: not loopback, private, link-local, unique
+/// local, unspecified or otherwise reserved.
+fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                || a == 0
+                // Shared address space (RFC 6598) and reserved (240.0.0.0/4).
+                || (a == 100 && (64..128).contains(&b))
+                || a >= 240)
+        }
+        IpAddr::V6(ip) => {
+            if let Some(mapped) = ip.to_ipv4_mapped() {
+                return is_public_ip(IpAddr::V4(mapped));
+            }
+            let first = ip.segments()[0];
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                // Unique local (fc00::/7), link-local (fe80::/10) and documentation (2001:db8::/32).
+                || (first & 0xfe00) == 0xfc00
+                || (first & 0xffc0) == 0xfe80
+                || (first == 0x2001 && ip.segments()[1] == 0x0db8))
+        }
+    }
+}
+
+/// Resolves `host`, failing if any of its addresses isn't public.
+async fn resolve_public(host: &str) -> Result<Vec<SocketAddr>, PushError> {
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, 0))
+        .await
+        .map_err(|e| PushError::Transient(format!("Failed to resolve {host}: {e}")))?
+        .collect();
+    if addrs.is_empty() || !addrs.iter().all(|addr| is_public_ip(addr.ip())) {
+        return Err(PushError::InvalidToken);
+    }
+    Ok(addrs)
+}
+
+/// Checks that `url` is a push endpoint whose host only resolves to public addresses.
+async fn check_endpoint(url: &str) -> Result<(), PushError> {
+    if !is_push_endpoint(url) {
+        return Err(PushError::InvalidToken);
+    }
+    match reqwest::Url::parse(url).ok().and_then(|url| url.domain().map(str::to_string)) {
+        Some(domain) => resolve_public(&domain).await.map(|_| ()),
+        // A public IP address, checked above.
+        None => Ok(()),
+    }
+}
+
+/// Resolver of the clients calling device-provided endpoints: connections only ever go to the
+/// public addresses checked here, even if the name resolved elsewhere when it was registered.
+struct PublicResolver;
+
+impl reqwest::dns::Resolve for PublicResolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        Box::pin(async move {
+            let addrs = resolve_public(name.as_str()).await.map_err(|e| match e {
+                PushError::InvalidToken => format!("{} resolves to a non-public address", name.as_str()),
+                e => e.to_string(),
+            })?;
+            Ok(Box::new(addrs.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+/// Client for device-provided endpoints: no redirects, which could lead anywhere, and short
+/// timeouts.
+fn endpoint_client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .connect_timeout(Duration::from_secs(5))
+        .timeout(Duration::from_secs(10))
+        .dns_resolver(PublicResolver)
+        .build()
+        .expect("The push client configuration is valid")
+}
+
+/// Client for the configured FCM and APNs endpoints, which must not hold a delivery forever.
+fn provider_client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(5))
+        .timeout(Duration::from_secs(10))
+        .build()
+        .expect("The push client configuration is valid")
+}
+
+/// Sent as the collapse key of every transport, so a device's pending wake-ups replace each
+/// other instead of stacking up.
+pub const COLLAPSE_KEY: &str = "wake";
+
+/// Sends wake-up pushes. They carry no content: the app fetches its messages when woken.
+#[async_trait]
+pub trait PushProvider: Send + Sync {
+    async fn wake(&self, token: &str) -> Result<(), PushError>;
+}
+
+fn request_error(e: reqwest::Error) -> PushError {
+    PushError::Transient(format!("Push provider unreachable: {e}"))
+}
+
+/// Access token with the instant it should be replaced.
+type CachedToken = Mutex<Option<(String, Instant)>>;
+
+fn cached(token: &CachedToken) -> Option<String> {
+    token
+        .lock()
+        .unwrap()
+        .as_ref()
+        .filter(|(_, refresh_at)| Instant::now() < *refresh_at)
+        .map(|(token, _)| token.clone())
+}
+
+#[derive(Deserialize)]
+struct ServiceAccount {
+    project_id: String,
+    client_email: String,
+    private_key: String,
+    #[serde(default = "default_token_uri")]
+    token_uri: String,
+}
+
+fn default_token_uri() -> String {
+    "https://oauth2.googleapis.com/token".into()
+}
+
+#[derive(Serialize)]
+struct ServiceAccountClaims<'a> {
+    iss: &'a str,
+    scope: &'a str,
+    aud: &'a str,
+    iat: i64,
+    exp: i64,
+}
+
+/// Sends data messages through the FCM HTTP v1 API, authenticated as a Google service account.
+pub struct FcmPushProvider {
+    client: reqwest::Client,
+    /// `https://fcm.googleapis.com`, or a local mock.
+    endpoint: String,
+    account: ServiceAccount,
+    key: EncodingKey,
+    access_token: CachedToken,
+}
+
+impl FcmPushProvider {
+    /// `service_account` is the JSON key file downloaded from the Firebase console.
+    pub fn new(endpoint: impl Into<String>, service_account: &str) -> Result<Self, PushError> {
+        let account: ServiceAccount = serde_json::from_str(service_account)
+            .map_err(|e| PushError::Failed(format!("Invalid FCM service account: {e}")))?;
+        let key = EncodingKey::from_rsa_pem(account.private_key.as_bytes())
+            .map_err(|e| PushError::Failed(format!("Invalid FCM service account key: {e}")))?;
+
+        Ok(Self {
+            client: provider_client(),
+            endpoint: endpoint.into(),
+            account,
+            key,
+            access_token: Mutex::new(None),
+        })
+    }
+
+    /// Exchanges a signed service account assertion for an OAuth access token, reused until
+    /// shortly before it expires.
+    async fn access_token(&self) -> Result<String, PushError> {
+        if let Some(token) = cached(&self.access_token) {
+            return Ok(token);
+        }
+
+        let now = chrono::Utc::now().timestamp();
+        let assertion = jsonwebtoken::encode(
+            &Header::new(Algorithm::RS256),
+            &ServiceAccountClaims {
+                iss: &self.account.client_email,
+                scope: "https://www.googleapis.com/auth/firebase.messaging",
+                aud: &self.account.token_uri,
+                iat: now,
+                exp: now + 3600,
+            },
+            &self.key,
+        )
+            .map_err(|e| PushError::Failed(format!("Failed to sign FCM assertion: {e}")))?;
+
+        let response = self.client
+            .post(&self.account.token_uri)
+            .form(&[
+                ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+                ("assertion", &assertion),
+            ])
+            .send()
+            .await
+            .map_err(request_error)?;
+        if !response.status().is_success() {
+            return Err(PushError::Transient(format!("FCM token exchange returned {}", response.status())));
+        }
+
+        #[derive(Deserialize)]
+        struct TokenResponse {
+            access_token: String,
+            expires_in: u64,
+        }
+        let token: TokenResponse = response
+            .json()
+            .await
+            .map_err(|e| PushError::Transient(format!("Invalid FCM token response: {e}")))?;
+        let refresh_at = Instant::now() + Duration::from_secs(token.expires_in.saturating_sub(60));
+        *self.access_token.lock().unwrap() = Some((token.access_token.clone(), refresh_at));
+        Ok(token.access_token)
+    }
+}
+
+#[async_trait]
+impl PushProvider for FcmPushProvider {
+    async fn wake(&self, token: &str) -> Result<(), PushError> {
+        let access_token = self.access_token().await?;
+        let response = self.client
+            .post(format!("{}/v1/projects/{}/messages:send", self.endpoint, self.account.project_id))
+            .bearer_auth(access_token)
+            .json(&json!({
+                "message": {
+                    "token": token,
+                    "data": {"type": "wake"},
+                    "android": {"priority": "HIGH", "collapse_key": COLLAPSE_KEY},
+                },
+            }))
+            .send()
+            .await
+            .map_err(request_error)?;
+
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        let body: Value = response.json().await.unwrap_or_default();
+        let error_code = body["error"]["details"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find_map(|detail| detail["errorCode"].as_str());
+
+        match (status.as_u16(), error_code) {
+            // The message itself is fixed, so an invalid argument can only be the token.
+            (_, Some("UNREGISTERED" | "INVALID_ARGUMENT" | "SENDER_ID_MISMATCH")) | (404, _) => {
+                Err(PushError::InvalidToken)
+            }
+            (401, _) => {
+                *self.access_token.lock().unwrap() = None;
+                Err(PushError::Transient("FCM rejected the access token".into()))
+            }
+            (429 | 500..=599, _) => Err(PushError::Transient(format!("FCM returned {status}"))),
+            _ => Err(PushError::Failed(format!("FCM returned {status}: {body}"))),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ApnsClaims<'a> {
+    iss: &'a str,
+    iat: i64,
+}
+
+/// Sends background notifications through APNs over HTTP/2, with token-based authentication.
+pub struct ApnsPushProvider {
+    client: reqwest::Client,
+    /// `https://api.push.apple.com`, or `https://api.sandbox.push.apple.com` for development builds.
+    endpoint: String,
+    key: EncodingKey,
+    key_id: String,
+    team_id: String,
+    /// App bundle id.
+    topic: String,
+    provider_token: CachedToken,
+}
+
+impl ApnsPushProvider {
+    /// `key` is the `.p8` signing key from the Apple developer account, identified by `key_id`.
+    pub fn new(
+        endpoint: impl Into<String>,
+        key: &str,
+        key_id: impl Into<String>,
+        team_id: impl Into<String>,
+        topic: impl Into<String>,
+    ) -> Result<Self, PushError> {
+        let key = EncodingKey::from_ec_pem(key.as_bytes())
+            .map_err(|e| PushError::Failed(format!("Invalid APNs key: {e}")))?;
+
+        Ok(Self {
+            client: provider_client(),
+            endpoint: endpoint.into(),
+            key,
+            key_id: key_id.into(),
+            team_id: team_id.into(),
+            topic: topic.into(),
+            provider_token: Mutex::new(None),
+        })
+    }
+
+    /// APNs rejects provider tokens older than an hour, and refreshing them more than every
+    /// 20 minutes, so one is kept for 50 minutes.
+    fn provider_token(&self) -> Result<String, PushError> {
+        if let Some(token) = cached(&self.provider_token) {
+            return Ok(token);
+        }
+
+        let mut header = Header::new(Algorithm::ES256);
+        header.kid = Some(self.key_id.clone());
+        let token = jsonwebtoken::encode(
+            &header,
+            &ApnsClaims { iss: &self.team_id, iat: chrono::Utc::now().timestamp() },
+            &self.key,
+        )
+            .map_err(|e| PushError::Failed(format!("Failed to sign APNs token: {e}")))?;
+        let refresh_at = Instant::now() + Duration::from_secs(50 * 60);
+        *self.provider_token.lock().unwrap() = Some((token.clone(), refresh_at));
+        Ok(token)
+    }
+}
+
+#[async_trait]
+impl PushProvider for ApnsPushProvider {
+    async fn wake(&self, token: &str) -> Result<(), PushError> {
+        // Tokens registered before they were validated.
+        if !PushPlatform::Apns.is_valid_token(token) {
+            return Err(PushError::InvalidToken);
+        }
+        let response = self.client
+            .post(format!("{}/3/device/{token}", self.endpoint))
+            .bearer_auth(self.provider_token()?)
+            .header("apns-topic", &self.topic)
+            .header("apns-push-type", "background")
+            // Background pushes must use priority 5.
+            .header("apns-priority", "5")
+            .header("apns-collapse-id", COLLAPSE_KEY)
+            .json(&json!({"aps": {"content-available": 1}}))
+            .send()
+            .await
+            .map_err(request_error)?;
+
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        let body: Value = response.json().await.unwrap_or_default();
+        let reason = body["reason"].as_str().unwrap_or_default();
+
+        match (status.as_u16(), reason) {
+            (410, _) | (400, "BadDeviceToken" | "DeviceTokenNotForTopic") => Err(PushError::InvalidToken),
+            (403, "ExpiredProviderToken" | "InvalidProviderToken") => {
+                *self.provider_token.lock().unwrap() = None;
+                Err(PushError::Transient(format!("APNs rejected the provider token: {reason}")))
+            }
+            (429 | 500..=599, _) => Err(PushError::Transient(format!("APNs returned {status}: {reason}"))),
+            _ => Err(PushError::Failed(format!("APNs returned {status}: {reason}"))),
+        }
+    }
+}
+
+/// Push subscription of a browser (`PushManager.subscribe()`) or of a UnifiedPush distributor
+/// supporting Web Push encryption.
+#[derive(Serialize, Deserialize)]
+pub struct WebPushSubscription {
+    pub endpoint: String,
+    pub keys: WebPushKeys,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct WebPushKeys {
+    /// Base64url P-256 public key of the user agent, uncompressed.
+    pub p256dh: String,
+    /// Base64url 16-byte authentication secret.
+    pub auth: String,
+}
+
+impl WebPushSubscription {
+    /// Returns the decoded user agent public key and authentication secret, if the subscription
+    /// is usable.
+    fn keys(&self) -> Option<(PublicKey, Vec<u8>)> {
+        let decode = |v: &str| URL_SAFE_NO_PAD.decode(v.trim_end_matches('=')).ok();
+        let public_key = PublicKey::from_sec1_bytes(&decode(&self.keys.p256dh)?).ok()?;
+        let auth_secret = decode(&self.keys.auth).filter(|secret| secret.len() == 16)?;
+        is_push_endpoint(&self.endpoint).then_some((public_key, auth_secret))
+    }
+}
+
+/// Encrypts `plaintext` for a subscription, as a single `aes128gcm` record (RFC 8291).
+pub fn encrypt_web_push(
+    ua_public: &PublicKey,
+    auth_secret: &[u8],
+    plaintext: &[u8],
+) -> Result<Vec<u8>, PushError> {
+    let as_secret = loop {
+        if let Ok(key) = SecretKey::from_slice(&rand::rng().random::<[u8; 32]>()) {
+            break key;
+        }
+    };
+    encrypt_web_push_with(ua_public, auth_secret, plaintext, &as_secret, rand::rng().random())
+}
+
+/// [`encrypt_web_push`] with the given ephemeral application server key and salt.
+fn encrypt_web_push_with(
+    ua_public: &PublicKey,
+    auth_secret: &[u8],
+    plaintext: &[u8],
+    as_secret: &SecretKey,
+    salt: [u8; 16],
+) -> Result<Vec<u8>, PushError> {
+    let failed = |e: &str| PushError::Failed(format!("Web Push encryption failed: {e}"));
+
+    let as_public = as_secret.public_key().to_encoded_point(false);
+    let ua_public_bytes = ua_public.to_encoded_point(false);
+    let shared = p256::ecdh::diffie_hellman(as_secret.to_nonzero_scalar(), ua_public.as_affine());
+
+    let key_info = [b"WebPush: info\0".as_slice(), ua_public_bytes.as_bytes(), as_public.as_bytes()].concat();
+    let mut ikm = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(auth_secret), shared.raw_secret_bytes())
+        .expand(&key_info, &mut ikm)
+        .map_err(|e| failed(&e.to_string()))?;
+
+    let hkdf = Hkdf::<Sha256>::new(Some(&salt), &ikm);
+    let mut cek = [0u8; 16];
+    let mut nonce = [0u8; 12];
+    hkdf.expand(b"Content-Encoding: aes128gcm\0", &mut cek).map_err(|e| failed(&e.to_string()))?;
+    hkdf.expand(b"Content-Encoding: nonce\0", &mut nonce).map_err(|e| failed(&e.to_string()))?;
+
+    // A single record, marked as the last one by the 0x02 delimiter.
+    let record = [plaintext, &[2]].concat();
+    let ciphertext = Aes128Gcm::new_from_slice(&cek)
+        .map_err(|e| failed(&e.to_string()))?
+        .encrypt(Nonce::from_slice(&nonce), record.as_slice())
+        .map_err(|e| failed(&e.to_string()))?;
+
+    // The only record is the last one, so it may be shorter than the usual record size.
+    Ok([
+        salt.as_slice(),
+        &4096u32.to_be_bytes(),
+        &[as_public.len() as u8],
+        as_public.as_bytes(),
+        &ciphertext,
+    ].concat())
+}
+
+/// Lifetime of a queued wake-up at the push service. Messages wait on the server regardless.
+const WEB_PUSH_TTL_SECS: u32 = 24 * 3600;
+
+/// Maps a push service response (RFC 8030) to a result.
+async fn web_push_result(response: reqwest::Response) -> Result<(), PushError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(());
+    }
+    let body = response.text().await.unwrap_or_default();
+
+    match status.as_u16() {
+        404 | 410 => Err(PushError::InvalidToken),
+        429 | 500..=599 => Err(PushError::Transient(format!("Push service returned {status}"))),
+        _ => Err(PushError::Failed(format!("Push service returned {status}: {body}"))),
+    }
+}
+
+/// Posts wake-ups straight to UnifiedPush endpoints, which relay them to the device's distributor.
+pub struct UnifiedPushProvider {
+    client: reqwest::Client,
+}
+
+impl UnifiedPushProvider {
+    pub fn new() -> Self {
+        Self { client: endpoint_client() }
+    }
+}
+
+impl Default for UnifiedPushProvider {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl PushProvider for UnifiedPushProvider {
+    async fn wake(&self, token: &str) -> Result<(), PushError> {
+        check_endpoint(token).await?;
+
+        let response = self.client
+            .post(token)
+            .header("TTL", WEB_PUSH_TTL_SECS)
+            .header("Urgency", "high")
+            .header("Topic", COLLAPSE_KEY)
+            .json(&json!({"type": "wake"}))
+            .send()
+            .await
+            .map_err(request_error)?;
+        web_push_result(response).await
+    }
+}
+
+#[derive(Serialize)]
+struct VapidClaims<'a> {
+    aud: &'a str,
+    exp: i64,
+    sub: &'a str,
+}
+
+/// Sends encrypted Web Push messages (RFC 8291), identifying the server with VAPID (RFC 8292).
+pub struct WebPushProvider {
+    client: reqwest::Client,
+    key: EncodingKey,
+    /// Base64url uncompressed public key, the `applicationServerKey` clients subscribe with.
+    public_key: String,
+    /// `mailto:` or `https:` contact for push services.
+    subject: String,
+}
+
+impl WebPushProvider {
+    /// `key` is a PKCS#8 PEM P-256 private key.
+    pub fn new(key: &str, subject: impl Into<String>) -> Result<Self, PushError> {
+        let invalid = |e: &dyn std::fmt::Display| PushError::Failed(format!("Invalid VAPID key: {e}"));
+        let secret = SecretKey::from_pkcs8_pem(key).map_err(|e| invalid(&e))?;
+
+        Ok(Self {
+            client: endpoint_client(),
+            key: EncodingKey::from_ec_pem(key.as_bytes()).map_err(|e| invalid(&e))?,
+            public_key: URL_SAFE_NO_PAD.encode(secret.public_key().to_encoded_point(false).as_bytes()),
+            subject: subject.into(),
+        })
+    }
+
+    pub fn public_key(&self) -> &str {
+        &self.public_key
+    }
+
+    fn vapid_header(&self, endpoint: &reqwest::Url) -> Result<String, PushError> {
+        let audience = endpoint.origin().ascii_serialization();
+        let token = jsonwebtoken::encode(
+            &Header::new(Algorithm::ES256),
+            &VapidClaims {
+                aud: &audience,
+                exp: chrono::Utc::now().timestamp() + 12 * 3600,
+                sub: &self.subject,
+            },
+            &self.key,
+        )
+            .map_err(|e| PushError::Failed(format!("Failed to sign VAPID token: {e}")))?;
+        Ok(format!("vapid t={token}, k={}", self.public_key))
+    }
+}
+
+#[async_trait]
+impl PushProvider for WebPushProvider {
+    async fn wake(&self, token: &str) -> Result<(), PushError> {
+        let subscription: WebPushSubscription = serde_json::from_str(token)
+            .map_err(|_| PushError::InvalidToken)?;
+        let (ua_public, auth_secret) = subscription.keys().ok_or(PushError::InvalidToken)?;
+        let endpoint = reqwest::Url::parse(&subscription.endpoint).map_err(|_| PushError::InvalidToken)?;
+        check_endpoint(endpoint.as_str()).await?;
+
+        let body = encrypt_web_push(&ua_public, &auth_secret, br#"{"type":"wake"}"#)?;
+        let response = self.client
+            .post(endpoint.clone())
+            .header("Authorization", self.vapid_header(&endpoint)?)
+            .header("Content-Encoding", "aes128gcm")
+            .header("Content-Type", "application/octet-stream")
+            .header("TTL", WEB_PUSH_TTL_SECS)
+            .header("Urgency", "high")
+            .header("Topic", COLLAPSE_KEY)
+            .body(body)
+            .send()
+            .await
+            .map_err(request_error)?;
+        web_push_result(response).await
+    }
+}
+
+/// Development sink: logs the pushes instead of sending them.
+pub struct LogPushProvider {
+    platform: PushPlatform,
+}
+
+impl LogPushProvider {
+    pub fn new(platform: PushPlatform) -> Self {
+        Self { platform }
+    }
+}
+
+#[async_trait]
+impl PushProvider for LogPushProvider {
+    async fn wake(&self, token: &str) -> Result<(), PushError> {
+        tracing::info!("Wake-up push to {} token {token}", self.platform.as_str());
+        Ok(())
+    }
+}
+
+/// Keeps every push in memory, so tests can check which tokens were woken. Tokens passed to
+/// [`MemoryPushProvider::invalidate`] fail like tokens the provider no longer knows, and those
+/// passed to [`MemoryPushProvider::fail_transiently`] like an unavailable provider.
+#[derive(Clone, Default)]
+pub struct MemoryPushProvider {
+    sent: Arc<Mutex<Vec<String>>>,
+    invalid: Arc<Mutex<HashSet<String>>>,
+    failures: Arc<Mutex<HashMap<String, u32>>>,
+}
+
+impl MemoryPushProvider {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns every token woken, oldest first.
+    pub fn sent(&self) -> Vec<String> {
+        self.sent.lock().unwrap().clone()
+    }
+
+    pub fn invalidate(&self, token: &str) {
+        self.invalid.lock().unwrap().insert(token.to_string());
+    }
+
+    /// Fails the next `times` pushes to `token` with a transient error.
+    pub fn fail_transiently(&self, token: &str, times: u32) {
+        self.failures.lock().unwrap().insert(token.to_string(), times);
+    }
+}
+
+#[async_trait]
+impl PushProvider for MemoryPushProvider {
+    async fn wake(&self, token: &str) -> Result<(), PushError> {
+        if self.invalid.lock().unwrap().contains(token) {
+            return Err(PushError::InvalidToken);
+        }
+        if let Some(left) = self.failures.lock().unwrap().get_mut(token).filter(|left| **left > 0) {
+            *left -= 1;
+            return Err(PushError::Transient("Simulated failure".into()));
+        }
+
+        self.sent.lock().unwrap().push(token.to_string());
+        Ok(())
+    }
+}
+
+/// One provider per [`PushPlatform`].
+pub struct PushProviders {
+    pub fcm: Arc<dyn PushProvider>,
+    pub apns: Arc<dyn PushProvider>,
+    pub unifiedpush: Arc<dyn PushProvider>,
+    pub webpush: Arc<dyn PushProvider>,
+}
+
+/// Routes wake-up pushes to each device's provider, retrying transient failures and forgetting
+/// tokens the provider reports as invalid.
+pub struct PushDispatcher {
+    pool: Pool<ConnectionManager<PgConnection>>,
+    providers: PushProviders,
+    /// VAPID public key clients subscribe to Web Push with, if Web Push is enabled.
+    vapid_public_key: Option<String>,
+    max_attempts: u32,
+    /// Delay before the first retry, doubled on each following one.
+    retry_delay: Duration,
+    /// How long a wake-up waits for further messages to the same device before it is sent.
+    debounce: Duration,
+    /// Devices with a wake-up waiting out the debounce window.
+    scheduled: Mutex<HashSet<Uuid>>,
+}
+
+/// After this long without a fetch, a wake-up is assumed lost and the device may be pushed again.
+const PENDING_WAKE_TIMEOUT_MINUTES: i64 = 60;
+
+impl PushDispatcher {
+    pub fn new(
+        pool: Pool<ConnectionManager<PgConnection>>,
+        providers: PushProviders,
+        vapid_public_key: Option<String>,
+    ) -> Self {
+        Self {
+            pool,
+            providers,
+            vapid_public_key,
+            max_attempts: 3,
+            retry_delay: Duration::from_secs(1),
+            debounce: Duration::from_secs(2),
+            scheduled: Mutex::new(HashSet::new()),
+        }
+    }
+
+    pub fn with_debounce(mut self, debounce: Duration) -> Self {
+        self.debounce = debounce;
+        self
+    }
+
+    pub fn with_retry_delay(mut self, retry_delay: Duration) -> Self {
+        self.retry_delay = retry_delay;
+        self
+    }
+
+    fn provider(&self, platform: PushPlatform) -> &dyn PushProvider {
+        match platform {
+            PushPlatform::Fcm => self.providers.fcm.as_ref(),
+            PushPlatform::Apns => self.providers.apns.as_ref(),
+            PushPlatform::UnifiedPush => self.providers.unifiedpush.as_ref(),
+            PushPlatform::WebPush => self.providers.webpush.as_ref(),
+        }
+    }
+
+    pub fn vapid_public_key(&self) -> Option<&str> {
+        self.vapid_public_key.as_deref()
+    }
+
+    /// Wakes `device_ids` in the background, once the debounce window has passed. Devices without
+    /// a push token, already waiting out the window, or woken and not fetched since, are skipped.
+    pub fn wake(self: &Arc<Self>, device_ids: Vec<Uuid>) {
+        let device_ids: Vec<Uuid> = {
+            let mut scheduled = self.scheduled.lock().unwrap();
+            device_ids.into_iter().filter(|id| scheduled.insert(*id)).collect()
+        };
+        if device_ids.is_empty() {
+            return;
+        }
+
+        let dispatcher = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(dispatcher.debounce).await;
+            // Devices that fetched while the window was open already have their messages.
+            let device_ids: Vec<Uuid> = {
+                let mut scheduled = dispatcher.scheduled.lock().unwrap();
+                device_ids.into_iter().filter(|id| scheduled.remove(id)).collect()
+            };
+            if device_ids.is_empty() {
+                return;
+            }
+
+            let targets = match dispatcher.claim_targets(&device_ids) {
+                Ok(targets) => targets,
+                Err(e) => {
+                    tracing::error!("Failed to load push targets: {e}");
+                    return;
+                }
+            };
+            for (device_id, platform, token) in targets {
+                let dispatcher = dispatcher.clone();
+                tokio::spawn(async move { dispatcher.deliver(device_id, platform, token).await });
+            }
+        });
+    }
+
+    /// Records that the device fetched its messages: the next message wakes it again, and a
+    /// wake-up still waiting out the debounce window is dropped.
+    pub fn fetched(&self, conn: &mut PgConnection, device_id: Uuid) -> QueryResult<()> {
+        self.scheduled.lock().unwrap().remove(&device_id);
+        clear_pending_wake(conn, device_id).map(|_| ())
+    }
+
+    /// Marks the devices that can be woken as having a wake-up outstanding, and returns them.
+    /// The mark is shared by every server instance, so a device is woken once until it fetches.
+    fn claim_targets(&self, device_ids: &[Uuid]) -> Result<Vec<(Uuid, PushPlatform, String)>, String> {
+        let mut conn = self.pool.get().map_err(|e| e.to_string())?;
+        let now = Utc::now();
+        let rows = diesel::update(devices::table
+            .filter(devices::id.eq_any(device_ids))
+            .filter(devices::is_revoked.is_distinct_from(true))
+            .filter(devices::push_token.is_not_null())
+            .filter(devices::push_platform.is_not_null())
+            .filter(devices::push_pending_since.is_null().or(
+                devices::push_pending_since.lt(now - chrono::Duration::minutes(PENDING_WAKE_TIMEOUT_MINUTES)),
+            )))
+            .set(devices::push_pending_since.eq(now))
+            .returning((devices::id, devices::push_platform, devices::push_token))
+            .get_results::<(Uuid, Option<String>, Option<String>)>(&mut conn)
+            .map_err(|e| e.to_string())?;
+
+        Ok(rows
+            .into_iter()
+            .filter_map(|(id, platform, token)| {
+                Some((id, PushPlatform::parse(platform.as_deref()?)?, token?))
+            })
+            .collect())
+    }
+
+    async fn deliver(&self, device_id: Uuid, platform: PushPlatform, token: String) {
+        let provider = self.provider(platform);
+        let mut delay = self.retry_delay;
+        for attempt in 1..=self.max_attempts {
+            match provider.wake(&token).await {
+                Ok(()) => return,
+                Err(PushError::InvalidToken) => {
+                    self.forget_token(device_id, &token);
+                    return;
+                }
+                Err(PushError::Transient(e)) if attempt < self.max_attempts => {
+                    tracing::warn!("Push to device {device_id} failed, retrying: {e}");
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+                Err(e) => {
+                    tracing::error!("Push to device {device_id} failed: {e}");
+                    // Nothing is outstanding, so the next message may try again.
+                    self.clear_pending(device_id);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn clear_pending(&self, device_id: Uuid) {
+        let cleared = self.pool.get().map_err(|e| e.to_string()).and_then(|mut conn| {
+            clear_pending_wake(&mut conn, device_id).map_err(|e| e.to_string())
+        });
+        if let Err(e) = cleared {
+            tracing::error!("Failed to clear pending wake-up of device {device_id}: {e}");
+        }
+    }
+
+    /// Clears the device's token, unless it registered a new one in the meantime.
+    fn forget_token(&self, device_id: Uuid, token: &str) {
+        let cleared = self.pool.get().map_err(|e| e.to_string()).and_then(|mut conn| {
+            diesel::update(devices::table
+                .find(device_id)
+                .filter(devices::push_token.eq(token)))
+                .set((
+                    devices::push_token.eq(None::<String>),
+                    devices::push_platform.eq(None::<String>),
+                ))
+                .execute(&mut conn)
+                .map_err(|e| e.to_string())
+        });
+        match cleared {
+            Ok(_) => tracing::info!("Cleared invalid push token of device {device_id}"),
+            Err(e) => tracing::error!("Failed to clear push token of device {device_id}: {e}"),
+        }
+    }
+}
+
+/// Records that the device fetched its messages, so the next message wakes it again.
+pub fn clear_pending_wake(conn: &mut PgConnection, device_id: Uuid) -> QueryResult<usize> {
+    diesel::update(devices::table
+        .find(device_id)
+        .filter(devices::push_pending_since.is_not_null()))
+        .set(devices::push_pending_since.eq(None::<chrono::DateTime<Utc>>))
+        .execute(conn)
+}
+
+impl PushProviders {
+    /// Sends every platform's pushes through `provider`.
+    pub fn all(provider: Arc<dyn PushProvider>) -> Self {
+        Self {
+            fcm: provider.clone(),
+            apns: provider.clone(),
+            unifiedpush: provider.clone(),
+            webpush: provider,
+        }
+    }
+
+    /// Builds the providers selected by `PUSH_PROVIDER` (`live` or `log`, defaults to `log`), with
+    /// the VAPID public key if Web Push is enabled.
+    ///
+    /// With `live`, FCM is enabled by `FCM_SERVICE_ACCOUNT_PATH`, APNs by `APNS_KEY_PATH` and Web
+    /// Push by `VAPID_PRIVATE_KEY_PATH`; a platform left unconfigured only logs its pushes.
+    /// UnifiedPush needs no credentials.
+    pub fn from_env() -> (Self, Option<String>) {
+        let provider = std::env::var("PUSH_PROVIDER").unwrap_or_else(|_| "log".into());
+        let log = |platform| Arc::new(LogPushProvider::new(platform)) as Arc<dyn PushProvider>;
+
+        match provider.as_str() {
+            "live" => {
+                let (webpush, vapid_public_key) = match webpush_from_env() {
+                    Some(webpush) => {
+                        let public_key = webpush.public_key().to_string();
+                        (Arc::new(webpush) as Arc<dyn PushProvider>, Some(public_key))
+                    }
+                    None => (log(PushPlatform::WebPush), None),
+                };
+                (Self {
+                    fcm: fcm_from_env(),
+                    apns: apns_from_env(),
+                    unifiedpush: Arc::new(UnifiedPushProvider::new()),
+                    webpush,
+                }, vapid_public_key)
+            }
+            "log" => (Self {
+                fcm: log(PushPlatform::Fcm),
+                apns: log(PushPlatform::Apns),
+                unifiedpush: log(PushPlatform::UnifiedPush),
+                webpush: log(PushPlatform::WebPush),
+            }, None),
+            other => panic!("Unknown PUSH_PROVIDER: {other}"),
+        }
+    }
+}
+
+/// Builds the dispatcher with [`PushProviders::from_env`], debouncing wake-ups by
+/// `PUSH_DEBOUNCE_MS` (2000 by default).
+pub fn from_env(pool: Pool<ConnectionManager<PgConnection>>) -> Arc<PushDispatcher> {
+    let (providers, vapid_public_key) = PushProviders::from_env();
+    let debounce = std::env::var("PUSH_DEBOUNCE_MS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(2000);
+    Arc::new(PushDispatcher::new(pool, providers, vapid_public_key)
+        .with_debounce(Duration::from_millis(debounce)))
+}
+
+fn fcm_from_env() -> Arc<dyn PushProvider> {
+    let Ok(path) = std::env::var("FCM_SERVICE_ACCOUNT_PATH") else {
+        tracing::warn!("FCM_SERVICE_ACCOUNT_PATH is not set, FCM pushes will only be logged");
+        return Arc::new(LogPushProvider::new(PushPlatform::Fcm));
+    };
+    let service_account = std::fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("Failed to read {path}: {e}"));
+
+    Arc::new(FcmPushProvider::new(
+        std::env::var("FCM_ENDPOINT").unwrap_or_else(|_| "https://fcm.googleapis.com".into()),
+        &service_account,
+    ).unwrap_or_else(|e| panic!("{e}")))
+}
+
+fn apns_from_env() -> Arc<dyn PushProvider> {
+    let Ok(path) = std::env::var("APNS_KEY_PATH") else {
+        tracing::warn!("APNS_KEY_PATH is not set, APNs pushes will only be logged");
+        return Arc::new(LogPushProvider::new(PushPlatform::Apns));
+    };
+    let key = std::fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("Failed to read {path}: {e}"));
+
+    Arc::new(ApnsPushProvider::new(
+        std::env::var("APNS_ENDPOINT").unwrap_or_else(|_| "https://api.push.apple.com".into()),
+        &key,
+        std::env::var("APNS_KEY_ID").expect("APNS_KEY_ID must be set with APNS_KEY_PATH"),
+        std::env::var("APNS_TEAM_ID").expect("APNS_TEAM_ID must be set with APNS_KEY_PATH"),
+        std::env::var("APNS_TOPIC").expect("APNS_TOPIC must be set with APNS_KEY_PATH"),
+    ).unwrap_or_else(|e| panic!("{e}")))
+}
+
+fn webpush_from_env() -> Option<WebPushProvider> {
+    let Ok(path) = std::env::var("VAPID_PRIVATE_KEY_PATH") else {
+        tracing::warn!("VAPID_PRIVATE_KEY_PATH is not set, Web Push pushes will only be logged");
+        return None;
+    };
+    let key = std::fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("Failed to read {path}: {e}"));
+
+    Some(WebPushProvider::new(
+        &key,
+        std::env::var("VAPID_SUBJECT").expect("VAPID_SUBJECT must be set with VAPID_PRIVATE_KEY_PATH"),
+    ).unwrap_or_else(|e| panic!("{e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::users;
+
+    struct Fixture {
+        pool: Pool<ConnectionManager<PgConnection>>,
+        user_id: Uuid,
+    }
+
+    impl Fixture {
+        fn new() -> Option<Self> {
+            let url = std::env::var("DATABASE_URL").ok()?;
+            let pool = Pool::builder().max_size(2).build(ConnectionManager::new(url)).unwrap();
+            let user_id = diesel::insert_into(users::table)
+                .values(users::phone_number.eq(format!("+4915{:09}", rand::rng().random_range(0..1_000_000_000))))
+                .returning(users::id)
+                .get_result(&mut pool.get().unwrap())
+                .unwrap();
+            Some(Self { pool, user_id })
+        }
+
+        fn device(&self, platform: PushPlatform, token: &str) -> Uuid {
+            diesel::insert_into(devices::table)
+                .values((
+                    devices::user_id.eq(self.user_id),
+                    devices::name.eq("test"),
+                    devices::identity_key_pub.eq(vec![0u8; 32]),
+                    devices::signed_prekey_pub.eq(vec![0u8; 32]),
+                    devices::signed_prekey_signature.eq(vec![0u8; 64]),
+                    devices::push_platform.eq(platform.as_str()),
+                    devices::push_token.eq(token),
+                ))
+                .returning(devices::id)
+                .get_result(&mut self.pool.get().unwrap())
+                .unwrap()
+        }
+
+        fn push_state(&self, device_id: Uuid) -> (Option<String>, bool) {
+            let (token, pending) = devices::table
+                .find(device_id)
+                .select((devices::push_token, devices::push_pending_since))
+                .first::<(Option<String>, Option<chrono::DateTime<Utc>>)>(&mut self.pool.get().unwrap())
+                .unwrap();
+            (token, pending.is_some())
+        }
+
+        fn dispatcher(&self, providers: PushProviders) -> Arc<PushDispatcher> {
+            Arc::new(PushDispatcher::new(self.pool.clone(), providers, None)
+                .with_debounce(Duration::from_millis(10))
+                .with_retry_delay(Duration::from_millis(10)))
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            if let Ok(mut conn) = self.pool.get() {
+                let _ = diesel::delete(users::table.find(self.user_id)).execute(&mut conn);
+            }
+        }
+    }
+
+    /// Polls `done` until it holds, for up to two seconds.
+    async fn eventually(done: impl Fn() -> bool) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while Instant::now() < deadline {
+            if done() {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        done()
+    }
+
+    fn decode(value: &str) -> Vec<u8> {
+        URL_SAFE_NO_PAD.decode(value).unwrap()
+    }
+
+    #[test]
+    fn encrypts_the_rfc_8291_example() {
+        // RFC 8291, Appendix A.
+        let ua_public = PublicKey::from_sec1_bytes(&decode(
+            "BCVxsr7N_eNgVRqvHtD0zTZsEc6-VV-JvLexhqUzORcxaOzi6-AYWXvTBHm4bjyPjs7Vd8pZGH6SRpkNtoIAiw4",
+        )).unwrap();
+        let as_secret = SecretKey::from_slice(&decode("yfWPiYE-n46HLnH0KqZOF1fJJU3MYrct3AELtAQ-oRw")).unwrap();
+        let salt: [u8; 16] = decode("DGv6ra1nlYgDCS1FRnbzlw").try_into().unwrap();
+
+        let body = encrypt_web_push_with(
+            &ua_public,
+            &decode("BTBZMqHH6r4Tts7J_aSIgg"),
+            b"When I grow up, I want to be a watermelon",
+            &as_secret,
+            salt,
+        ).unwrap();
+        assert_eq!(
+            URL_SAFE_NO_PAD.encode(body),
+            "DGv6ra1nlYgDCS1FRnbzlwAAEABBBP4z9KsN6nGRTbVYI_c7VJSPQTBtkgcy27mlmlMoZIIgDll6e3vCYLocInmYWAmS6TlzAC8w\
+             EqKK6PBru3jl7A_yl95bQpu6cVPTpK4Mqgkf1CXztLVBSt2Ks3oZwbuwXPXLWyouBWLVWGNWQexSgSxsj_Qulcy4a-fN",
+        );
+    }
+
+    #[test]
+    fn validates_token_shapes() {
+        let subscription = |endpoint: &str| json!({
+            "endpoint": endpoint,
+            "keys": {
+                "p256dh": "BCVxsr7N_eNgVRqvHtD0zTZsEc6-VV-JvLexhqUzORcxaOzi6-AYWXvTBHm4bjyPjs7Vd8pZGH6SRpkNtoIAiw4",
+                "auth": "BTBZMqHH6r4Tts7J_aSIgg",
+            },
+        }).to_string();
+
+        assert!(PushPlatform::Fcm.is_valid_token("dGVzdA:APA91b-Hx_2k"));
+        assert!(!PushPlatform::Fcm.is_valid_token(""));
+        assert!(!PushPlatform::Fcm.is_valid_token("token/../x"));
+        assert!(!PushPlatform::Fcm.is_valid_token(&"a".repeat(MAX_FCM_TOKEN_LENGTH + 1)));
+        assert!(PushPlatform::Apns.is_valid_token(&"0f".repeat(32)));
+        assert!(!PushPlatform::Apns.is_valid_token(""));
+        for token in ["0f0f/../x", "0f0f?x=1", "0f0f#x", "token"] {
+            assert!(!PushPlatform::Apns.is_valid_token(token), "{token}");
+        }
+        assert!(PushPlatform::UnifiedPush.is_valid_token("https://push.example.com/abc"));
+        assert!(PushPlatform::WebPush.is_valid_token(&subscription("https://push.example.com/abc")));
+
+        for endpoint in [
+            "http://push.example.com/abc",
+            "https://127.0.0.1/abc",
+            "https://10.0.0.1/abc",
+            "https://169.254.169.254/latest/meta-data",
+            "https://[::1]/abc",
+            "https://[fd00::1]/abc",
+            "https://[::ffff:192.168.0.1]/abc",
+            "https://0.0.0.0/abc",
+            "not a url",
+        ] {
+            assert!(!PushPlatform::UnifiedPush.is_valid_token(endpoint), "{endpoint}");
+            assert!(!PushPlatform::WebPush.is_valid_token(&subscription(endpoint)), "{endpoint}");
+        }
+        let no_keys = json!({"endpoint": "https://push.example.com/abc", "keys": {"p256dh": "", "auth": ""}});
+        assert!(!PushPlatform::WebPush.is_valid_token(&no_keys.to_string()));
+    }
+
+    #[test]
+    fn recognizes_public_addresses() {
+        for ip in ["8.8.8.8", "1.1.1.1", "2606:4700::1111", "2a00:1450::1"] {
+            assert!(is_public_ip(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in [
+            "0.0.0.0", "127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254",
+            "100.64.0.1", "255.255.255.255", "240.0.0.1", "::", "::1", "fe80::1", "fc00::1",
+            "fd12:3456::1", "::ffff:127.0.0.1", "2001:db8::1",
+        ] {
+            assert!(!is_public_ip(ip.parse().unwrap()), "{ip}");
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_endpoints_resolving_to_private_addresses() {
+        assert!(matches!(check_endpoint("https://localhost/abc").await, Err(PushError::InvalidToken)));
+        assert!(matches!(
+            PushPlatform::UnifiedPush.check_endpoint("https://localhost:8443/abc").await,
+            Err(PushError::InvalidToken),
+        ));
+        assert!(PushPlatform::Fcm.check_endpoint("anything").await.is_ok());
+
+        // The client refuses to connect even if the name was only checked earlier.
+        let error = endpoint_client().post("https://localhost/abc").send().await.unwrap_err();
+        assert!(error.is_connect(), "{error}");
+    }
+
+    #[tokio::test]
+    async fn routes_each_device_to_its_platform() {
+        let Some(fixture) = Fixture::new() else { return };
+        let (fcm, apns, unifiedpush, webpush) =
+            (MemoryPushProvider::new(), MemoryPushProvider::new(), MemoryPushProvider::new(), MemoryPushProvider::new());
+        let dispatcher = fixture.dispatcher(PushProviders {
+            fcm: Arc::new(fcm.clone()),
+            apns: Arc::new(apns.clone()),
+            unifiedpush: Arc::new(unifiedpush.clone()),
+            webpush: Arc::new(webpush.clone()),
+        });
+        let devices = vec![
+            fixture.device(PushPlatform::Fcm, "fcm-token"),
+            fixture.device(PushPlatform::Apns, "apns-token"),
+            fixture.device(PushPlatform::UnifiedPush, "unifiedpush-token"),
+            fixture.device(PushPlatform::WebPush, "webpush-token"),
+        ];
+
+        dispatcher.wake(devices.clone());
+        assert!(eventually(|| [&fcm, &apns, &unifiedpush, &webpush].iter().all(|p| !p.sent().is_empty())).await);
+        assert_eq!(fcm.sent(), ["fcm-token"]);
+        assert_eq!(apns.sent(), ["apns-token"]);
+        assert_eq!(unifiedpush.sent(), ["unifiedpush-token"]);
+        assert_eq!(webpush.sent(), ["webpush-token"]);
+
+        // Each device stays woken until it fetches.
+        dispatcher.wake(devices.clone());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(fcm.sent().len(), 1);
+        assert!(devices.iter().all(|id| fixture.push_state(*id).1));
+    }
+
+    #[tokio::test]
+    async fn retries_transient_failures() {
+        let Some(fixture) = Fixture::new() else { return };
+        let push = MemoryPushProvider::new();
+        let dispatcher = fixture.dispatcher(PushProviders::all(Arc::new(push.clone())));
+        let recovers = fixture.device(PushPlatform::Fcm, "recovers");
+        let keeps_failing = fixture.device(PushPlatform::Fcm, "keeps-failing");
+        push.fail_transiently("recovers", 2);
+        push.fail_transiently("keeps-failing", 3);
+
+        dispatcher.wake(vec![recovers, keeps_failing]);
+        assert!(eventually(|| push.sent() == ["recovers"]).await);
+        assert!(fixture.push_state(recovers).1);
+        // Once the attempts are used up nothing is outstanding, so the next message tries again.
+        assert!(eventually(|| !fixture.push_state(keeps_failing).1).await);
+        assert_eq!(fixture.push_state(keeps_failing).0.as_deref(), Some("keeps-failing"));
+    }
+
+    #[tokio::test]
+    async fn clears_invalid_tokens() {
+        let Some(fixture) = Fixture::new() else { return };
+        let push = MemoryPushProvider::new();
+        let dispatcher = fixture.dispatcher(PushProviders::all(Arc::new(push.clone())));
+        let device_id = fixture.device(PushPlatform::Apns, "unregistered");
+        push.invalidate("unregistered");
+
+        dispatcher.wake(vec![device_id]);
+        assert!(eventually(|| fixture.push_state(device_id).0.is_none()).await);
+        assert!(push.sent().is_empty());
+    }
+}
@@ -0,0 +1,568 @@
+use crate::phone::normalize_phone;
+use crate::schema::rate_limit_buckets;
+use async_trait::async_trait;
+use axum::body::Body;
+use axum::extract::{ConnectInfo, Request, State};
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use chrono::Utc;
+use diesel::prelude::*;
+use diesel::r2d2::{ConnectionManager, Pool};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Request bodies larger than this are not inspected for a phone number.
+const MAX_INSPECTED_BODY: usize = 16 * 1024;
+
+/// A token bucket holding up to `capacity` tokens, refilled evenly over `period`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limit {
+    pub capacity: u32,
+    pub period: Duration,
+}
+
+impl Limit {
+    pub const fn new(capacity: u32, period_secs: u64) -> Self {
+        Self {
+            capacity,
+            period: Duration::from_secs(period_secs),
+        }
+    }
+
+    /// Parses `"<capacity>/<period in seconds>"`, e.g. `"5/600"`.
+    pub fn parse(value: &str) -> Option<Self> {
+        let (capacity, period) = value.trim().split_once('/')?;
+        let capacity = capacity.trim().parse().ok()?;
+        let period = period.trim().parse().ok()?;
+        if capacity == 0 || period == 0 {
+            return None;
+        }
+
+        Some(Self::new(capacity, period))
+    }
+
+    fn refill_per_sec(&self) -> f64 {
+        self.capacity as f64 / self.period.as_secs_f64()
+    }
+
+    /// Refills `tokens` for `elapsed` and tries to take `cost` of them.
+    /// Returns the remaining tokens, or how long to wait before `cost` tokens are available
+    /// ([`Duration::MAX`] if they never will be).
+    fn take(&self, tokens: f64, elapsed: Duration, cost: u32) -> Result<f64, Duration> {
+        let rate = self.refill_per_sec();
+        let tokens = (tokens + elapsed.as_secs_f64() * rate).min(self.capacity as f64);
+
+        if tokens >= cost as f64 {
+            Ok(tokens - cost as f64)
+        } else if cost > self.capacity {
+            Err(Duration::MAX)
+        } else {
+            Err(Duration::try_from_secs_f64((cost as f64 - tokens) / rate).unwrap_or(Duration::MAX))
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RateLimitError {
+    /// A bucket is empty; the caller must wait this long.
+    Exceeded(Duration),
+    /// The store failed and is configured to fail closed.
+    Unavailable(String),
+}
+
+impl std::fmt::Display for RateLimitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Exceeded(retry_after) => write!(f, "Rate limit exceeded, retry after {retry_after:?}"),
+            Self::Unavailable(e) => write!(f, "Rate limit store unavailable: {e}"),
+        }
+    }
+}
+
+impl RateLimitError {
+    /// Response of handlers returning a status and JSON body; `message` is shown when the limit
+    /// is exceeded.
+    pub fn to_json(&self, message: &str) -> (StatusCode, Json<Value>) {
+        match self {
+            Self::Exceeded(_) => (StatusCode::TOO_MANY_REQUESTS, Json(json!({
+                "message": message,
+                "status": 429,
+            }))),
+            Self::Unavailable(_) => (StatusCode::SERVICE_UNAVAILABLE, Json(json!({
+                "message": "Service temporarily unavailable",
+                "status": 503,
+            }))),
+        }
+    }
+}
+
+impl IntoResponse for RateLimitError {
+    fn into_response(self) -> Response {
+        let mut response = self.to_json("Too many requests, please retry later").into_response();
+        if let Self::Exceeded(retry_after) = self {
+            response.headers_mut().insert(
+                header::RETRY_AFTER,
+                HeaderValue::from(retry_after.as_secs().max(1)),
+            );
+        }
+        response
+    }
+}
+
+/// What a store does when it can't reach its backend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailurePolicy {
+    /// Let the request through, keeping the service available without limits.
+    Open,
+    /// Reject the request with [`RateLimitError::Unavailable`].
+    Closed,
+}
+
+/// Stores token buckets by key.
+#[async_trait]
+pub trait RateLimitStore: Send + Sync {
+    /// Takes `cost` tokens from every bucket in `buckets`, or from none of them if any is short,
+    /// in which case it returns the longest wait.
+    async fn acquire_all(&self, buckets: &[(String, Limit)], cost: u32) -> Result<(), RateLimitError>;
+
+    /// Takes `cost` tokens from the bucket `key`, or returns how long the caller must wait.
+    async fn acquire(&self, key: &str, limit: Limit, cost: u32) -> Result<(), RateLimitError> {
+        self.acquire_all(&[(key.to_string(), limit)], cost).await
+    }
+
+    /// Forgets buckets that haven't been touched for `idle` (they would be full by now anyway).
+    async fn purge(&self, idle: Duration);
+}
+
+#[derive(Default)]
+pub struct MemoryRateLimitStore {
+    buckets: Mutex<HashMap<String, (f64, Instant)>>,
+}
+
+impl MemoryRateLimitStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl RateLimitStore for MemoryRateLimitStore {
+    async fn acquire_all(&self, requested: &[(String, Limit)], cost: u32) -> Result<(), RateLimitError> {
+        let mut buckets = self.buckets.lock().unwrap();
+        let now = Instant::now();
+        let remaining = take_all(requested, cost, |key, limit| {
+            let (tokens, updated_at) = buckets
+                .get(key)
+                .copied()
+                .unwrap_or((limit.capacity as f64, now));
+            (tokens, now - updated_at)
+        })?;
+
+        for ((key, _), remaining) in requested.iter().zip(remaining) {
+            buckets.insert(key.clone(), (remaining, now));
+        }
+
+        Ok(())
+    }
+
+    async fn purge(&self, idle: Duration) {
+        let now = Instant::now();
+        self.buckets
+            .lock()
+            .unwrap()
+            .retain(|_, (_, updated_at)| now - *updated_at < idle);
+    }
+}
+
+/// Takes `cost` tokens from each bucket, given its stored tokens and the time since they were
+/// counted. Returns the remaining tokens of each bucket, or the longest wait if any is short.
+fn take_all(
+    buckets: &[(String, Limit)],
+    cost: u32,
+    mut state: impl FnMut(&str, Limit) -> (f64, Duration),
+) -> Result<Vec<f64>, RateLimitError> {
+    let mut remaining = Vec::with_capacity(buckets.len());
+    let mut retry_after = None;
+
+    for (key, limit) in buckets {
+        let (tokens, elapsed) = state(key, *limit);
+        match limit.take(tokens, elapsed, cost) {
+            Ok(r) => remaining.push(r),
+            Err(wait) => retry_after = Some(retry_after.map_or(wait, |w: Duration| w.max(wait))),
+        }
+    }
+
+    match retry_after {
+        Some(wait) => Err(RateLimitError::Exceeded(wait)),
+        None => Ok(remaining),
+    }
+}
+
+/// Keeps buckets in the `rate_limit_buckets` table, so limits are shared between server instances.
+pub struct PgRateLimitStore {
+    pool: Pool<ConnectionManager<PgConnection>>,
+    on_error: FailurePolicy,
+}
+
+impl PgRateLimitStore {
+    pub fn new(pool: Pool<ConnectionManager<PgConnection>>, on_error: FailurePolicy) -> Self {
+        Self { pool, on_error }
+    }
+}
+
+#[async_trait]
+impl RateLimitStore for PgRateLimitStore {
+    async fn acquire_all(&self, requested: &[(String, Limit)], cost: u32) -> Result<(), RateLimitError> {
+        let pool = self.pool.clone();
+        // Locking in key order keeps concurrent requests sharing buckets from deadlocking.
+        let mut requested = requested.to_vec();
+        requested.sort_by(|a, b| a.0.cmp(&b.0));
+        requested.dedup_by(|a, b| a.0 == b.0);
+
+        let result = tokio::task::spawn_blocking(move || {
+            let mut conn = pool.get().map_err(|e| e.to_string())?;
+            conn.transaction::<_, diesel::result::Error, _>(|conn| {
+                let now = Utc::now();
+                let rows: Vec<_> = requested
+                    .iter()
+                    .map(|(key, limit)| (
+                        rate_limit_buckets::key.eq(key),
+                        rate_limit_buckets::tokens.eq(limit.capacity as f64),
+                        rate_limit_buckets::updated_at.eq(now),
+                    ))
+                    .collect();
+                diesel::insert_into(rate_limit_buckets::table)
+                    .values(&rows)
+                    .on_conflict_do_nothing()
+                    .execute(conn)?;
+
+                let stored: HashMap<String, (f64, chrono::DateTime<Utc>)> = rate_limit_buckets::table
+                    .filter(rate_limit_buckets::key.eq_any(requested.iter().map(|(key, _)| key)))
+                    .order(rate_limit_buckets::key)
+                    .select((rate_limit_buckets::key, rate_limit_buckets::tokens, rate_limit_buckets::updated_at))
+                    .for_update()
+                    .load::<(String, f64, chrono::DateTime<Utc>)>(conn)?
+                    .into_iter()
+                    .map(|(key, tokens, updated_at)| (key, (tokens, updated_at)))
+                    .collect();
+
+                let taken = take_all(&requested, cost, |key, limit| {
+                    stored
+                        .get(key)
+                        .map(|(tokens, updated_at)| (*tokens, (now - *updated_at).to_std().unwrap_or_default()))
+                        .unwrap_or((limit.capacity as f64, Duration::ZERO))
+                });
+                let remaining = match taken {
+                    Ok(remaining) => remaining,
+                    Err(e) => return Ok(Err(e)),
+                };
+
+                for ((key, _), remaining) in requested.iter().zip(remaining) {
+                    diesel::update(rate_limit_buckets::table.filter(rate_limit_buckets::key.eq(key)))
+                        .set((
+                            rate_limit_buckets::tokens.eq(remaining),
+                            rate_limit_buckets::updated_at.eq(now),
+                        ))
+                        .execute(conn)?;
+                }
+                Ok(Ok(()))
+            })
+                .map_err(|e| e.to_string())
+        })
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|r| r);
+
+        match result {
+            Ok(limited) => limited,
+            Err(e) if self.on_error == FailurePolicy::Open => {
+                tracing::error!("Rate limit lookup failed, letting the request through: {e}");
+                Ok(())
+            }
+            Err(e) => {
+                tracing::error!("Rate limit lookup failed, rejecting the request: {e}");
+                Err(RateLimitError::Unavailable(e))
+            }
+        }
+    }
+
+    async fn purge(&self, idle: Duration) {
+        let pool = self.pool.clone();
+        let cutoff = Utc::now() - chrono::Duration::from_std(idle).unwrap_or(chrono::Duration::days(1));
+
+        let result = tokio::task::spawn_blocking(move || {
+            let mut conn = pool.get().map_err(|e| e.to_string())?;
+            diesel::delete(rate_limit_buckets::table.filter(rate_limit_buckets::updated_at.lt(cutoff)))
+                .execute(&mut conn)
+                .map_err(|e| e.to_string())
+        })
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|r| r);
+        if let Err(e) = result {
+            tracing::error!("Failed to purge rate limit buckets: {e}");
+        }
+    }
+}
+
+/// Per-route limits; a `None` limit isn't enforced.
+#[derive(Debug, Clone, Copy)]
+pub struct RouteLimits {
+    pub ip: Option<Limit>,
+    pub phone: Option<Limit>,
+    pub prefix: Option<Limit>,
+}
+
+impl RouteLimits {
+    /// Overrides the defaults with `RATE_LIMIT_<ROUTE>_{IP,PHONE,PREFIX}` (`"5/600"`, or `"off"`).
+    pub fn from_env(route: &str, defaults: RouteLimits) -> Self {
+        let read = |kind: &str, default: Option<Limit>| {
+            let name = format!("RATE_LIMIT_{}_{kind}", route.to_uppercase());
+            match std::env::var(&name) {
+                Ok(value) if value == "off" => None,
+                Ok(value) => Some(Limit::parse(&value).unwrap_or_else(|| panic!("Invalid {name}: {value}"))),
+                Err(_) => default,
+            }
+        };
+
+        Self {
+            ip: read("IP", defaults.ip),
+            phone: read("PHONE", defaults.phone),
+            prefix: read("PREFIX", defaults.prefix),
+        }
+    }
+}
+
+/// State of the [`rate_limit`] middleware for one route.
+#[derive(Clone)]
+pub struct RateLimiter {
+    pub store: Arc<dyn RateLimitStore>,
+    pub route: &'static str,
+    pub limits: RouteLimits,
+    /// Number of leading characters of the E.164 number grouped into one prefix bucket.
+    pub prefix_length: usize,
+    /// Number of reverse proxies in front of the server, see [`client_ip`].
+    pub proxy_hops: usize,
+}
+
+impl RateLimiter {
+    fn keys(&self, ip: Option<IpAddr>, phone: Option<&str>) -> Vec<(String, Limit)> {
+        let mut keys = Vec::new();
+
+        if let (Some(limit), Some(ip)) = (self.limits.ip, ip) {
+            keys.push((format!("{}:ip:{}", self.route, ip_bucket(ip)), limit));
+        }
+        if let Some(phone) = phone {
+            if let Some(limit) = self.limits.phone {
+                keys.push((format!("{}:phone:{phone}", self.route), limit));
+            }
+            if let Some(limit) = self.limits.prefix {
+                let prefix: String = phone.chars().take(self.prefix_length).collect();
+                keys.push((format!("{}:prefix:{prefix}", self.route), limit));
+            }
+        }
+
+        keys
+    }
+}
+
+/// Returns the address of the client.
+///
+/// Behind `proxy_hops` reverse proxies, each appending the address it received the request from
+/// to `X-Forwarded-For`, the client is the `proxy_hops`-th entry from the right; entries further
+/// left were sent by the client and can't be trusted. Without proxies, or when the header has
+/// fewer entries than expected, this is the peer address.
+pub fn client_ip(headers: &HeaderMap, peer: Option<SocketAddr>, proxy_hops: usize) -> Option<IpAddr> {
+    if proxy_hops > 0 {
+        let forwarded = headers
+            .get_all("X-Forwarded-For")
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .collect::<Vec<_>>();
+        let forwarded = forwarded
+            .len()
+            .checked_sub(proxy_hops)
+            .and_then(|i| forwarded[i].trim().parse().ok());
+        if forwarded.is_some() {
+            return forwarded;
+        }
+    }
+
+    peer.map(|addr| addr.ip())
+}
+
+/// IPv6 clients usually get a whole /64, so they share one bucket.
+fn ip_bucket(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(v4) => v4.to_string(),
+        IpAddr::V6(v6) => {
+            let s = v6.segments();
+            format!("{:x}:{:x}:{:x}:{:x}::/64", s[0], s[1], s[2], s[3])
+        }
+    }
+}
+
+/// Rejects requests with `429 Too Many Requests` and a `Retry-After` header once any of the
+/// client IP, phone number or phone prefix buckets of the route is empty.
+///
+/// The phone number is read from the `phone_number` field of the JSON body and normalized first,
+/// so different spellings of a number share a bucket.
+pub async fn rate_limit(State(limiter): State<RateLimiter>, request: Request, next: Next) -> Response {
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| *addr);
+    let ip = client_ip(request.headers(), peer, limiter.proxy_hops);
+
+    let (parts, body) = request.into_parts();
+    let bytes = match axum::body::to_bytes(body, MAX_INSPECTED_BODY).await {
+        Ok(b) => b,
+        Err(_) => return (StatusCode::PAYLOAD_TOO_LARGE, Json(json!({
+            "message": "Request body too large",
+            "status": 413,
+        }))).into_response(),
+    };
+    let phone = serde_json::from_slice::<serde_json::Value>(&bytes)
+        .ok()
+        .and_then(|v| v.get("phone_number").and_then(|p| p.as_str()).and_then(normalize_phone));
+
+    if let Err(e) = limiter.store.acquire_all(&limiter.keys(ip, phone.as_deref()), 1).await {
+        return e.into_response();
+    }
+
+    next.run(Request::from_parts(parts, Body::from(bytes))).await
+}
+
+/// Builds the store selected by `RATE_LIMIT_BACKEND` (`memory` or `postgres`, defaults to `memory`).
+///
+/// `RATE_LIMIT_ON_ERROR` (`open` or `closed`, defaults to `closed`) sets the [`FailurePolicy`] of
+/// the `postgres` store.
+pub fn store_from_env(pool: Pool<ConnectionManager<PgConnection>>) -> Arc<dyn RateLimitStore> {
+    let on_error = match std::env::var("RATE_LIMIT_ON_ERROR").as_deref() {
+        Ok("open") => FailurePolicy::Open,
+        Ok("closed") | Err(_) => FailurePolicy::Closed,
+        Ok(other) => panic!("Unknown RATE_LIMIT_ON_ERROR: {other}"),
+    };
+
+    match std::env::var("RATE_LIMIT_BACKEND").as_deref() {
+        Ok("postgres") => Arc::new(PgRateLimitStore::new(pool, on_error)),
+        Ok("memory") | Err(_) => Arc::new(MemoryRateLimitStore::new()),
+        Ok(other) => panic!("Unknown RATE_LIMIT_BACKEND: {other}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(names: &[&str], limit: Limit) -> Vec<(String, Limit)> {
+        names.iter().map(|name| (name.to_string(), limit)).collect()
+    }
+
+    #[test]
+    fn parses_limits() {
+        assert_eq!(Limit::parse(" 5 / 600 "), Some(Limit::new(5, 600)));
+        assert_eq!(Limit::parse("0/600"), None);
+        assert_eq!(Limit::parse("5/0"), None);
+        assert_eq!(Limit::parse("5"), None);
+    }
+
+    #[test]
+    fn takes_and_refills() {
+        let limit = Limit::new(10, 100);
+        assert_eq!(limit.take(10.0, Duration::ZERO, 3), Ok(7.0));
+        // One token per 10 seconds, capped at the capacity.
+        assert_eq!(limit.take(0.0, Duration::from_secs(30), 3), Ok(0.0));
+        assert_eq!(limit.take(9.0, Duration::from_secs(1000), 0), Ok(10.0));
+        assert_eq!(limit.take(1.0, Duration::ZERO, 3), Err(Duration::from_secs(20)));
+        // More than the bucket ever holds.
+        assert_eq!(limit.take(10.0, Duration::ZERO, 11), Err(Duration::MAX));
+        assert_eq!(Limit::new(0, 100).take(0.0, Duration::from_secs(30), 1), Err(Duration::MAX));
+        assert_eq!(Limit::new(0, 100).take(0.0, Duration::ZERO, 0), Ok(0.0));
+    }
+
+    #[tokio::test]
+    async fn memory_store_empties_bucket() {
+        let store = MemoryRateLimitStore::new();
+        let limit = Limit::new(2, 3600);
+        assert!(store.acquire("a", limit, 1).await.is_ok());
+        assert!(store.acquire("a", limit, 1).await.is_ok());
+        assert!(matches!(store.acquire("a", limit, 1).await, Err(RateLimitError::Exceeded(_))));
+        assert!(store.acquire("b", limit, 1).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn memory_store_takes_all_or_nothing() {
+        let store = MemoryRateLimitStore::new();
+        let limit = Limit::new(1, 3600);
+        store.acquire("b", limit, 1).await.unwrap();
+
+        // "b" is empty, so "a" must keep its token.
+        assert!(store.acquire_all(&keys(&["a", "b"], limit), 1).await.is_err());
+        assert!(store.acquire("a", limit, 1).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn postgres_store_takes_all_or_nothing() {
+        let Ok(url) = std::env::var("DATABASE_URL") else { return };
+        let pool = Pool::builder().max_size(1).build(ConnectionManager::new(url)).unwrap();
+        let store = PgRateLimitStore::new(pool, FailurePolicy::Closed);
+        let prefix = uuid::Uuid::new_v4();
+        let (a, b) = (format!("test:{prefix}:a"), format!("test:{prefix}:b"));
+        let limit = Limit::new(1, 3600);
+
+        store.acquire(&b, limit, 1).await.unwrap();
+        let both = vec![(a.clone(), limit), (b.clone(), limit)];
+        assert!(matches!(store.acquire_all(&both, 1).await, Err(RateLimitError::Exceeded(_))));
+        assert!(store.acquire(&a, limit, 1).await.is_ok());
+        assert!(store.acquire(&a, limit, 1).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn postgres_store_applies_failure_policy() {
+        let unreachable = || Pool::builder()
+            .connection_timeout(Duration::from_millis(100))
+            .build_unchecked(ConnectionManager::new("postgres://nobody@127.0.0.1:1/none"));
+        let limit = Limit::new(1, 3600);
+
+        let open = PgRateLimitStore::new(unreachable(), FailurePolicy::Open);
+        assert!(open.acquire("a", limit, 1).await.is_ok());
+        let closed = PgRateLimitStore::new(unreachable(), FailurePolicy::Closed);
+        assert!(matches!(closed.acquire("a", limit, 1).await, Err(RateLimitError::Unavailable(_))));
+    }
+
+    #[test]
+    fn client_ip_counts_proxy_hops_from_the_right() {
+        let peer = Some(SocketAddr::from(([10, 0, 0, 1], 443)));
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Forwarded-For", HeaderValue::from_static("1.1.1.1, 2.2.2.2, 3.3.3.3"));
+
+        assert_eq!(client_ip(&headers, peer, 0), Some("10.0.0.1".parse().unwrap()));
+        assert_eq!(client_ip(&headers, peer, 1), Some("3.3.3.3".parse().unwrap()));
+        assert_eq!(client_ip(&headers, peer, 2), Some("2.2.2.2".parse().unwrap()));
+        // More hops than entries: the header wasn't set by the expected proxies.
+        assert_eq!(client_ip(&headers, peer, 4), Some("10.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn client_ip_ignores_spoofed_leading_entries() {
+        let peer = Some(SocketAddr::from(([10, 0, 0, 1], 443)));
+        let mut headers = HeaderMap::new();
+        headers.append("X-Forwarded-For", HeaderValue::from_static("6.6.6.6"));
+        headers.append("X-Forwarded-For", HeaderValue::from_static("4.4.4.4"));
+
+        assert_eq!(client_ip(&headers, peer, 1), Some("4.4.4.4".parse().unwrap()));
+    }
+
+    #[test]
+    fn groups_ipv6_by_64() {
+        assert_eq!(ip_bucket("2001:db8:1:2:3:4:5:6".parse().unwrap()), "2001:db8:1:2::/64");
+        assert_eq!(ip_bucket("192.0.2.1".parse().unwrap()), "192.0.2.1");
+    }
+}
@@ -0,0 +1,290 @@
+use crate::routes::v1::profile::release_profile_avatar;
+use crate::verification::{check_code, require_pow, send_code, CodePurpose};
+use crate::{AppState, AuthUser};
+use axum::extract::ConnectInfo;
+use axum::http::{HeaderMap, StatusCode};
+use axum::{Extension, Json};
+use diesel::prelude::*;
+use diesel::result::DatabaseErrorKind;
+use diesel::result::Error::DatabaseError;
+use e2ee_back::phone::normalize_phone;
+use e2ee_back::rate_limit::{client_ip, Limit};
+use e2ee_back::schema::{attachment_uploads, devices, profiles, users, verification_codes};
+use e2ee_back::system_messages;
+use serde::Deserialize;
+use serde_json::json;
+use std::net::SocketAddr;
+use uuid::Uuid;
+
+#[derive(Deserialize, Default)]
+pub struct RequestAccountDeletion {
+    challenge: Option<String>,
+    solution: Option<String>,
+}
+
+/// Sends a code to the account's phone number, to be passed to [`delete_account`].
+///
+/// Like registration, this may require a proof of work for the account's phone number.
+pub async fn request_account_deletion(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    payload: Option<Json<RequestAccountDeletion>>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let payload = payload.map(|Json(p)| p).unwrap_or_default();
+    if let Err(e) = state.rate_limits
+        .acquire(&format!("account_delete:user:{}", auth.user_id), Limit::new(3, 900), 1)
+        .await
+    {
+        return e.to_json("Please wait a bit for another code");
+    }
+
+    let mut conn = state.db.get().unwrap();
+    let phone = match users::table
+        .find(auth.user_id)
+        .select(users::phone_number)
+        .first::<String>(&mut conn)
+        .optional()
+        .unwrap()
+    {
+        Some(p) => p,
+        None => return (StatusCode::NOT_FOUND, Json(json!({
+            "message": "Account not found",
+            "status": 404,
+        }))),
+    };
+
+    let ip = client_ip(&headers, Some(peer), state.proxy_hops);
+    if let Err(e) = require_pow(&state, &phone, ip, payload.challenge.as_deref(), payload.solution.as_deref()) {
+        return e;
+    }
+
+    if let Err(e) = send_code(&state, &phone, CodePurpose::DeleteAccount).await {
+        return e;
+    }
+
+    (StatusCode::ACCEPTED, Json(json!({"success": true})))
+}
+
+#[derive(Deserialize)]
+pub struct DeleteAccount {
+    otp: String,
+}
+
+/// Deletes the account and everything linked to it: devices, their prekeys and every queued
+/// message sent to or by the user. Contacts receive an `account_deleted` system message.
+pub async fn delete_account(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    Json(payload): Json<DeleteAccount>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let mut conn = state.db.get().unwrap();
+    let phone = match users::table
+        .find(auth.user_id)
+        .select(users::phone_number)
+        .first::<String>(&mut conn)
+        .optional()
+        .unwrap()
+    {
+        Some(p) => p,
+        None => return (StatusCode::NOT_FOUND, Json(json!({
+            "message": "Account not found",
+            "status": 404,
+        }))),
+    };
+
+    if let Err(e) = check_code(&mut conn, &phone, CodePurpose::DeleteAccount, &payload.otp) {
+        return e;
+    }
+
+    let profile_avatars = profiles::table
+        .filter(profiles::user_id.eq(auth.user_id))
+        .select(profiles::avatar_hash)
+        .load::<Option<String>>(&mut conn)
+        .unwrap();
+
+    let result = conn.transaction::<_, diesel::result::Error, _>(|conn| {
+        let contacts = system_messages::contacts_of(conn, auth.user_id)?;
+        let uploads = diesel::delete(attachment_uploads::table.filter(attachment_uploads::user_id.eq(auth.user_id)))
+            .returning(attachment_uploads::id)
+            .get_results::<Uuid>(conn)?;
+
+        // Devices, prekeys and messages are removed by the ON DELETE CASCADE foreign keys.
+        diesel::delete(users::table.find(auth.user_id)).execute(conn)?;
+        diesel::delete(verification_codes::table.filter(verification_codes::phone_number.eq(&phone)))
+            .execute(conn)?;
+
+        let woken = system_messages::queue_for_users(conn, &contacts, &json!({
+            "type": "account_deleted",
+            "user_id": auth.user_id,
+        }))?;
+        Ok((uploads, woken))
+    });
+
+    let uploads = match result {
+        Ok((uploads, woken)) => {
+            state.push.wake(woken);
+            uploads
+        }
+        Err(e) => {
+            tracing::error!("Failed to delete account: {e}");
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({
+                "message": "Something went wrong",
+                "status": 500,
+            })));
+        }
+    };
+
+    for id in uploads {
+        if let Err(e) = state.uploads.remove(id) {
+            tracing::error!("Failed to remove staged upload {id}: {e}");
+        }
+    }
+
+    for hash in profile_avatars.into_iter().flatten() {
+        release_profile_avatar(&state, &mut conn, &hash).await;
+    }
+
+    (StatusCode::OK, Json(json!({"success": true})))
+}
+
+#[derive(Deserialize)]
+pub struct ChangePhone {
+    phone_number: String,
+    challenge: Option<String>,
+    solution: Option<String>,
+}
+
+/// Sends a code to the new phone number, to be passed to [`confirm_phone_change`].
+///
+/// Like registration, this may require a proof of work for the new phone number.
+pub async fn request_phone_change(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(payload): Json<ChangePhone>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let phone = match normalize_phone(&payload.phone_number) {
+        Some(p) => p,
+        None => return (StatusCode::BAD_REQUEST, Json(json!({
+            "message": "Invalid phone number",
+            "status": 400,
+        }))),
+    };
+
+    let mut conn = state.db.get().unwrap();
+    let owner = users::table
+        .filter(users::phone_number.eq(&phone))
+        .select(users::id)
+        .first::<Uuid>(&mut conn)
+        .optional()
+        .unwrap();
+    match owner {
+        Some(id) if id == auth.user_id => return (StatusCode::BAD_REQUEST, Json(json!({
+            "message": "This is already your phone number",
+            "status": 400,
+        }))),
+        Some(_) => return (StatusCode::CONFLICT, Json(json!({
+            "message": "Phone number already in use",
+            "status": 409,
+        }))),
+        None => {}
+    }
+
+    let ip = client_ip(&headers, Some(peer), state.proxy_hops);
+    if let Err(e) = require_pow(&state, &phone, ip, payload.challenge.as_deref(), payload.solution.as_deref()) {
+        return e;
+    }
+
+    if let Err(e) = send_code(&state, &phone, CodePurpose::ChangePhone).await {
+        return e;
+    }
+
+    (StatusCode::ACCEPTED, Json(json!({"success": true})))
+}
+
+#[derive(Deserialize)]
+pub struct ConfirmPhoneChange {
+    phone_number: String,
+    otp: String,
+    #[serde(default)]
+    notify_contacts: bool,
+}
+
+/// Moves the account to the verified new phone number, keeping its id, devices and contacts.
+///
+/// The user's other devices receive a `phone_number_changed` system message, and so do their
+/// contacts if `notify_contacts` is set (without the new number).
+pub async fn confirm_phone_change(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    Json(payload): Json<ConfirmPhoneChange>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let phone = match normalize_phone(&payload.phone_number) {
+        Some(p) => p,
+        None => return (StatusCode::BAD_REQUEST, Json(json!({
+            "message": "Invalid phone number",
+            "status": 400,
+        }))),
+    };
+
+    let mut conn = state.db.get().unwrap();
+    if let Err(e) = check_code(&mut conn, &phone, CodePurpose::ChangePhone, &payload.otp) {
+        return e;
+    }
+
+    let result = conn.transaction::<_, diesel::result::Error, _>(|conn| {
+        let updated = diesel::update(users::table.find(auth.user_id))
+            .set(users::phone_number.eq(&phone))
+            .execute(conn)?;
+        if updated == 0 {
+            return Ok(None);
+        }
+
+        let other_devices = devices::table
+            .filter(devices::user_id.eq(auth.user_id))
+            .filter(devices::id.ne(auth.device_id))
+            .filter(devices::is_revoked.is_distinct_from(true))
+            .select((devices::user_id, devices::id))
+            .load::<(Option<Uuid>, Uuid)>(conn)?;
+        let mut woken = system_messages::queue_for_devices(conn, &other_devices, &json!({
+            "type": "phone_number_changed",
+            "user_id": auth.user_id,
+            "phone_number": phone,
+        }))?;
+
+        if payload.notify_contacts {
+            let contacts = system_messages::contacts_of(conn, auth.user_id)?;
+            woken.extend(system_messages::queue_for_users(conn, &contacts, &json!({
+                "type": "phone_number_changed",
+                "user_id": auth.user_id,
+            }))?);
+        }
+        Ok(Some(woken))
+    });
+
+    match result {
+        Ok(Some(woken)) => {
+            state.push.wake(woken);
+            (StatusCode::OK, Json(json!({"success": true})))
+        }
+        Ok(None) => (StatusCode::NOT_FOUND, Json(json!({
+            "message": "Account not found",
+            "status": 404,
+        }))),
+        // The number was registered by someone else since the code was sent.
+        Err(DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => (StatusCode::CONFLICT, Json(json!({
+            "message": "Phone number already in use",
+            "status": 409,
+        }))),
+        Err(e) => {
+            tracing::error!("Failed to change phone number: {e}");
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({
+                "message": "Something went wrong",
+                "status": 500,
+            })))
+        }
+    }
+}
@@ -0,0 +1,637 @@
+use crate::{AppState, AuthUser};
+use axum::body::Bytes;
+use axum::extract::Path;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Redirect, Response};
+use axum::{Extension, Json};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::{DateTime, Duration, Utc};
+use diesel::prelude::*;
+use e2ee_back::attachments::{attachment_key, lock_quota, storage_used};
+use e2ee_back::schema::{attachment_uploads, attachments};
+use rand::Rng;
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use uuid::Uuid;
+
+/// Runs `insert` in a transaction, unless `size` more bytes would take the user over their
+/// storage quota. Quota checks of a user are serialized, so concurrent uploads can't all pass.
+fn with_quota<T>(
+    conn: &mut PgConnection,
+    state: &AppState,
+    user_id: Uuid,
+    size: i64,
+    insert: impl FnOnce(&mut PgConnection) -> QueryResult<T>,
+) -> Result<T, (StatusCode, Json<serde_json::Value>)> {
+    let result = conn.transaction::<_, diesel::result::Error, _>(|conn| {
+        lock_quota(conn, user_id)?;
+        let used = storage_used(conn, user_id)?;
+        if used + size > state.storage_quota {
+            return Ok(Err((StatusCode::PAYLOAD_TOO_LARGE, Json(json!({
+                "message": "Storage quota exceeded",
+                "status": 413,
+                "used": used,
+                "quota": state.storage_quota,
+            })))));
+        }
+        insert(conn).map(Ok)
+    });
+
+    result.unwrap_or_else(|e| {
+        tracing::error!("Failed to reserve storage: {e}");
+        Err(internal_error())
+    })
+}
+
+/// Stores the request body, already encrypted by the client, as a new attachment.
+///
+/// The returned download token is only stored hashed; senders pass it to recipients along with
+/// the attachment id and key inside the encrypted message.
+pub async fn upload_attachment(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    body: Bytes,
+) -> (StatusCode, Json<serde_json::Value>) {
+    if body.is_empty() {
+        return (StatusCode::BAD_REQUEST, Json(json!({
+            "message": "Empty attachment",
+            "status": 400,
+        })));
+    }
+
+    store_attachment(&state, auth.user_id, body.to_vec(), None).await
+}
+
+/// Stores `data` as a new attachment, counted against the user's quota, or in place of the
+/// resumable `upload` that reserved its space.
+///
+/// The row is recorded unconfirmed before the blob is written, so a blob is never left without a
+/// row to sweep it, and confirmed once the blob is stored.
+async fn store_attachment(
+    state: &AppState,
+    user_id: Uuid,
+    data: Vec<u8>,
+    upload: Option<Uuid>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let id = Uuid::new_v4();
+    let token = URL_SAFE_NO_PAD.encode(rand::rng().random::<[u8; 32]>());
+    let size = data.len() as i64;
+
+    let mut conn = match state.db.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            tracing::error!("Failed to get a database connection: {e}");
+            return internal_error();
+        }
+    };
+    let insert = |conn: &mut PgConnection| diesel::insert_into(attachments::table)
+        .values((
+            attachments::id.eq(id),
+            attachments::uploader_user_id.eq(user_id),
+            attachments::size.eq(size),
+            attachments::download_token_hash.eq(Sha256::digest(token.as_bytes()).to_vec()),
+            attachments::confirmed.eq(false),
+        ))
+        .execute(conn);
+    let recorded = match upload {
+        None => with_quota(&mut conn, state, user_id, size, insert),
+        // Claiming the upload in the same transaction lets only one concurrent finalize through.
+        Some(upload) => conn
+            .transaction::<_, diesel::result::Error, _>(|conn| {
+                let claimed = diesel::delete(attachment_uploads::table
+                    .find(upload)
+                    .filter(attachment_uploads::user_id.eq(user_id)))
+                    .execute(conn)?;
+                if claimed == 0 {
+                    return Ok(Err(upload_not_found()));
+                }
+                insert(conn).map(Ok)
+            })
+            .unwrap_or_else(|e| {
+                tracing::error!("Failed to record attachment: {e}");
+                Err(internal_error())
+            }),
+    };
+    if let Err(response) = recorded {
+        return response;
+    }
+    drop(conn);
+
+    if let Err(e) = state.storage.put(&attachment_key(id), data).await {
+        tracing::error!("Failed to store attachment: {e}");
+        // Left unconfirmed, the row is swept along with anything partially written.
+        return internal_error();
+    }
+
+    let confirmed = state.db.get().map_err(|e| e.to_string()).and_then(|mut conn| {
+        diesel::update(attachments::table.find(id))
+            .set(attachments::confirmed.eq(true))
+            .execute(&mut conn)
+            .map_err(|e| e.to_string())
+    });
+    if let Err(e) = confirmed {
+        tracing::error!("Failed to confirm attachment {id}: {e}");
+        return internal_error();
+    }
+
+    (StatusCode::OK, Json(json!({
+        "attachment_id": id,
+        "download_token": token,
+        "size": size,
+    })))
+}
+
+/// Returns the attachment blob, given the download token in `X-Attachment-Token`, or redirects
+/// to a short-lived URL if the storage backend can serve it directly.
+pub async fn get_attachment(
+    state: Extension<AppState>,
+    _auth: AuthUser,
+    headers: HeaderMap,
+    Path(id): Path<Uuid>,
+) -> Response {
+    let not_found = || (StatusCode::NOT_FOUND, Json(json!({
+        "message": "Attachment not found",
+        "status": 404,
+    }))).into_response();
+
+    let mut conn = state.db.get().unwrap();
+    let Some(token_hash) = attachments::table
+        .find(id)
+        .filter(attachments::confirmed.eq(true))
+        .select(attachments::download_token_hash)
+        .first::<Vec<u8>>(&mut conn)
+        .optional()
+        .unwrap()
+    else {
+        return not_found();
+    };
+
+    let token = headers
+        .get("X-Attachment-Token")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    let matches: bool = Sha256::digest(token.as_bytes())
+        .as_slice()
+        .ct_eq(&token_hash)
+        .into();
+    if !matches {
+        // Same answer as an unknown id, so ids can't be probed without their token.
+        return not_found();
+    }
+
+    match state.storage.presigned_get_url(&attachment_key(id)) {
+        Ok(Some(url)) => return Redirect::temporary(&url).into_response(),
+        Ok(None) => {}
+        Err(e) => tracing::error!("Failed to presign attachment download: {e}"),
+    }
+
+    match state.storage.get(&attachment_key(id)).await {
+        Ok(Some(data)) => (
+            StatusCode::OK,
+            [
+                (header::CONTENT_TYPE, "application/octet-stream"),
+                (header::CACHE_CONTROL, "private, max-age=31536000, immutable"),
+            ],
+            data,
+        ).into_response(),
+        Ok(None) => not_found(),
+        Err(e) => {
+            tracing::error!("Failed to read attachment: {e}");
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({
+                "message": "Something went wrong",
+                "status": 500,
+            }))).into_response()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CreatePresignedUpload {
+    size: i64,
+}
+
+/// Creates an attachment whose content the client uploads straight to the storage backend, with
+/// a `PUT` of exactly `size` bytes to the returned `upload_url`, then confirms with
+/// [`confirm_presigned_upload`]. Until then the attachment can't be downloaded nor sent, and it is
+/// deleted if not confirmed within an hour.
+///
+/// Backends that can't presign URLs answer `501 Not Implemented`; clients then fall back to
+/// [`upload_attachment`] or [`create_upload`].
+pub async fn create_presigned_upload(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    Json(payload): Json<CreatePresignedUpload>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    if payload.size <= 0 {
+        return (StatusCode::BAD_REQUEST, Json(json!({
+            "message": "Invalid size",
+            "status": 400,
+        })));
+    }
+    if payload.size as u64 > state.attachment_max_size as u64 {
+        return (StatusCode::PAYLOAD_TOO_LARGE, Json(json!({
+            "message": format!("Attachments are limited to {} bytes", state.attachment_max_size),
+            "status": 413,
+        })));
+    }
+
+    let id = Uuid::new_v4();
+    let upload_url = match state.storage.presigned_put_url(&attachment_key(id), payload.size as u64) {
+        Ok(Some(url)) => url,
+        Ok(None) => return (StatusCode::NOT_IMPLEMENTED, Json(json!({
+            "message": "The storage backend doesn't support direct uploads",
+            "status": 501,
+        }))),
+        Err(e) => {
+            tracing::error!("Failed to presign attachment upload: {e}");
+            return internal_error();
+        }
+    };
+
+    let token = URL_SAFE_NO_PAD.encode(rand::rng().random::<[u8; 32]>());
+    let mut conn = state.db.get().unwrap();
+    let recorded = with_quota(&mut conn, &state, auth.user_id, payload.size, |conn| {
+        diesel::insert_into(attachments::table)
+            .values((
+                attachments::id.eq(id),
+                attachments::uploader_user_id.eq(auth.user_id),
+                attachments::size.eq(payload.size),
+                attachments::download_token_hash.eq(Sha256::digest(token.as_bytes()).to_vec()),
+                attachments::confirmed.eq(false),
+            ))
+            .execute(conn)
+    });
+    if let Err(response) = recorded {
+        return response;
+    }
+
+    (StatusCode::OK, Json(json!({
+        "attachment_id": id,
+        "download_token": token,
+        "size": payload.size,
+        "upload_url": upload_url,
+    })))
+}
+
+/// Confirms a presigned upload once the blob is in storage with the announced size. A blob of
+/// another size is deleted along with the attachment.
+pub async fn confirm_presigned_upload(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    Path(id): Path<Uuid>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let not_found = || (StatusCode::NOT_FOUND, Json(json!({
+        "message": "Attachment not found",
+        "status": 404,
+    })));
+
+    let mut conn = state.db.get().unwrap();
+    let Some((size, confirmed)) = attachments::table
+        .find(id)
+        .filter(attachments::uploader_user_id.eq(auth.user_id))
+        .select((attachments::size, attachments::confirmed))
+        .first::<(i64, bool)>(&mut conn)
+        .optional()
+        .unwrap()
+    else {
+        return not_found();
+    };
+    if confirmed {
+        return (StatusCode::OK, Json(json!({"success": true})));
+    }
+    drop(conn);
+
+    let stored = match state.storage.size(&attachment_key(id)).await {
+        Ok(stored) => stored,
+        Err(e) => {
+            tracing::error!("Failed to check presigned upload {id}: {e}");
+            return internal_error();
+        }
+    };
+
+    let mut conn = state.db.get().unwrap();
+    match stored {
+        None => (StatusCode::CONFLICT, Json(json!({
+            "message": "The attachment wasn't uploaded yet",
+            "status": 409,
+        }))),
+        Some(stored) if stored == size as u64 => {
+            let updated = diesel::update(attachments::table.find(id))
+                .set(attachments::confirmed.eq(true))
+                .execute(&mut conn)
+                .unwrap();
+            if updated == 0 {
+                // Swept as expired meanwhile.
+                return not_found();
+            }
+            (StatusCode::OK, Json(json!({"success": true})))
+        }
+        Some(_) => {
+            diesel::delete(attachments::table.find(id).filter(attachments::confirmed.eq(false)))
+                .execute(&mut conn)
+                .unwrap();
+            if let Err(e) = state.storage.delete(&attachment_key(id)).await {
+                tracing::error!("Failed to delete attachment {id}: {e}");
+            }
+            (StatusCode::UNPROCESSABLE_ENTITY, Json(json!({
+                "message": "Size mismatch, the attachment was discarded",
+                "status": 422,
+            })))
+        }
+    }
+}
+
+/// Returns how much of their storage quota the user's attachments and uploads in progress use.
+pub async fn get_storage_usage(
+    state: Extension<AppState>,
+    auth: AuthUser,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let mut conn = state.db.get().unwrap();
+    let used = storage_used(&mut conn, auth.user_id).unwrap();
+    let count = attachments::table
+        .filter(attachments::uploader_user_id.eq(auth.user_id))
+        .count()
+        .get_result::<i64>(&mut conn)
+        .unwrap();
+
+    (StatusCode::OK, Json(json!({
+        "used": used,
+        "quota": state.storage_quota,
+        "attachments": count,
+    })))
+}
+
+const UPLOAD_TTL_HOURS: i64 = 24;
+
+fn upload_not_found() -> (StatusCode, Json<serde_json::Value>) {
+    (StatusCode::NOT_FOUND, Json(json!({
+        "message": "Upload not found",
+        "status": 404,
+    })))
+}
+
+fn internal_error() -> (StatusCode, Json<serde_json::Value>) {
+    (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({
+        "message": "Something went wrong",
+        "status": 500,
+    })))
+}
+
+#[derive(Deserialize)]
+pub struct CreateUpload {
+    /// Total size of the encrypted attachment.
+    size: i64,
+}
+
+/// Starts a resumable upload, to be filled with [`append_upload_chunk`] and completed with
+/// [`finalize_upload`] within 24 hours.
+pub async fn create_upload(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    Json(payload): Json<CreateUpload>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    if payload.size <= 0 {
+        return (StatusCode::BAD_REQUEST, Json(json!({
+            "message": "Invalid size",
+            "status": 400,
+        })));
+    }
+    if payload.size as u64 > state.attachment_max_size as u64 {
+        return (StatusCode::PAYLOAD_TOO_LARGE, Json(json!({
+            "message": format!("Attachments are limited to {} bytes", state.attachment_max_size),
+            "status": 413,
+        })));
+    }
+
+    let mut conn = state.db.get().unwrap();
+    let expires_at = Utc::now() + Duration::hours(UPLOAD_TTL_HOURS);
+    let id = match with_quota(&mut conn, &state, auth.user_id, payload.size, |conn| {
+        diesel::insert_into(attachment_uploads::table)
+            .values((
+                attachment_uploads::user_id.eq(auth.user_id),
+                attachment_uploads::size.eq(payload.size),
+                attachment_uploads::expires_at.eq(expires_at),
+            ))
+            .returning(attachment_uploads::id)
+            .get_result::<Uuid>(conn)
+    }) {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+
+    (StatusCode::OK, Json(json!({
+        "upload_id": id,
+        "size": payload.size,
+        "offset": 0,
+        "expires_at": expires_at,
+    })))
+}
+
+/// Returns how much of the upload was received, i.e. where to resume.
+pub async fn get_upload(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    Path(id): Path<Uuid>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let mut conn = state.db.get().unwrap();
+    let upload = attachment_uploads::table
+        .find(id)
+        .filter(attachment_uploads::user_id.eq(auth.user_id))
+        .filter(attachment_uploads::expires_at.gt(Utc::now()))
+        .select((attachment_uploads::size, attachment_uploads::received, attachment_uploads::expires_at))
+        .first::<(i64, i64, DateTime<Utc>)>(&mut conn)
+        .optional()
+        .unwrap();
+
+    match upload {
+        Some((size, received, expires_at)) => (StatusCode::OK, Json(json!({
+            "upload_id": id,
+            "size": size,
+            "offset": received,
+            "expires_at": expires_at,
+        }))),
+        None => upload_not_found(),
+    }
+}
+
+/// Appends the request body at the offset given in `Upload-Offset`, which must be the current
+/// offset of the upload (`409 Conflict` with the current offset otherwise).
+pub async fn append_upload_chunk(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    headers: HeaderMap,
+    Path(id): Path<Uuid>,
+    body: Bytes,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let Some(offset) = headers
+        .get("Upload-Offset")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<i64>().ok())
+    else {
+        return (StatusCode::BAD_REQUEST, Json(json!({
+            "message": "Missing or invalid Upload-Offset header",
+            "status": 400,
+        })));
+    };
+    if body.is_empty() {
+        return (StatusCode::BAD_REQUEST, Json(json!({
+            "message": "Empty chunk",
+            "status": 400,
+        })));
+    }
+
+    let mut conn = state.db.get().unwrap();
+    let result = conn.transaction::<_, diesel::result::Error, _>(|conn| {
+        // Held until the chunk is written, so concurrent retries can't interleave.
+        let Some((size, received)) = attachment_uploads::table
+            .find(id)
+            .filter(attachment_uploads::user_id.eq(auth.user_id))
+            .filter(attachment_uploads::expires_at.gt(Utc::now()))
+            .select((attachment_uploads::size, attachment_uploads::received))
+            .for_update()
+            .first::<(i64, i64)>(conn)
+            .optional()?
+        else {
+            return Ok(upload_not_found());
+        };
+
+        if offset != received {
+            return Ok((StatusCode::CONFLICT, Json(json!({
+                "message": "Offset mismatch",
+                "status": 409,
+                "offset": received,
+            }))));
+        }
+        let end = received + body.len() as i64;
+        if end > size {
+            return Ok((StatusCode::BAD_REQUEST, Json(json!({
+                "message": "Chunk exceeds the upload size",
+                "status": 400,
+            }))));
+        }
+
+        if let Err(e) = state.uploads.write_at(id, offset as u64, &body) {
+            tracing::error!("Failed to stage upload chunk: {e}");
+            return Ok(internal_error());
+        }
+        diesel::update(attachment_uploads::table.find(id))
+            .set(attachment_uploads::received.eq(end))
+            .execute(conn)?;
+        Ok((StatusCode::OK, Json(json!({"offset": end}))))
+    });
+
+    result.unwrap_or_else(|e| {
+        tracing::error!("Failed to append upload chunk: {e}");
+        internal_error()
+    })
+}
+
+#[derive(Deserialize)]
+pub struct FinalizeUpload {
+    /// Hex SHA-256 of the whole encrypted attachment.
+    sha256: String,
+}
+
+/// Turns a complete upload into an attachment, once its content matches the client's digest.
+/// A mismatch discards the upload.
+pub async fn finalize_upload(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    Path(id): Path<Uuid>,
+    Json(payload): Json<FinalizeUpload>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let mut conn = state.db.get().unwrap();
+    let Some((size, received)) = attachment_uploads::table
+        .find(id)
+        .filter(attachment_uploads::user_id.eq(auth.user_id))
+        .filter(attachment_uploads::expires_at.gt(Utc::now()))
+        .select((attachment_uploads::size, attachment_uploads::received))
+        .first::<(i64, i64)>(&mut conn)
+        .optional()
+        .unwrap()
+    else {
+        return upload_not_found();
+    };
+    if received != size {
+        return (StatusCode::CONFLICT, Json(json!({
+            "message": "Upload incomplete",
+            "status": 409,
+            "offset": received,
+        })));
+    }
+
+    drop(conn);
+
+    let data = match state.uploads.read(id) {
+        Ok(data) => data,
+        // Finalized concurrently.
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return upload_not_found(),
+        Err(e) => {
+            tracing::error!("Failed to read staged upload: {e}");
+            return internal_error();
+        }
+    };
+
+    if !format!("{:x}", Sha256::digest(&data)).eq_ignore_ascii_case(payload.sha256.trim()) {
+        let mut conn = state.db.get().unwrap();
+        diesel::delete(attachment_uploads::table
+            .find(id)
+            .filter(attachment_uploads::user_id.eq(auth.user_id)))
+            .execute(&mut conn)
+            .unwrap();
+        if let Err(e) = state.uploads.remove(id) {
+            tracing::error!("Failed to remove staged upload {id}: {e}");
+        }
+        return (StatusCode::UNPROCESSABLE_ENTITY, Json(json!({
+            "message": "Digest mismatch, the upload was discarded",
+            "status": 422,
+        })));
+    }
+
+    let response = store_attachment(&state, auth.user_id, data, Some(id)).await;
+    // Otherwise the staged file is kept for a retry, or purged once its row is gone.
+    if response.0 == StatusCode::OK && let Err(e) = state.uploads.remove(id) {
+        tracing::error!("Failed to remove staged upload {id}: {e}");
+    }
+    response
+}
+
+pub async fn delete_upload(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    Path(id): Path<Uuid>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let mut conn = state.db.get().unwrap();
+    let deleted = diesel::delete(attachment_uploads::table
+        .find(id)
+        .filter(attachment_uploads::user_id.eq(auth.user_id)))
+        .execute(&mut conn)
+        .unwrap();
+
+    if deleted > 0 && let Err(e) = state.uploads.remove(id) {
+        tracing::error!("Failed to remove staged upload {id}: {e}");
+    }
+
+    (StatusCode::OK, Json(json!({"success": true})))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::TestApp;
+
+    #[tokio::test]
+    async fn attachments_that_cant_be_recorded_leave_no_blob() {
+        let Some(app) = TestApp::new() else { return };
+
+        // No such uploader, so the row insert fails on its foreign key.
+        let (status, _) = store_attachment(&app.state, Uuid::new_v4(), b"ciphertext".to_vec(), None).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+
+        let blobs = app.dir().join("blobs/attachments");
+        assert!(!blobs.exists() || std::fs::read_dir(blobs).unwrap().next().is_none());
+    }
+}
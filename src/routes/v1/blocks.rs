@@ -0,0 +1,127 @@
+use crate::{AppState, AuthUser};
+use axum::extract::Path;
+use axum::http::StatusCode;
+use axum::{Extension, Json};
+use diesel::prelude::*;
+use e2ee_back::schema::{blocks, devices, messages, users};
+use e2ee_back::system_messages;
+use serde_json::json;
+use uuid::Uuid;
+
+/// Tells the user's other devices to refetch the block list. Returns them, to wake once committed.
+pub fn sync_block_list(conn: &mut PgConnection, auth: &AuthUser) -> QueryResult<Vec<Uuid>> {
+    let other_devices = devices::table
+        .filter(devices::user_id.eq(auth.user_id))
+        .filter(devices::id.ne(auth.device_id))
+        .filter(devices::is_revoked.is_distinct_from(true))
+        .select((devices::user_id, devices::id))
+        .load::<(Option<Uuid>, Uuid)>(conn)?;
+
+    system_messages::queue_for_devices(conn, &other_devices, &json!({
+        "type": "blocks_changed",
+    }))
+}
+
+pub async fn get_blocks(state: Extension<AppState>, auth: AuthUser) -> (StatusCode, Json<serde_json::Value>) {
+    let mut conn = state.db.get().unwrap();
+    let blocked = blocks::table
+        .filter(blocks::blocker_user_id.eq(auth.user_id))
+        .order(blocks::created_at)
+        .select(blocks::blocked_user_id)
+        .load::<Uuid>(&mut conn)
+        .unwrap();
+
+    (StatusCode::OK, Json(json!({"data": blocked})))
+}
+
+/// Blocks `user_id`: their pending messages to the user are dropped, and they can neither send
+/// new ones nor fetch the user's prekeys.
+pub async fn block_user(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    Path(user_id): Path<Uuid>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    if user_id == auth.user_id {
+        return (StatusCode::BAD_REQUEST, Json(json!({
+            "message": "You cannot block yourself",
+            "status": 400,
+        })));
+    }
+
+    let mut conn = state.db.get().unwrap();
+    let result = conn.transaction::<_, diesel::result::Error, _>(|conn| {
+        let exists = users::table
+            .find(user_id)
+            .count()
+            .get_result::<i64>(conn)? > 0;
+        if !exists {
+            return Ok(None);
+        }
+
+        let inserted = diesel::insert_into(blocks::table)
+            .values((
+                blocks::blocker_user_id.eq(auth.user_id),
+                blocks::blocked_user_id.eq(user_id),
+            ))
+            .on_conflict_do_nothing()
+            .execute(conn)?;
+
+        diesel::delete(messages::table
+            .filter(messages::sender_user_id.eq(user_id))
+            .filter(messages::recipient_user_id.eq(auth.user_id))
+            .filter(messages::delivered_at.is_null()))
+            .execute(conn)?;
+
+        if inserted > 0 {
+            return sync_block_list(conn, &auth).map(Some);
+        }
+        Ok(Some(vec![]))
+    });
+
+    match result {
+        Ok(Some(woken)) => {
+            state.push.wake(woken);
+            (StatusCode::OK, Json(json!({"success": true})))
+        }
+        Ok(None) => (StatusCode::NOT_FOUND, Json(json!({
+            "message": "User not found",
+            "status": 404,
+        }))),
+        Err(e) => {
+            tracing::error!("Failed to block user: {e}");
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({
+                "message": "Something went wrong",
+                "status": 500,
+            })))
+        }
+    }
+}
+
+pub async fn unblock_user(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    Path(user_id): Path<Uuid>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let mut conn = state.db.get().unwrap();
+    let result = conn.transaction::<_, diesel::result::Error, _>(|conn| {
+        let deleted = diesel::delete(blocks::table.find((auth.user_id, user_id))).execute(conn)?;
+        if deleted > 0 {
+            return sync_block_list(conn, &auth);
+        }
+        Ok(vec![])
+    });
+
+    match result {
+        Ok(woken) => {
+            state.push.wake(woken);
+            (StatusCode::OK, Json(json!({"success": true})))
+        }
+        Err(e) => {
+            tracing::error!("Failed to unblock user: {e}");
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({
+                "message": "Something went wrong",
+                "status": 500,
+            })))
+        }
+    }
+}
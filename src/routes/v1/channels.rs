@@ -0,0 +1,452 @@
+use crate::{AppState, AuthUser};
+use axum::extract::{Path, Query};
+use axum::http::StatusCode;
+use axum::{Extension, Json};
+use base64::Engine;
+use diesel::dsl::exists;
+use diesel::prelude::*;
+use e2ee_back::schema::{channel_admins, channel_posts, channel_subscribers, channels, users};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use uuid::Uuid;
+
+const MAX_CHANNEL_STATE_SIZE: usize = 64 * 1024;
+const MAX_POST_SIZE: usize = 256 * 1024;
+const MAX_RETENTION_DAYS: i32 = 365;
+const MAX_POSTS_PER_PAGE: i64 = 100;
+
+fn decode(value: &str, max_size: usize) -> Option<Vec<u8>> {
+    base64::engine::general_purpose::STANDARD
+        .decode(value)
+        .ok()
+        .filter(|v| !v.is_empty() && v.len() <= max_size)
+}
+
+fn not_found() -> (StatusCode, Json<Value>) {
+    (StatusCode::NOT_FOUND, Json(json!({
+        "message": "Channel not found",
+        "status": 404,
+    })))
+}
+
+fn forbidden(message: &str) -> (StatusCode, Json<Value>) {
+    (StatusCode::FORBIDDEN, Json(json!({
+        "message": message,
+        "status": 403,
+    })))
+}
+
+fn is_admin(conn: &mut PgConnection, channel_id: Uuid, user_id: Uuid) -> QueryResult<bool> {
+    diesel::select(exists(channel_admins::table.find((channel_id, user_id)))).get_result(conn)
+}
+
+fn is_subscriber(conn: &mut PgConnection, channel_id: Uuid, user_id: Uuid) -> QueryResult<bool> {
+    diesel::select(exists(channel_subscribers::table.find((channel_id, user_id)))).get_result(conn)
+}
+
+fn owner_of(conn: &mut PgConnection, channel_id: Uuid) -> QueryResult<Option<Uuid>> {
+    channels::table
+        .find(channel_id)
+        .select(channels::owner_user_id)
+        .first::<Uuid>(conn)
+        .optional()
+}
+
+#[derive(Deserialize)]
+pub struct CreateChannel {
+    /// Base64 channel metadata, encrypted with the channel key.
+    state: String,
+    retention_days: Option<i32>,
+}
+
+/// Creates a channel owned, administered and subscribed to by the user.
+pub async fn create_channel(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    Json(payload): Json<CreateChannel>,
+) -> (StatusCode, Json<Value>) {
+    let Some(channel_state) = decode(&payload.state, MAX_CHANNEL_STATE_SIZE) else {
+        return (StatusCode::BAD_REQUEST, Json(json!({
+            "message": "Invalid channel state",
+            "status": 400,
+        })));
+    };
+    let retention_days = payload.retention_days.unwrap_or(30);
+    if !(1..=MAX_RETENTION_DAYS).contains(&retention_days) {
+        return (StatusCode::BAD_REQUEST, Json(json!({
+            "message": format!("Retention is 1 to {MAX_RETENTION_DAYS} days"),
+            "status": 400,
+        })));
+    }
+
+    let mut conn = state.db.get().unwrap();
+    let result = conn.transaction::<_, diesel::result::Error, _>(|conn| {
+        let channel_id = diesel::insert_into(channels::table)
+            .values((
+                channels::owner_user_id.eq(auth.user_id),
+                channels::state.eq(&channel_state),
+                channels::retention_days.eq(retention_days),
+            ))
+            .returning(channels::id)
+            .get_result::<Uuid>(conn)?;
+        diesel::insert_into(channel_admins::table)
+            .values((channel_admins::channel_id.eq(channel_id), channel_admins::user_id.eq(auth.user_id)))
+            .execute(conn)?;
+        diesel::insert_into(channel_subscribers::table)
+            .values((channel_subscribers::channel_id.eq(channel_id), channel_subscribers::user_id.eq(auth.user_id)))
+            .execute(conn)?;
+        Ok(channel_id)
+    });
+
+    match result {
+        Ok(channel_id) => (StatusCode::OK, Json(json!({"channel_id": channel_id}))),
+        Err(e) => {
+            tracing::error!("Failed to create channel: {e}");
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({
+                "message": "Something went wrong",
+                "status": 500,
+            })))
+        }
+    }
+}
+
+pub async fn get_channel(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    Path(channel_id): Path<Uuid>,
+) -> (StatusCode, Json<Value>) {
+    let mut conn = state.db.get().unwrap();
+    let Some((owner, channel_state, retention_days)) = channels::table
+        .find(channel_id)
+        .select((channels::owner_user_id, channels::state, channels::retention_days))
+        .first::<(Uuid, Vec<u8>, i32)>(&mut conn)
+        .optional()
+        .unwrap()
+    else {
+        return not_found();
+    };
+    let subscriber_count = channel_subscribers::table
+        .filter(channel_subscribers::channel_id.eq(channel_id))
+        .count()
+        .get_result::<i64>(&mut conn)
+        .unwrap();
+
+    (StatusCode::OK, Json(json!({
+        "channel_id": channel_id,
+        "owner_user_id": owner,
+        "state": base64::engine::general_purpose::STANDARD.encode(channel_state),
+        "retention_days": retention_days,
+        "subscriber_count": subscriber_count,
+        "subscribed": is_subscriber(&mut conn, channel_id, auth.user_id).unwrap(),
+        "admin": is_admin(&mut conn, channel_id, auth.user_id).unwrap(),
+    })))
+}
+
+#[derive(Deserialize)]
+pub struct UpdateChannel {
+    state: Option<String>,
+    retention_days: Option<i32>,
+}
+
+/// Updates the channel metadata or retention. Admins only.
+pub async fn update_channel(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    Path(channel_id): Path<Uuid>,
+    Json(payload): Json<UpdateChannel>,
+) -> (StatusCode, Json<Value>) {
+    let channel_state = match payload.state.as_deref().map(|s| decode(s, MAX_CHANNEL_STATE_SIZE)) {
+        Some(None) => return (StatusCode::BAD_REQUEST, Json(json!({
+            "message": "Invalid channel state",
+            "status": 400,
+        }))),
+        Some(Some(s)) => Some(s),
+        None => None,
+    };
+    if payload.retention_days.is_some_and(|d| !(1..=MAX_RETENTION_DAYS).contains(&d)) {
+        return (StatusCode::BAD_REQUEST, Json(json!({
+            "message": format!("Retention is 1 to {MAX_RETENTION_DAYS} days"),
+            "status": 400,
+        })));
+    }
+
+    let mut conn = state.db.get().unwrap();
+    if owner_of(&mut conn, channel_id).unwrap().is_none() {
+        return not_found();
+    }
+    if !is_admin(&mut conn, channel_id, auth.user_id).unwrap() {
+        return forbidden("Only admins can update the channel");
+    }
+
+    if let Some(channel_state) = channel_state {
+        diesel::update(channels::table.find(channel_id))
+            .set(channels::state.eq(channel_state))
+            .execute(&mut conn)
+            .unwrap();
+    }
+    if let Some(retention_days) = payload.retention_days {
+        diesel::update(channels::table.find(channel_id))
+            .set(channels::retention_days.eq(retention_days))
+            .execute(&mut conn)
+            .unwrap();
+    }
+
+    (StatusCode::OK, Json(json!({"success": true})))
+}
+
+/// Deletes the channel with all its posts. Owner only.
+pub async fn delete_channel(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    Path(channel_id): Path<Uuid>,
+) -> (StatusCode, Json<Value>) {
+    let mut conn = state.db.get().unwrap();
+    match owner_of(&mut conn, channel_id).unwrap() {
+        None => return not_found(),
+        Some(owner) if owner != auth.user_id => return forbidden("Only the owner can delete the channel"),
+        Some(_) => {}
+    }
+
+    diesel::delete(channels::table.find(channel_id))
+        .execute(&mut conn)
+        .unwrap();
+
+    (StatusCode::OK, Json(json!({"success": true})))
+}
+
+/// Makes `user_id` an admin, allowed to post. Owner only.
+pub async fn add_channel_admin(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    Path((channel_id, user_id)): Path<(Uuid, Uuid)>,
+) -> (StatusCode, Json<Value>) {
+    let mut conn = state.db.get().unwrap();
+    match owner_of(&mut conn, channel_id).unwrap() {
+        None => return not_found(),
+        Some(owner) if owner != auth.user_id => return forbidden("Only the owner can manage admins"),
+        Some(_) => {}
+    }
+    let user_exists = users::table
+        .find(user_id)
+        .count()
+        .get_result::<i64>(&mut conn)
+        .unwrap() > 0;
+    if !user_exists {
+        return (StatusCode::NOT_FOUND, Json(json!({
+            "message": "User not found",
+            "status": 404,
+        })));
+    }
+
+    diesel::insert_into(channel_admins::table)
+        .values((channel_admins::channel_id.eq(channel_id), channel_admins::user_id.eq(user_id)))
+        .on_conflict_do_nothing()
+        .execute(&mut conn)
+        .unwrap();
+
+    (StatusCode::OK, Json(json!({"success": true})))
+}
+
+pub async fn remove_channel_admin(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    Path((channel_id, user_id)): Path<(Uuid, Uuid)>,
+) -> (StatusCode, Json<Value>) {
+    let mut conn = state.db.get().unwrap();
+    match owner_of(&mut conn, channel_id).unwrap() {
+        None => return not_found(),
+        Some(owner) if owner != auth.user_id => return forbidden("Only the owner can manage admins"),
+        Some(owner) if owner == user_id => return (StatusCode::BAD_REQUEST, Json(json!({
+            "message": "The owner is always an admin",
+            "status": 400,
+        }))),
+        Some(_) => {}
+    }
+
+    diesel::delete(channel_admins::table.find((channel_id, user_id)))
+        .execute(&mut conn)
+        .unwrap();
+
+    (StatusCode::OK, Json(json!({"success": true})))
+}
+
+/// Returns the channels the user is subscribed to.
+pub async fn get_subscriptions(state: Extension<AppState>, auth: AuthUser) -> (StatusCode, Json<Value>) {
+    let mut conn = state.db.get().unwrap();
+    let subscriptions = channel_subscribers::table
+        .filter(channel_subscribers::user_id.eq(auth.user_id))
+        .order(channel_subscribers::subscribed_at)
+        .select(channel_subscribers::channel_id)
+        .load::<Uuid>(&mut conn)
+        .unwrap();
+
+    (StatusCode::OK, Json(json!({"data": subscriptions})))
+}
+
+pub async fn subscribe(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    Path(channel_id): Path<Uuid>,
+) -> (StatusCode, Json<Value>) {
+    let mut conn = state.db.get().unwrap();
+    if owner_of(&mut conn, channel_id).unwrap().is_none() {
+        return not_found();
+    }
+
+    diesel::insert_into(channel_subscribers::table)
+        .values((channel_subscribers::channel_id.eq(channel_id), channel_subscribers::user_id.eq(auth.user_id)))
+        .on_conflict_do_nothing()
+        .execute(&mut conn)
+        .unwrap();
+
+    (StatusCode::OK, Json(json!({"success": true})))
+}
+
+pub async fn unsubscribe(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    Path(channel_id): Path<Uuid>,
+) -> (StatusCode, Json<Value>) {
+    let mut conn = state.db.get().unwrap();
+    diesel::delete(channel_subscribers::table.find((channel_id, auth.user_id)))
+        .execute(&mut conn)
+        .unwrap();
+
+    (StatusCode::OK, Json(json!({"success": true})))
+}
+
+#[derive(Deserialize)]
+pub struct CreatePost {
+    /// Base64 post, encrypted with the channel key.
+    ciphertext: String,
+}
+
+/// Publishes a post, stored once for all subscribers. Admins only.
+pub async fn create_post(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    Path(channel_id): Path<Uuid>,
+    Json(payload): Json<CreatePost>,
+) -> (StatusCode, Json<Value>) {
+    let Some(ciphertext) = decode(&payload.ciphertext, MAX_POST_SIZE) else {
+        return (StatusCode::BAD_REQUEST, Json(json!({
+            "message": "Invalid ciphertext",
+            "status": 400,
+        })));
+    };
+
+    let mut conn = state.db.get().unwrap();
+    if owner_of(&mut conn, channel_id).unwrap().is_none() {
+        return not_found();
+    }
+    if !is_admin(&mut conn, channel_id, auth.user_id).unwrap() {
+        return forbidden("Only admins can post to this channel");
+    }
+
+    // Bumping the channel's counter locks its row until commit, so posts of a channel commit in
+    // `seq` order and a reader paging by `seq` can't skip one that commits late.
+    let result = conn.transaction::<_, diesel::result::Error, _>(|conn| {
+        let seq = diesel::update(channels::table.find(channel_id))
+            .set(channels::last_post_seq.eq(channels::last_post_seq + 1))
+            .returning(channels::last_post_seq)
+            .get_result::<i64>(conn)?;
+        diesel::insert_into(channel_posts::table)
+            .values((
+                channel_posts::channel_id.eq(channel_id),
+                channel_posts::seq.eq(seq),
+                channel_posts::author_user_id.eq(auth.user_id),
+                channel_posts::ciphertext.eq(&ciphertext),
+            ))
+            .returning(channel_posts::id)
+            .get_result::<i64>(conn)
+    });
+
+    match result {
+        Ok(post_id) => (StatusCode::OK, Json(json!({"post_id": post_id}))),
+        Err(e) => {
+            tracing::error!("Failed to create channel post: {e}");
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({
+                "message": "Something went wrong",
+                "status": 500,
+            })))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PostsQuery {
+    /// Cursor (a post `seq`) returned by the previous page; omitted to start from the oldest retained post.
+    after: Option<i64>,
+    limit: Option<i64>,
+}
+
+/// Returns posts in publication order, starting after the `after` cursor. Subscribers only.
+pub async fn get_posts(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    Path(channel_id): Path<Uuid>,
+    Query(query): Query<PostsQuery>,
+) -> (StatusCode, Json<Value>) {
+    let mut conn = state.db.get().unwrap();
+    if owner_of(&mut conn, channel_id).unwrap().is_none() {
+        return not_found();
+    }
+    if !is_subscriber(&mut conn, channel_id, auth.user_id).unwrap() {
+        return forbidden("Subscribe to the channel first");
+    }
+
+    let limit = query.limit.unwrap_or(MAX_POSTS_PER_PAGE).clamp(1, MAX_POSTS_PER_PAGE);
+    let posts = channel_posts::table
+        .filter(channel_posts::channel_id.eq(channel_id))
+        .filter(channel_posts::seq.gt(query.after.unwrap_or(0)))
+        .order(channel_posts::seq)
+        .limit(limit)
+        .select((
+            channel_posts::id,
+            channel_posts::seq,
+            channel_posts::author_user_id,
+            channel_posts::ciphertext,
+            channel_posts::created_at,
+        ))
+        .load::<(i64, i64, Option<Uuid>, Vec<u8>, chrono::DateTime<chrono::Utc>)>(&mut conn)
+        .unwrap();
+
+    let engine = base64::engine::general_purpose::STANDARD;
+    let next_cursor = posts.last().map(|(_, seq, ..)| *seq).or(query.after);
+    (StatusCode::OK, Json(json!({
+        "data": posts
+            .iter()
+            .map(|(id, seq, author, ciphertext, created_at)| json!({
+                "id": id,
+                "seq": seq,
+                "author_user_id": author,
+                "ciphertext": engine.encode(ciphertext),
+                "created_at": created_at,
+            }))
+            .collect::<Vec<_>>(),
+        "next_cursor": next_cursor,
+    })))
+}
+
+/// Deletes a post before its retention ends. Admins only.
+pub async fn delete_post(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    Path((channel_id, post_id)): Path<(Uuid, i64)>,
+) -> (StatusCode, Json<Value>) {
+    let mut conn = state.db.get().unwrap();
+    if owner_of(&mut conn, channel_id).unwrap().is_none() {
+        return not_found();
+    }
+    if !is_admin(&mut conn, channel_id, auth.user_id).unwrap() {
+        return forbidden("Only admins can delete posts");
+    }
+
+    diesel::delete(channel_posts::table
+        .filter(channel_posts::id.eq(post_id))
+        .filter(channel_posts::channel_id.eq(channel_id)))
+        .execute(&mut conn)
+        .unwrap();
+
+    (StatusCode::OK, Json(json!({"success": true})))
+}
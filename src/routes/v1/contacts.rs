@@ -0,0 +1,85 @@
+use crate::{AppState, AuthUser};
+use axum::http::{header, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::{Extension, Json};
+use base64::Engine;
+use diesel::prelude::*;
+use e2ee_back::rate_limit::{Limit, RateLimitError};
+use e2ee_back::schema::users;
+use serde::Deserialize;
+use serde_json::json;
+use uuid::Uuid;
+
+/// Length of the truncated `SHA-256(E.164 number)` clients send.
+const HASH_LENGTH: usize = 10;
+const MAX_HASHES_PER_REQUEST: usize = 1000;
+
+#[derive(Deserialize)]
+pub struct DiscoverContacts {
+    /// Base64 of the first 10 bytes of `SHA-256(E.164 number)` of each contact.
+    hashes: Vec<String>,
+}
+
+/// Returns the users registered with any of the submitted phone number hashes.
+///
+/// Every hash counts against a daily per-account quota (`DISCOVERY_QUOTA`) to make enumerating
+/// the phone number space impractical. Submitted hashes must never be logged.
+pub async fn discover_contacts(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    Json(payload): Json<DiscoverContacts>,
+) -> Response {
+    // A batch larger than the daily quota could never be served, however long the client waits.
+    let max_hashes = MAX_HASHES_PER_REQUEST.min(state.discovery_quota as usize);
+    if payload.hashes.len() > max_hashes {
+        return (StatusCode::BAD_REQUEST, Json(json!({
+            "message": format!("At most {max_hashes} hashes per request"),
+            "status": 400,
+        }))).into_response();
+    }
+
+    let engine = base64::engine::general_purpose::STANDARD;
+    let hashes = match payload.hashes
+        .iter()
+        .map(|h| engine.decode(h).ok().filter(|h| h.len() == HASH_LENGTH))
+        .collect::<Option<Vec<_>>>()
+    {
+        Some(h) => h,
+        None => return (StatusCode::BAD_REQUEST, Json(json!({
+            "message": format!("Hashes must be {HASH_LENGTH} bytes, base64 encoded"),
+            "status": 400,
+        }))).into_response(),
+    };
+    if hashes.is_empty() {
+        return (StatusCode::OK, Json(json!({"matches": []}))).into_response();
+    }
+
+    let quota = Limit::new(state.discovery_quota, 24 * 3600);
+    if let Err(e) = state.rate_limits
+        .acquire(&format!("discover:user:{}", auth.user_id), quota, hashes.len() as u32)
+        .await
+    {
+        let mut response = e.to_json("Contact discovery quota exceeded").into_response();
+        if let RateLimitError::Exceeded(wait) = e {
+            response.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(wait.as_secs().max(1)));
+        }
+        return response;
+    }
+
+    let mut conn = state.db.get().unwrap();
+    let matches = users::table
+        .filter(users::phone_number_hash.eq_any(&hashes))
+        .select((users::phone_number_hash, users::id))
+        .load::<(Option<Vec<u8>>, Uuid)>(&mut conn)
+        .unwrap();
+
+    let matches: Vec<_> = matches
+        .into_iter()
+        .filter_map(|(hash, id)| hash.map(|h| json!({
+            "hash": engine.encode(h),
+            "user_id": id,
+        })))
+        .collect();
+
+    (StatusCode::OK, Json(json!({"matches": matches}))).into_response()
+}
@@ -1,7 +1,10 @@
 use crate::{AppState, AuthUser};
+use axum::http::StatusCode;
 use axum::{Extension, Json};
 use diesel::prelude::*;
+use e2ee_back::push::{PushPlatform, WebPushSubscription};
 use e2ee_back::schema::devices;
+use serde::Deserialize;
 use serde_json::{json, Value};
 use uuid::Uuid;
 
@@ -20,3 +23,73 @@ pub async fn get_devices(state: Extension<AppState>, auth: AuthUser) -> Json<Val
         "data": results,
     }))
 }
+
+#[derive(Deserialize)]
+pub struct SetPushToken {
+    /// `fcm`, `apns`, `unifiedpush` or `webpush`.
+    platform: String,
+    /// FCM or APNs token, or UnifiedPush endpoint URL.
+    token: Option<String>,
+    /// Web Push subscription, in place of `token`.
+    subscription: Option<WebPushSubscription>,
+}
+
+/// Registers where this device receives wake-up pushes, replacing any previous token.
+pub async fn set_push_token(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    Json(payload): Json<SetPushToken>,
+) -> (StatusCode, Json<Value>) {
+    let Some(platform) = PushPlatform::parse(&payload.platform) else {
+        return (StatusCode::BAD_REQUEST, Json(json!({
+            "message": "Invalid push platform",
+            "status": 400,
+        })));
+    };
+    let token = match (platform, &payload.subscription) {
+        (PushPlatform::WebPush, Some(subscription)) => serde_json::to_string(subscription).unwrap(),
+        _ => payload.token.unwrap_or_default(),
+    };
+    if !platform.is_valid_token(&token) || platform.check_endpoint(&token).await.is_err() {
+        return (StatusCode::BAD_REQUEST, Json(json!({
+            "message": "Invalid push token",
+            "status": 400,
+        })));
+    }
+
+    let mut conn = state.db.get().unwrap();
+    diesel::update(devices::table.find(auth.device_id))
+        .set((
+            devices::push_platform.eq(platform.as_str()),
+            devices::push_token.eq(&token),
+        ))
+        .execute(&mut conn)
+        .unwrap();
+
+    (StatusCode::OK, Json(json!({"success": true})))
+}
+
+/// Returns the VAPID public key Web Push clients subscribe with (`applicationServerKey`).
+pub async fn get_vapid_public_key(state: Extension<AppState>, _auth: AuthUser) -> (StatusCode, Json<Value>) {
+    match state.push.vapid_public_key() {
+        Some(key) => (StatusCode::OK, Json(json!({"vapid_public_key": key}))),
+        None => (StatusCode::NOT_FOUND, Json(json!({
+            "message": "Web Push is not enabled",
+            "status": 404,
+        }))),
+    }
+}
+
+/// Stops wake-up pushes to this device.
+pub async fn delete_push_token(state: Extension<AppState>, auth: AuthUser) -> Json<Value> {
+    let mut conn = state.db.get().unwrap();
+    diesel::update(devices::table.find(auth.device_id))
+        .set((
+            devices::push_platform.eq(None::<String>),
+            devices::push_token.eq(None::<String>),
+        ))
+        .execute(&mut conn)
+        .unwrap();
+
+    Json(json!({"success": true}))
+}
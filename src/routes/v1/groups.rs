@@ -0,0 +1,1037 @@
+use crate::routes::v1::messages::{default_protocol_version, CLIENT_MESSAGE_TYPES};
+use crate::{AppState, AuthUser};
+use axum::extract::Path;
+use axum::http::StatusCode;
+use axum::{Extension, Json};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use rand::Rng;
+use e2ee_back::attachments::{self, MAX_ATTACHMENTS_PER_MESSAGE};
+use e2ee_back::blocking::is_blocked;
+use e2ee_back::franking::{self, FRANKING_SIZE};
+use e2ee_back::models::{Group, GroupMember, NewMessage};
+use e2ee_back::schema::{blocks, devices, group_join_requests, group_members, group_payloads, groups, messages, users};
+use e2ee_back::system_messages;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+
+const MAX_GROUP_STATE_SIZE: usize = 64 * 1024;
+const MAX_GROUP_SIZE: usize = 1000;
+
+pub const ROLE_ADMIN: &str = "admin";
+pub const ROLE_MEMBER: &str = "member";
+
+/// Why a group change was refused.
+pub enum GroupError {
+    /// Unknown group, or one the user isn't a member of.
+    NotFound,
+    Forbidden(&'static str),
+    /// The client's version is stale; carries the current one.
+    VersionMismatch(i64),
+    InvalidMember(&'static str),
+    /// The sender's view of the members' devices is stale: missing and extra device ids.
+    MismatchedDevices(Vec<Uuid>, Vec<Uuid>),
+    AttachmentNotFound,
+    Database(diesel::result::Error),
+}
+
+impl From<diesel::result::Error> for GroupError {
+    fn from(e: diesel::result::Error) -> Self {
+        GroupError::Database(e)
+    }
+}
+
+impl GroupError {
+    pub fn into_response(self) -> (StatusCode, Json<Value>) {
+        match self {
+            GroupError::NotFound => (StatusCode::NOT_FOUND, Json(json!({
+                "message": "Group not found",
+                "status": 404,
+            }))),
+            GroupError::Forbidden(message) => (StatusCode::FORBIDDEN, Json(json!({
+                "message": message,
+                "status": 403,
+            }))),
+            GroupError::VersionMismatch(version) => (StatusCode::CONFLICT, Json(json!({
+                "message": "Group version mismatch",
+                "status": 409,
+                "version": version,
+            }))),
+            GroupError::InvalidMember(message) => (StatusCode::BAD_REQUEST, Json(json!({
+                "message": message,
+                "status": 400,
+            }))),
+            GroupError::MismatchedDevices(missing, extra) => (StatusCode::CONFLICT, Json(json!({
+                "message": "Mismatched devices",
+                "status": 409,
+                "missing_devices": missing,
+                "extra_devices": extra,
+            }))),
+            GroupError::AttachmentNotFound => (StatusCode::BAD_REQUEST, Json(json!({
+                "message": "Attachment not found",
+                "status": 400,
+            }))),
+            GroupError::Database(e) => {
+                tracing::error!("Failed to update group: {e}");
+                (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({
+                    "message": "Something went wrong",
+                    "status": 500,
+                })))
+            }
+        }
+    }
+}
+
+fn decode_state(state: &str) -> Option<Vec<u8>> {
+    base64::engine::general_purpose::STANDARD
+        .decode(state)
+        .ok()
+        .filter(|s| !s.is_empty() && s.len() <= MAX_GROUP_STATE_SIZE)
+}
+
+fn invalid_state() -> (StatusCode, Json<Value>) {
+    (StatusCode::BAD_REQUEST, Json(json!({
+        "message": "Invalid group state",
+        "status": 400,
+    })))
+}
+
+pub fn member_role(conn: &mut PgConnection, group_id: Uuid, user_id: Uuid) -> QueryResult<Option<String>> {
+    group_members::table
+        .find((group_id, user_id))
+        .select(group_members::role)
+        .first::<String>(conn)
+        .optional()
+}
+
+fn member_ids(conn: &mut PgConnection, group_id: Uuid) -> QueryResult<Vec<Uuid>> {
+    group_members::table
+        .filter(group_members::group_id.eq(group_id))
+        .select(group_members::user_id)
+        .load::<Uuid>(conn)
+}
+
+/// Tells the devices of every member, and of `also_notify` (e.g. removed members), that the
+/// group changed. Returns the devices, to wake once committed.
+fn notify_group_updated(
+    conn: &mut PgConnection,
+    group_id: Uuid,
+    version: i64,
+    also_notify: &[Uuid],
+) -> QueryResult<Vec<Uuid>> {
+    let mut recipients = member_ids(conn, group_id)?;
+    recipients.extend_from_slice(also_notify);
+
+    system_messages::queue_for_users(conn, &recipients, &json!({
+        "type": "group_updated",
+        "group_id": group_id,
+        "version": version,
+    }))
+}
+
+/// Applies a change made by `user_id` on top of `expected_version`, replacing the state and
+/// bumping the version.
+///
+/// `change` runs in the same transaction with the locked group and the user's role (`None` for
+/// non-members, e.g. when joining), and returns the users to notify besides the remaining
+/// members. The group is deleted once its last member is gone.
+///
+/// Returns the new version and the notified devices, to wake once committed.
+fn apply_change(
+    conn: &mut PgConnection,
+    group_id: Uuid,
+    user_id: Uuid,
+    expected_version: i64,
+    state: &[u8],
+    change: impl FnOnce(&mut PgConnection, &Group, Option<&str>) -> Result<Vec<Uuid>, GroupError>,
+) -> Result<(i64, Vec<Uuid>), GroupError> {
+    conn.transaction::<_, GroupError, _>(|conn| {
+        let group = groups::table
+            .find(group_id)
+            .for_update()
+            .first::<Group>(conn)
+            .optional()?
+            .ok_or(GroupError::NotFound)?;
+        let role = member_role(conn, group_id, user_id)?;
+        if group.version != expected_version {
+            return Err(GroupError::VersionMismatch(group.version));
+        }
+
+        let also_notify = change(conn, &group, role.as_deref())?;
+        let version = group.version + 1;
+
+        if member_ids(conn, group_id)?.is_empty() {
+            diesel::delete(groups::table.find(group_id)).execute(conn)?;
+            let woken = system_messages::queue_for_users(conn, &also_notify, &json!({
+                "type": "group_deleted",
+                "group_id": group_id,
+            }))?;
+            return Ok((version, woken));
+        }
+
+        diesel::update(groups::table.find(group_id))
+            .set((
+                groups::state.eq(state),
+                groups::version.eq(version),
+                groups::updated_at.eq(Utc::now()),
+            ))
+            .execute(conn)?;
+        let woken = notify_group_updated(conn, group_id, version, &also_notify)?;
+        Ok((version, woken))
+    })
+}
+
+/// [`apply_change`] for changes made by a member. Non-members get [`GroupError::NotFound`].
+pub fn change_group(
+    conn: &mut PgConnection,
+    group_id: Uuid,
+    user_id: Uuid,
+    expected_version: i64,
+    state: &[u8],
+    change: impl FnOnce(&mut PgConnection, &Group, &str) -> Result<Vec<Uuid>, GroupError>,
+) -> Result<(i64, Vec<Uuid>), GroupError> {
+    apply_change(conn, group_id, user_id, expected_version, state, |conn, group, role| match role {
+        Some(role) => change(conn, group, role),
+        None => Err(GroupError::NotFound),
+    })
+}
+
+/// Whether `role` meets the minimum role `access` configured for an action.
+pub fn has_access(role: &str, access: &str) -> bool {
+    access == ROLE_MEMBER || role == ROLE_ADMIN
+}
+
+/// Checks that `user_id` is an admin of the group, for changes outside the group state.
+fn require_admin(conn: &mut PgConnection, group_id: Uuid, user_id: Uuid) -> Result<(), GroupError> {
+    match member_role(conn, group_id, user_id)?.as_deref() {
+        Some(ROLE_ADMIN) => Ok(()),
+        Some(_) => Err(GroupError::Forbidden("Only admins can do this")),
+        None => Err(GroupError::NotFound),
+    }
+}
+
+fn is_valid_access(access: &str) -> bool {
+    access == ROLE_ADMIN || access == ROLE_MEMBER
+}
+
+fn hash_invite_token(token: &str) -> Vec<u8> {
+    Sha256::digest(token.as_bytes()).to_vec()
+}
+
+fn add_member(conn: &mut PgConnection, group_id: Uuid, user_id: Uuid) -> Result<(), GroupError> {
+    if member_ids(conn, group_id)?.len() >= MAX_GROUP_SIZE {
+        return Err(GroupError::InvalidMember("The group is full"));
+    }
+
+    let inserted = diesel::insert_into(group_members::table)
+        .values((
+            group_members::group_id.eq(group_id),
+            group_members::user_id.eq(user_id),
+            group_members::role.eq(ROLE_MEMBER),
+        ))
+        .on_conflict_do_nothing()
+        .execute(conn)?;
+    if inserted == 0 {
+        return Err(GroupError::InvalidMember("Already a member"));
+    }
+
+    diesel::delete(group_join_requests::table.find((group_id, user_id))).execute(conn)?;
+    Ok(())
+}
+
+/// Checks that `user_id` can be added to a group by `by_user_id`. Users who blocked the adder
+/// look the same as unknown ones.
+fn check_new_member(conn: &mut PgConnection, user_id: Uuid, by_user_id: Uuid) -> Result<(), GroupError> {
+    let exists = users::table
+        .find(user_id)
+        .count()
+        .get_result::<i64>(conn)? > 0;
+    if !exists || is_blocked(conn, user_id, by_user_id)? {
+        return Err(GroupError::InvalidMember("User not found"));
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub struct CreateGroup {
+    /// Base64 encrypted group state.
+    state: String,
+    /// Members besides the creator, who becomes the group's admin.
+    members: Vec<Uuid>,
+}
+
+pub async fn create_group(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    Json(payload): Json<CreateGroup>,
+) -> (StatusCode, Json<Value>) {
+    let Some(group_state) = decode_state(&payload.state) else {
+        return invalid_state();
+    };
+    let members: HashSet<Uuid> = payload.members
+        .into_iter()
+        .filter(|id| *id != auth.user_id)
+        .collect();
+    if members.len() + 1 > MAX_GROUP_SIZE {
+        return (StatusCode::BAD_REQUEST, Json(json!({
+            "message": format!("Groups are limited to {MAX_GROUP_SIZE} members"),
+            "status": 400,
+        })));
+    }
+
+    let mut conn = state.db.get().unwrap();
+    let result = conn.transaction::<_, GroupError, _>(|conn| {
+        for member in &members {
+            check_new_member(conn, *member, auth.user_id)?;
+        }
+
+        let group_id = diesel::insert_into(groups::table)
+            .values(groups::state.eq(&group_state))
+            .returning(groups::id)
+            .get_result::<Uuid>(conn)?;
+
+        let rows: Vec<_> = std::iter::once((auth.user_id, ROLE_ADMIN))
+            .chain(members.iter().map(|id| (*id, ROLE_MEMBER)))
+            .map(|(user_id, role)| (
+                group_members::group_id.eq(group_id),
+                group_members::user_id.eq(user_id),
+                group_members::role.eq(role),
+            ))
+            .collect();
+        diesel::insert_into(group_members::table)
+            .values(&rows)
+            .execute(conn)?;
+
+        let woken = notify_group_updated(conn, group_id, 1, &[])?;
+        Ok((group_id, woken))
+    });
+
+    match result {
+        Ok((group_id, woken)) => {
+            state.push.wake(woken);
+            (StatusCode::OK, Json(json!({
+                "group_id": group_id,
+                "version": 1,
+            })))
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+pub async fn get_group(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    Path(group_id): Path<Uuid>,
+) -> (StatusCode, Json<Value>) {
+    let mut conn = state.db.get().unwrap();
+    if member_role(&mut conn, group_id, auth.user_id).unwrap().is_none() {
+        return GroupError::NotFound.into_response();
+    }
+
+    let group = groups::table
+        .find(group_id)
+        .first::<Group>(&mut conn)
+        .unwrap();
+    let members = GroupMember::belonging_to(&group)
+        .order(group_members::joined_at)
+        .load::<GroupMember>(&mut conn)
+        .unwrap();
+
+    (StatusCode::OK, Json(json!({
+        "group_id": group.id,
+        "version": group.version,
+        "state": base64::engine::general_purpose::STANDARD.encode(&group.state),
+        "members": members
+            .iter()
+            .map(|m| json!({"user_id": m.user_id, "role": m.role}))
+            .collect::<Vec<_>>(),
+        "permissions": {
+            "add_members": group.add_members_access,
+            "edit_state": group.edit_state_access,
+            "send": group.send_access,
+        },
+        "invite_link": group.invite_link_token_hash.is_some().then(|| json!({
+            "requires_approval": group.invite_link_requires_approval,
+        })),
+    })))
+}
+
+#[derive(Deserialize)]
+pub struct GroupChange {
+    /// Base64 encrypted group state after the change.
+    state: String,
+    /// Version the change was made on top of.
+    version: i64,
+}
+
+pub async fn update_group_state(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    Path(group_id): Path<Uuid>,
+    Json(payload): Json<GroupChange>,
+) -> (StatusCode, Json<Value>) {
+    let Some(group_state) = decode_state(&payload.state) else {
+        return invalid_state();
+    };
+
+    let mut conn = state.db.get().unwrap();
+    let result = change_group(&mut conn, group_id, auth.user_id, payload.version, &group_state, |_, group, role| {
+        if !has_access(role, &group.edit_state_access) {
+            return Err(GroupError::Forbidden("Only admins can edit this group"));
+        }
+        Ok(vec![])
+    });
+
+    match result {
+        Ok((version, woken)) => {
+            state.push.wake(woken);
+            (StatusCode::OK, Json(json!({"version": version})))
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct AddGroupMember {
+    user_id: Uuid,
+    state: String,
+    version: i64,
+}
+
+pub async fn add_group_member(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    Path(group_id): Path<Uuid>,
+    Json(payload): Json<AddGroupMember>,
+) -> (StatusCode, Json<Value>) {
+    let Some(group_state) = decode_state(&payload.state) else {
+        return invalid_state();
+    };
+
+    let mut conn = state.db.get().unwrap();
+    let result = change_group(&mut conn, group_id, auth.user_id, payload.version, &group_state, |conn, group, role| {
+        if !has_access(role, &group.add_members_access) {
+            return Err(GroupError::Forbidden("Only admins can add members"));
+        }
+        check_new_member(conn, payload.user_id, auth.user_id)?;
+        add_member(conn, group_id, payload.user_id)?;
+        Ok(vec![])
+    });
+
+    match result {
+        Ok((version, woken)) => {
+            state.push.wake(woken);
+            (StatusCode::OK, Json(json!({"version": version})))
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Removes another member. Admins only; members leave with [`leave_group`].
+pub async fn remove_group_member(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    Path((group_id, user_id)): Path<(Uuid, Uuid)>,
+    Json(payload): Json<GroupChange>,
+) -> (StatusCode, Json<Value>) {
+    if user_id == auth.user_id {
+        return GroupError::InvalidMember("Use leave to remove yourself").into_response();
+    }
+    let Some(group_state) = decode_state(&payload.state) else {
+        return invalid_state();
+    };
+
+    let mut conn = state.db.get().unwrap();
+    let result = change_group(&mut conn, group_id, auth.user_id, payload.version, &group_state, |conn, _, role| {
+        if role != ROLE_ADMIN {
+            return Err(GroupError::Forbidden("Only admins can remove members"));
+        }
+
+        let deleted = diesel::delete(group_members::table.find((group_id, user_id))).execute(conn)?;
+        if deleted == 0 {
+            return Err(GroupError::InvalidMember("Not a member"));
+        }
+        Ok(vec![user_id])
+    });
+
+    match result {
+        Ok((version, woken)) => {
+            state.push.wake(woken);
+            (StatusCode::OK, Json(json!({"version": version})))
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Leaves the group. The last member leaving deletes it, and the longest-standing member is
+/// promoted when the last admin leaves.
+pub async fn leave_group(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    Path(group_id): Path<Uuid>,
+    Json(payload): Json<GroupChange>,
+) -> (StatusCode, Json<Value>) {
+    let Some(group_state) = decode_state(&payload.state) else {
+        return invalid_state();
+    };
+
+    let mut conn = state.db.get().unwrap();
+    let result = change_group(&mut conn, group_id, auth.user_id, payload.version, &group_state, |conn, _, _| {
+        diesel::delete(group_members::table.find((group_id, auth.user_id))).execute(conn)?;
+
+        let admins = group_members::table
+            .filter(group_members::group_id.eq(group_id))
+            .filter(group_members::role.eq(ROLE_ADMIN))
+            .count()
+            .get_result::<i64>(conn)?;
+        if admins == 0 {
+            let successor = group_members::table
+                .filter(group_members::group_id.eq(group_id))
+                .order((group_members::joined_at, group_members::user_id))
+                .select(group_members::user_id)
+                .first::<Uuid>(conn)
+                .optional()?;
+            if let Some(successor) = successor {
+                diesel::update(group_members::table.find((group_id, successor)))
+                    .set(group_members::role.eq(ROLE_ADMIN))
+                    .execute(conn)?;
+            }
+        }
+        Ok(vec![auth.user_id])
+    });
+
+    match result {
+        Ok((version, woken)) => {
+            state.push.wake(woken);
+            (StatusCode::OK, Json(json!({"version": version})))
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SetMemberRole {
+    role: String,
+    state: String,
+    version: i64,
+}
+
+/// Promotes or demotes a member. Admins only, and a group always keeps at least one admin.
+pub async fn set_member_role(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    Path((group_id, user_id)): Path<(Uuid, Uuid)>,
+    Json(payload): Json<SetMemberRole>,
+) -> (StatusCode, Json<Value>) {
+    if !is_valid_access(&payload.role) {
+        return GroupError::InvalidMember("Roles are admin or member").into_response();
+    }
+    let Some(group_state) = decode_state(&payload.state) else {
+        return invalid_state();
+    };
+
+    let mut conn = state.db.get().unwrap();
+    let result = change_group(&mut conn, group_id, auth.user_id, payload.version, &group_state, |conn, _, role| {
+        if role != ROLE_ADMIN {
+            return Err(GroupError::Forbidden("Only admins can change roles"));
+        }
+
+        let updated = diesel::update(group_members::table.find((group_id, user_id)))
+            .set(group_members::role.eq(&payload.role))
+            .execute(conn)?;
+        if updated == 0 {
+            return Err(GroupError::InvalidMember("Not a member"));
+        }
+
+        let admins = group_members::table
+            .filter(group_members::group_id.eq(group_id))
+            .filter(group_members::role.eq(ROLE_ADMIN))
+            .count()
+            .get_result::<i64>(conn)?;
+        if admins == 0 {
+            return Err(GroupError::InvalidMember("A group needs at least one admin"));
+        }
+        Ok(vec![])
+    });
+
+    match result {
+        Ok((version, woken)) => {
+            state.push.wake(woken);
+            (StatusCode::OK, Json(json!({"version": version})))
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SetGroupPermissions {
+    /// Minimum role (`admin` or `member`) for each action; omitted ones are left unchanged.
+    add_members: Option<String>,
+    edit_state: Option<String>,
+    send: Option<String>,
+    state: String,
+    version: i64,
+}
+
+pub async fn set_group_permissions(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    Path(group_id): Path<Uuid>,
+    Json(payload): Json<SetGroupPermissions>,
+) -> (StatusCode, Json<Value>) {
+    let accesses = [&payload.add_members, &payload.edit_state, &payload.send];
+    if accesses.iter().any(|a| a.as_deref().is_some_and(|a| !is_valid_access(a))) {
+        return GroupError::InvalidMember("Permissions are admin or member").into_response();
+    }
+    let Some(group_state) = decode_state(&payload.state) else {
+        return invalid_state();
+    };
+
+    let mut conn = state.db.get().unwrap();
+    let result = change_group(&mut conn, group_id, auth.user_id, payload.version, &group_state, |conn, group, role| {
+        if role != ROLE_ADMIN {
+            return Err(GroupError::Forbidden("Only admins can change permissions"));
+        }
+
+        diesel::update(groups::table.find(group_id))
+            .set((
+                groups::add_members_access.eq(payload.add_members.as_ref().unwrap_or(&group.add_members_access)),
+                groups::edit_state_access.eq(payload.edit_state.as_ref().unwrap_or(&group.edit_state_access)),
+                groups::send_access.eq(payload.send.as_ref().unwrap_or(&group.send_access)),
+            ))
+            .execute(conn)?;
+        Ok(vec![])
+    });
+
+    match result {
+        Ok((version, woken)) => {
+            state.push.wake(woken);
+            (StatusCode::OK, Json(json!({"version": version})))
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SetInviteLink {
+    /// Whether joining through the link needs an admin's approval.
+    #[serde(default)]
+    requires_approval: bool,
+}
+
+/// Creates a new invite link token, revoking the previous one. Admins only.
+///
+/// Only the token's hash is stored, so the token is returned once and has to be shared by the
+/// admin's client (with the group key, which never reaches the server).
+pub async fn set_invite_link(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    Path(group_id): Path<Uuid>,
+    Json(payload): Json<SetInviteLink>,
+) -> (StatusCode, Json<Value>) {
+    let token = URL_SAFE_NO_PAD.encode(rand::rng().random::<[u8; 32]>());
+
+    let mut conn = state.db.get().unwrap();
+    let result = conn.transaction::<_, GroupError, _>(|conn| {
+        require_admin(conn, group_id, auth.user_id)?;
+        diesel::update(groups::table.find(group_id))
+            .set((
+                groups::invite_link_token_hash.eq(hash_invite_token(&token)),
+                groups::invite_link_requires_approval.eq(payload.requires_approval),
+            ))
+            .execute(conn)?;
+        Ok(())
+    });
+
+    match result {
+        Ok(()) => (StatusCode::OK, Json(json!({
+            "token": token,
+            "requires_approval": payload.requires_approval,
+        }))),
+        Err(e) => e.into_response(),
+    }
+}
+
+pub async fn delete_invite_link(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    Path(group_id): Path<Uuid>,
+) -> (StatusCode, Json<Value>) {
+    let mut conn = state.db.get().unwrap();
+    let result = conn.transaction::<_, GroupError, _>(|conn| {
+        require_admin(conn, group_id, auth.user_id)?;
+        diesel::update(groups::table.find(group_id))
+            .set(groups::invite_link_token_hash.eq(None::<Vec<u8>>))
+            .execute(conn)?;
+        Ok(())
+    });
+
+    match result {
+        Ok(()) => (StatusCode::OK, Json(json!({"success": true}))),
+        Err(e) => e.into_response(),
+    }
+}
+
+fn find_by_invite_token(conn: &mut PgConnection, token: &str) -> QueryResult<Option<Group>> {
+    groups::table
+        .filter(groups::invite_link_token_hash.eq(hash_invite_token(token)))
+        .first::<Group>(conn)
+        .optional()
+}
+
+/// Returns what a prospective member needs to decide on joining: the encrypted state (readable
+/// with the key from the link), its version and the member count.
+pub async fn get_invite_link_group(
+    state: Extension<AppState>,
+    _auth: AuthUser,
+    Path(token): Path<String>,
+) -> (StatusCode, Json<Value>) {
+    let mut conn = state.db.get().unwrap();
+    let Some(group) = find_by_invite_token(&mut conn, &token).unwrap() else {
+        return GroupError::NotFound.into_response();
+    };
+    let member_count = member_ids(&mut conn, group.id).unwrap().len();
+
+    (StatusCode::OK, Json(json!({
+        "group_id": group.id,
+        "version": group.version,
+        "state": base64::engine::general_purpose::STANDARD.encode(&group.state),
+        "member_count": member_count,
+        "requires_approval": group.invite_link_requires_approval,
+    })))
+}
+
+#[derive(Deserialize)]
+pub struct JoinGroup {
+    /// State and version including the new member, required unless the link needs approval.
+    state: Option<String>,
+    version: Option<i64>,
+}
+
+/// Joins a group through an invite link, or asks its admins to approve the request if the link
+/// requires it.
+pub async fn join_group(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    Path(token): Path<String>,
+    Json(payload): Json<JoinGroup>,
+) -> (StatusCode, Json<Value>) {
+    let mut conn = state.db.get().unwrap();
+    let Some(group) = find_by_invite_token(&mut conn, &token).unwrap() else {
+        return GroupError::NotFound.into_response();
+    };
+
+    if group.invite_link_requires_approval {
+        let result = conn.transaction::<_, GroupError, _>(|conn| {
+            if member_role(conn, group.id, auth.user_id)?.is_some() {
+                return Err(GroupError::InvalidMember("Already a member"));
+            }
+            let inserted = diesel::insert_into(group_join_requests::table)
+                .values((
+                    group_join_requests::group_id.eq(group.id),
+                    group_join_requests::user_id.eq(auth.user_id),
+                ))
+                .on_conflict_do_nothing()
+                .execute(conn)?;
+
+            if inserted > 0 {
+                let admins = group_members::table
+                    .filter(group_members::group_id.eq(group.id))
+                    .filter(group_members::role.eq(ROLE_ADMIN))
+                    .select(group_members::user_id)
+                    .load::<Uuid>(conn)?;
+                return Ok(system_messages::queue_for_users(conn, &admins, &json!({
+                    "type": "group_join_requested",
+                    "group_id": group.id,
+                    "user_id": auth.user_id,
+                }))?);
+            }
+            Ok(vec![])
+        });
+
+        return match result {
+            Ok(woken) => {
+                state.push.wake(woken);
+                (StatusCode::ACCEPTED, Json(json!({"pending_approval": true})))
+            }
+            Err(e) => e.into_response(),
+        };
+    }
+
+    let (Some(group_state), Some(version)) = (payload.state.as_deref().and_then(decode_state), payload.version) else {
+        return invalid_state();
+    };
+    let token_hash = hash_invite_token(&token);
+    let result = apply_change(&mut conn, group.id, auth.user_id, version, &group_state, |conn, group, role| {
+        // The link may have been revoked or switched to approval since it was looked up.
+        if group.invite_link_token_hash.as_ref() != Some(&token_hash) || group.invite_link_requires_approval {
+            return Err(GroupError::NotFound);
+        }
+        if role.is_some() {
+            return Err(GroupError::InvalidMember("Already a member"));
+        }
+        add_member(conn, group.id, auth.user_id)?;
+        Ok(vec![])
+    });
+
+    match result {
+        Ok((version, woken)) => {
+            state.push.wake(woken);
+            (StatusCode::OK, Json(json!({
+                "group_id": group.id,
+                "version": version,
+            })))
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+pub async fn get_join_requests(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    Path(group_id): Path<Uuid>,
+) -> (StatusCode, Json<Value>) {
+    let mut conn = state.db.get().unwrap();
+    if let Err(e) = require_admin(&mut conn, group_id, auth.user_id) {
+        return e.into_response();
+    }
+
+    let requests = group_join_requests::table
+        .filter(group_join_requests::group_id.eq(group_id))
+        .order(group_join_requests::created_at)
+        .select((group_join_requests::user_id, group_join_requests::created_at))
+        .load::<(Uuid, Option<chrono::NaiveDateTime>)>(&mut conn)
+        .unwrap();
+
+    (StatusCode::OK, Json(json!({
+        "data": requests
+            .iter()
+            .map(|(user_id, created_at)| json!({"user_id": user_id, "created_at": created_at}))
+            .collect::<Vec<_>>(),
+    })))
+}
+
+pub async fn approve_join_request(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    Path((group_id, user_id)): Path<(Uuid, Uuid)>,
+    Json(payload): Json<GroupChange>,
+) -> (StatusCode, Json<Value>) {
+    let Some(group_state) = decode_state(&payload.state) else {
+        return invalid_state();
+    };
+
+    let mut conn = state.db.get().unwrap();
+    let result = change_group(&mut conn, group_id, auth.user_id, payload.version, &group_state, |conn, _, role| {
+        if role != ROLE_ADMIN {
+            return Err(GroupError::Forbidden("Only admins can approve join requests"));
+        }
+
+        let pending = group_join_requests::table
+            .find((group_id, user_id))
+            .count()
+            .get_result::<i64>(conn)? > 0;
+        if !pending {
+            return Err(GroupError::InvalidMember("No pending join request"));
+        }
+        add_member(conn, group_id, user_id)?;
+        Ok(vec![])
+    });
+
+    match result {
+        Ok((version, woken)) => {
+            state.push.wake(woken);
+            (StatusCode::OK, Json(json!({"version": version})))
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Denies a join request, or cancels it when called by the requester.
+pub async fn delete_join_request(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    Path((group_id, user_id)): Path<(Uuid, Uuid)>,
+) -> (StatusCode, Json<Value>) {
+    let mut conn = state.db.get().unwrap();
+    if user_id != auth.user_id && let Err(e) = require_admin(&mut conn, group_id, auth.user_id) {
+        return e.into_response();
+    }
+
+    diesel::delete(group_join_requests::table.find((group_id, user_id)))
+        .execute(&mut conn)
+        .unwrap();
+
+    (StatusCode::OK, Json(json!({"success": true})))
+}
+
+#[derive(Deserialize)]
+pub struct GroupMessageHeader {
+    device_id: Uuid,
+    /// Base64 per-device part, e.g. a sender-key distribution message or an empty-payload header.
+    header: String,
+    message_type: i16,
+    #[serde(default = "default_protocol_version")]
+    protocol_version: i16,
+}
+
+#[derive(Deserialize)]
+pub struct SendGroupMessage {
+    /// Base64 ciphertext encrypted with the sender key, stored once for all recipients.
+    ciphertext: String,
+    /// One header per active device of every other member, and of the sender's other devices.
+    headers: Vec<GroupMessageHeader>,
+    franking_commitment: Option<String>,
+    /// Attachments referenced by the message, kept until every copy is acknowledged.
+    #[serde(default)]
+    attachment_ids: Vec<Uuid>,
+}
+
+/// Fans a sender-key message out to every member device in one transaction.
+///
+/// The request is rejected with `409 Conflict` and the `missing_devices` / `extra_devices` lists
+/// if the sender's view of the group's devices is stale. Members who blocked the sender are
+/// silently skipped.
+pub async fn send_group_message(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    Path(group_id): Path<Uuid>,
+    Json(payload): Json<SendGroupMessage>,
+) -> (StatusCode, Json<Value>) {
+    let engine = base64::engine::general_purpose::STANDARD;
+    if payload.headers.iter().any(|h| !CLIENT_MESSAGE_TYPES.contains(&h.message_type)) {
+        return (StatusCode::BAD_REQUEST, Json(json!({
+            "message": "Invalid message type",
+            "status": 400,
+        })));
+    }
+    let ciphertext = engine.decode(&payload.ciphertext).ok().filter(|c| !c.is_empty());
+    let headers = payload.headers
+        .iter()
+        .map(|h| engine.decode(&h.header).ok())
+        .collect::<Option<Vec<_>>>();
+    let (Some(ciphertext), Some(headers)) = (ciphertext, headers) else {
+        return (StatusCode::BAD_REQUEST, Json(json!({
+            "message": "Invalid ciphertext",
+            "status": 400,
+        })));
+    };
+    let commitment = match &payload.franking_commitment {
+        Some(c) => match engine.decode(c).ok().filter(|c| c.len() == FRANKING_SIZE) {
+            Some(c) => Some(c),
+            None => return (StatusCode::BAD_REQUEST, Json(json!({
+                "message": "Invalid franking commitment",
+                "status": 400,
+            }))),
+        },
+        None => None,
+    };
+    if payload.attachment_ids.len() > MAX_ATTACHMENTS_PER_MESSAGE {
+        return (StatusCode::BAD_REQUEST, Json(json!({
+            "message": format!("At most {MAX_ATTACHMENTS_PER_MESSAGE} attachments per message"),
+            "status": 400,
+        })));
+    }
+
+    let mut conn = state.db.get().unwrap();
+
+    let result = conn.transaction::<_, GroupError, _>(|conn| {
+        // Membership changes wait for the fan-out, so every member at send time gets the message.
+        let group = groups::table
+            .find(group_id)
+            .for_share()
+            .first::<Group>(conn)
+            .optional()?
+            .ok_or(GroupError::NotFound)?;
+        let role = member_role(conn, group_id, auth.user_id)?.ok_or(GroupError::NotFound)?;
+        if !has_access(&role, &group.send_access) {
+            return Err(GroupError::Forbidden("Only admins can send to this group"));
+        }
+        if !attachments::lock_all(conn, &payload.attachment_ids)? {
+            return Err(GroupError::AttachmentNotFound);
+        }
+
+        let member_devices = devices::table
+            .inner_join(group_members::table.on(group_members::user_id.nullable().eq(devices::user_id)))
+            .filter(group_members::group_id.eq(group_id))
+            .filter(devices::id.ne(auth.device_id))
+            .filter(devices::is_revoked.is_distinct_from(true))
+            .select((devices::id, devices::user_id))
+            .load::<(Uuid, Option<Uuid>)>(conn)?;
+        let expected: HashSet<Uuid> = member_devices.iter().map(|(id, _)| *id).collect();
+        let provided: HashSet<Uuid> = payload.headers.iter().map(|h| h.device_id).collect();
+        if provided != expected || provided.len() != payload.headers.len() {
+            return Err(GroupError::MismatchedDevices(
+                expected.difference(&provided).copied().collect(),
+                provided.difference(&expected).copied().collect(),
+            ));
+        }
+
+        let blocked_by: HashSet<Uuid> = blocks::table
+            .filter(blocks::blocked_user_id.eq(auth.user_id))
+            .select(blocks::blocker_user_id)
+            .load::<Uuid>(conn)?
+            .into_iter()
+            .collect();
+        let device_owners: HashMap<Uuid, Option<Uuid>> = member_devices.into_iter().collect();
+
+        let payload_id = diesel::insert_into(group_payloads::table)
+            .values((
+                group_payloads::group_id.eq(group_id),
+                group_payloads::sender_user_id.eq(auth.user_id),
+                group_payloads::sender_device_id.eq(auth.device_id),
+                group_payloads::ciphertext.eq(&ciphertext),
+            ))
+            .returning(group_payloads::id)
+            .get_result::<i64>(conn)?;
+
+        let now = Utc::now();
+        let now = DateTime::from_timestamp_millis(now.timestamp_millis()).unwrap_or(now);
+        let rows: Vec<NewMessage> = payload.headers
+            .iter()
+            .zip(&headers)
+            .filter_map(|(h, header)| {
+                let owner = device_owners[&h.device_id];
+                if owner.is_some_and(|owner| blocked_by.contains(&owner)) {
+                    return None;
+                }
+                Some(NewMessage {
+                    sender_user_id: Some(auth.user_id),
+                    sender_device_id: Some(auth.device_id),
+                    recipient_user_id: owner,
+                    recipient_device_id: Some(h.device_id),
+                    ciphertext: header,
+                    message_type: h.message_type,
+                    protocol_version: h.protocol_version,
+                    is_request: false,
+                    franking_commitment: commitment.as_deref(),
+                    franking_tag: commitment.as_ref().zip(owner).map(|(c, owner)| {
+                        franking::tag(&state.franking_secret, c, auth.user_id, owner, now.timestamp_millis())
+                    }),
+                    created_at: now.naive_utc(),
+                    group_payload_id: Some(payload_id),
+                })
+            })
+            .collect();
+
+        let message_ids = diesel::insert_into(messages::table)
+            .values(&rows)
+            .returning(messages::id)
+            .get_results::<i64>(conn)?;
+        attachments::link_to_messages(conn, &message_ids, &payload.attachment_ids)?;
+        Ok(rows.iter().filter_map(|m| m.recipient_device_id).collect())
+    });
+
+    match result {
+        Ok(recipient_devices) => {
+            state.push.wake(recipient_devices);
+            (StatusCode::OK, Json(json!({"success": true})))
+        }
+        Err(e) => e.into_response(),
+    }
+}
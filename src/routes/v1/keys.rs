@@ -1,15 +1,19 @@
 use crate::routes::v1::register::Claims;
-use crate::{AppState, AuthUser};
+use crate::{AppState, AuthUser, PendingDeviceAuth};
+use axum::extract::Path;
 use axum::http::StatusCode;
 use axum::{Extension, Json};
 use base64::Engine;
 use chrono::{Duration, Utc};
 use diesel::prelude::*;
+use e2ee_back::blocking::is_blocked;
 use e2ee_back::models::Device;
+use e2ee_back::push::PushPlatform;
 use e2ee_back::schema::{devices, one_time_prekeys};
 use jsonwebtoken::{encode, EncodingKey, Header};
 use serde::Deserialize;
 use serde_json::json;
+use uuid::Uuid;
 
 #[derive(Deserialize)]
 pub struct UploadKeysRequest {
@@ -19,11 +23,14 @@ pub struct UploadKeysRequest {
     one_time_prekeys: Vec<String>,
     device_name: String,
     push_token: String,
+    /// `fcm`, `apns`, `unifiedpush` or `webpush` (with the subscription as JSON in `push_token`).
+    /// Without it the token isn't used until set with `PUT /v1/devices/push`.
+    push_platform: Option<String>,
 }
 
 pub async fn upload_keys(
     Extension(state): Extension<AppState>,
-    auth: AuthUser,
+    auth: PendingDeviceAuth,
     Json(payload): Json<UploadKeysRequest>,
 ) -> (StatusCode, Json<serde_json::Value>) {
     let mut conn = state.db.get().unwrap();
@@ -45,6 +52,23 @@ pub async fn upload_keys(
         })));
     }
 
+    let push_platform = match payload.push_platform.as_deref().map(PushPlatform::parse) {
+        Some(None) => return (StatusCode::BAD_REQUEST, Json(json!({
+            "message": "Invalid push platform",
+            "status": 400,
+        }))),
+        platform => platform.flatten(),
+    };
+    if let Some(platform) = push_platform
+        && (!platform.is_valid_token(&payload.push_token)
+            || platform.check_endpoint(&payload.push_token).await.is_err())
+    {
+        return (StatusCode::BAD_REQUEST, Json(json!({
+            "message": "Invalid push token",
+            "status": 400,
+        })));
+    }
+
     let identity_key_bytes = base64::engine::general_purpose::STANDARD
         .decode(payload.identity_key_pub)
         .expect("Failed to decode base64");
@@ -71,6 +95,8 @@ pub async fn upload_keys(
             devices::signed_prekey_signature.eq(signed_prekey_sig_bytes),
             devices::created_at.eq(Utc::now()),
             devices::is_revoked.eq(false),
+            devices::push_token.eq(payload.push_token),
+            devices::push_platform.eq(push_platform.map(PushPlatform::as_str)),
         ))
         .execute(&mut conn)
         .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({
@@ -121,3 +147,77 @@ pub async fn upload_keys(
         "auth_token": token,
     })))
 }
+
+/// Returns the prekey bundle of every active device of `user_id`, consuming one one-time prekey
+/// per device when any is left.
+pub async fn get_prekey_bundle(
+    Extension(state): Extension<AppState>,
+    auth: AuthUser,
+    Path(user_id): Path<Uuid>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let mut conn = state.db.get().unwrap();
+
+    // Blocked requesters can't tell the user apart from one that doesn't exist.
+    if is_blocked(&mut conn, user_id, auth.user_id).unwrap() {
+        return (StatusCode::NOT_FOUND, Json(json!({
+            "message": "User not found",
+            "status": 404,
+        })));
+    }
+
+    let result = conn.transaction::<_, diesel::result::Error, _>(|conn| {
+        let user_devices = devices::table
+            .filter(devices::user_id.eq(user_id))
+            .filter(devices::is_revoked.is_distinct_from(true))
+            .load::<Device>(conn)?;
+
+        let mut bundles = Vec::with_capacity(user_devices.len());
+        for device in user_devices {
+            let prekey = one_time_prekeys::table
+                .filter(one_time_prekeys::device_id.eq(device.id))
+                .filter(one_time_prekeys::is_consumed.eq(false))
+                .order(one_time_prekeys::id)
+                .select((one_time_prekeys::id, one_time_prekeys::prekey_pub))
+                .for_update()
+                .skip_locked()
+                .first::<(i64, Vec<u8>)>(conn)
+                .optional()?;
+            if let Some((id, _)) = &prekey {
+                diesel::update(one_time_prekeys::table.find(id))
+                    .set(one_time_prekeys::is_consumed.eq(true))
+                    .execute(conn)?;
+            }
+
+            let engine = base64::engine::general_purpose::STANDARD;
+            bundles.push(json!({
+                "device_id": device.id,
+                "identity_key_pub": engine.encode(&device.identity_key_pub),
+                "signed_prekey_pub": engine.encode(&device.signed_prekey_pub),
+                "signed_prekey_signature": engine.encode(&device.signed_prekey_signature),
+                "one_time_prekey": prekey.map(|(id, key)| json!({
+                    "id": id,
+                    "prekey_pub": engine.encode(key),
+                })),
+            }));
+        }
+        Ok(bundles)
+    });
+
+    match result {
+        Ok(bundles) if bundles.is_empty() => (StatusCode::NOT_FOUND, Json(json!({
+            "message": "User not found",
+            "status": 404,
+        }))),
+        Ok(bundles) => (StatusCode::OK, Json(json!({
+            "user_id": user_id,
+            "devices": bundles,
+        }))),
+        Err(e) => {
+            tracing::error!("Failed to load prekey bundle: {e}");
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({
+                "message": "Something went wrong",
+                "status": 500,
+            })))
+        }
+    }
+}
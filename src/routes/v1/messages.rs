@@ -1,15 +1,461 @@
+use axum::http::StatusCode;
 use axum::{Extension, Json};
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use diesel::dsl::{count_star, exists};
 use diesel::prelude::*;
+use diesel::sql_types::Text;
+use serde::Deserialize;
 use serde_json::{Value, json};
-use e2ee_back::{models::*, schema::messages::dsl::*};
-use crate::AppState;
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+use e2ee_back::attachments::{self, MAX_ATTACHMENTS_PER_MESSAGE};
+use e2ee_back::blocking::is_blocked;
+use e2ee_back::franking::{self, FRANKING_SIZE};
+use e2ee_back::models::*;
+use e2ee_back::schema::{accepted_conversations, blocks, devices, group_payloads, messages, users};
+use crate::routes::v1::blocks::sync_block_list;
+use crate::{AppState, AuthUser};
 
-pub async fn get_messages(state: Extension<AppState>) -> Json<Value> {
+/// Message types clients may send; system messages (2) only come from the server.
+pub const CLIENT_MESSAGE_TYPES: [i16; 2] = [0, 1];
+/// Pending messages a sender may queue per device before the recipient accepts the request.
+const MAX_PENDING_REQUESTS: i64 = 5;
+const MAX_SENDERS_PER_UPDATE: usize = 100;
+
+/// Serializes sending requests from `sender_id` to `recipient_id` until the end of the current
+/// transaction, so concurrent sends can't each see room under [`MAX_PENDING_REQUESTS`].
+fn lock_pending_requests(conn: &mut PgConnection, sender_id: Uuid, recipient_id: Uuid) -> QueryResult<()> {
+    diesel::sql_query("SELECT pg_advisory_xact_lock(hashtext($1))")
+        .bind::<Text, _>(format!("pending_requests:{sender_id}:{recipient_id}"))
+        .execute(conn)
+        .map(|_| ())
+}
+
+/// Returns the device's pending messages, leaving out requests from senders the user hasn't accepted.
+///
+/// Group messages come with the shared sender-key ciphertext in `group_payload`.
+pub async fn get_messages(state: Extension<AppState>, auth: AuthUser) -> (StatusCode, Json<Value>) {
     let mut conn = state.db.get().unwrap();
-    let results = messages
-        .limit(10)
+    // Before loading, so a message queued after this fetch still wakes the device.
+    if let Err(e) = state.push.fetched(&mut conn, auth.device_id) {
+        tracing::error!("Failed to clear pending wake-up of device {}: {e}", auth.device_id);
+        return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({
+            "message": "Something went wrong",
+            "status": 500,
+        })));
+    }
+    let results = messages::table
+        .filter(messages::recipient_device_id.eq(auth.device_id))
+        .filter(messages::delivered_at.is_null())
+        .filter(messages::is_request.eq(false))
+        .order(messages::id)
+        .limit(100)
         .load::<Message>(&mut conn)
         .expect("Failed to load messages");
 
+    let payload_ids: Vec<i64> = results.iter().filter_map(|m| m.group_payload_id).collect();
+    let payloads: HashMap<i64, (Uuid, Vec<u8>)> = group_payloads::table
+        .filter(group_payloads::id.eq_any(&payload_ids))
+        .select((group_payloads::id, group_payloads::group_id, group_payloads::ciphertext))
+        .load::<(i64, Uuid, Vec<u8>)>(&mut conn)
+        .expect("Failed to load group payloads")
+        .into_iter()
+        .map(|(id, group_id, ciphertext)| (id, (group_id, ciphertext)))
+        .collect();
+
+    let engine = base64::engine::general_purpose::STANDARD;
+    (StatusCode::OK, Json(Value::Array(results
+        .iter()
+        .map(|m| {
+            let mut value = json!(m);
+            if let Some((group_id, ciphertext)) = m.group_payload_id.and_then(|id| payloads.get(&id)) {
+                value["group_payload"] = json!({
+                    "group_id": group_id,
+                    "ciphertext": engine.encode(ciphertext),
+                });
+            }
+            value
+        })
+        .collect())))
+}
+
+/// Returns the device's pending message requests, to be accepted or rejected per sender.
+pub async fn get_message_requests(state: Extension<AppState>, auth: AuthUser) -> Json<Value> {
+    let mut conn = state.db.get().unwrap();
+    let results = messages::table
+        .filter(messages::recipient_device_id.eq(auth.device_id))
+        .filter(messages::delivered_at.is_null())
+        .filter(messages::is_request.eq(true))
+        .order(messages::id)
+        .limit(100)
+        .load::<Message>(&mut conn)
+        .expect("Failed to load message requests");
+
     Json(json!(results))
 }
+
+#[derive(Deserialize)]
+pub struct OutgoingMessage {
+    device_id: Uuid,
+    /// Base64 ciphertext for this device.
+    ciphertext: String,
+    message_type: i16,
+    #[serde(default = "default_protocol_version")]
+    protocol_version: i16,
+    /// Base64 `HMAC-SHA256(franking_key, plaintext)`, letting the recipient report the message.
+    franking_commitment: Option<String>,
+}
+
+pub fn default_protocol_version() -> i16 {
+    1
+}
+
+#[derive(Deserialize)]
+pub struct SendMessages {
+    recipient_user_id: Uuid,
+    /// One ciphertext per active device of the recipient (other than the sending device).
+    messages: Vec<OutgoingMessage>,
+    /// Attachments referenced by the message, kept until every copy is acknowledged.
+    #[serde(default)]
+    attachment_ids: Vec<Uuid>,
+}
+
+/// Queues one ciphertext per device of the recipient.
+///
+/// The request is rejected with `409 Conflict` and the `missing_devices` / `extra_devices` lists
+/// if it doesn't cover exactly the recipient's active devices. Messages to a user who blocked the
+/// sender are silently dropped, and messages to a user who hasn't accepted the conversation yet
+/// land in their request queue, up to [`MAX_PENDING_REQUESTS`] per device.
+pub async fn send_messages(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    Json(payload): Json<SendMessages>,
+) -> (StatusCode, Json<Value>) {
+    if payload.messages.iter().any(|m| !CLIENT_MESSAGE_TYPES.contains(&m.message_type)) {
+        return (StatusCode::BAD_REQUEST, Json(json!({
+            "message": "Invalid message type",
+            "status": 400,
+        })));
+    }
+
+    let engine = base64::engine::general_purpose::STANDARD;
+    let ciphertexts = match payload.messages
+        .iter()
+        .map(|m| engine.decode(&m.ciphertext).ok().filter(|c| !c.is_empty()))
+        .collect::<Option<Vec<_>>>()
+    {
+        Some(c) => c,
+        None => return (StatusCode::BAD_REQUEST, Json(json!({
+            "message": "Invalid ciphertext",
+            "status": 400,
+        }))),
+    };
+    let commitments = match payload.messages
+        .iter()
+        .map(|m| match &m.franking_commitment {
+            Some(c) => engine.decode(c).ok().filter(|c| c.len() == FRANKING_SIZE).map(Some),
+            None => Some(None),
+        })
+        .collect::<Option<Vec<_>>>()
+    {
+        Some(c) => c,
+        None => return (StatusCode::BAD_REQUEST, Json(json!({
+            "message": "Invalid franking commitment",
+            "status": 400,
+        }))),
+    };
+
+    if payload.attachment_ids.len() > MAX_ATTACHMENTS_PER_MESSAGE {
+        return (StatusCode::BAD_REQUEST, Json(json!({
+            "message": format!("At most {MAX_ATTACHMENTS_PER_MESSAGE} attachments per message"),
+            "status": 400,
+        })));
+    }
+
+    let mut conn = state.db.get().unwrap();
+
+    let recipient_exists = users::table
+        .find(payload.recipient_user_id)
+        .count()
+        .get_result::<i64>(&mut conn)
+        .unwrap() > 0;
+    if !recipient_exists {
+        return (StatusCode::NOT_FOUND, Json(json!({
+            "message": "User not found",
+            "status": 404,
+        })));
+    }
+
+    if is_blocked(&mut conn, payload.recipient_user_id, auth.user_id).unwrap() {
+        return (StatusCode::OK, Json(json!({"success": true})));
+    }
+
+    let expected: HashSet<Uuid> = devices::table
+        .filter(devices::user_id.eq(payload.recipient_user_id))
+        .filter(devices::id.ne(auth.device_id))
+        .filter(devices::is_revoked.is_distinct_from(true))
+        .select(devices::id)
+        .load::<Uuid>(&mut conn)
+        .unwrap()
+        .into_iter()
+        .collect();
+    let provided: HashSet<Uuid> = payload.messages.iter().map(|m| m.device_id).collect();
+    if provided != expected || provided.len() != payload.messages.len() {
+        return (StatusCode::CONFLICT, Json(json!({
+            "message": "Mismatched devices",
+            "status": 409,
+            "missing_devices": expected.difference(&provided).collect::<Vec<_>>(),
+            "extra_devices": provided.difference(&expected).collect::<Vec<_>>(),
+        })));
+    }
+
+    let result = conn.transaction::<_, diesel::result::Error, _>(|conn| {
+        if !attachments::lock_all(conn, &payload.attachment_ids)? {
+            return Ok(Err((StatusCode::BAD_REQUEST, Json(json!({
+                "message": "Attachment not found",
+                "status": 400,
+            })))));
+        }
+
+        let is_request = payload.recipient_user_id != auth.user_id && !diesel::select(exists(
+            accepted_conversations::table.find((payload.recipient_user_id, auth.user_id)),
+        ))
+            .get_result::<bool>(conn)?;
+
+        if is_request {
+            lock_pending_requests(conn, auth.user_id, payload.recipient_user_id)?;
+            let pending = messages::table
+                .filter(messages::sender_user_id.eq(auth.user_id))
+                .filter(messages::recipient_user_id.eq(payload.recipient_user_id))
+                .filter(messages::is_request.eq(true))
+                .filter(messages::delivered_at.is_null())
+                .group_by(messages::recipient_device_id)
+                .select(count_star())
+                .load::<i64>(conn)?;
+            if pending.into_iter().max().unwrap_or(0) >= MAX_PENDING_REQUESTS {
+                return Ok(Err((StatusCode::TOO_MANY_REQUESTS, Json(json!({
+                    "message": "Too many pending message requests for this user",
+                    "status": 429,
+                })))));
+            }
+        }
+
+        // Millisecond precision, so the timestamp bound by the franking tag survives the round trip.
+        let now = Utc::now();
+        let now = DateTime::from_timestamp_millis(now.timestamp_millis()).unwrap_or(now);
+        let rows: Vec<NewMessage> = payload.messages
+            .iter()
+            .zip(&ciphertexts)
+            .zip(&commitments)
+            .map(|((m, ciphertext), commitment)| NewMessage {
+                sender_user_id: Some(auth.user_id),
+                sender_device_id: Some(auth.device_id),
+                recipient_user_id: Some(payload.recipient_user_id),
+                recipient_device_id: Some(m.device_id),
+                ciphertext,
+                message_type: m.message_type,
+                protocol_version: m.protocol_version,
+                is_request,
+                franking_commitment: commitment.as_deref(),
+                franking_tag: commitment.as_ref().map(|c| {
+                    franking::tag(&state.franking_secret, c, auth.user_id, payload.recipient_user_id, now.timestamp_millis())
+                }),
+                created_at: now.naive_utc(),
+                group_payload_id: None,
+            })
+            .collect();
+
+        let message_ids = diesel::insert_into(messages::table)
+            .values(&rows)
+            .returning(messages::id)
+            .get_results::<i64>(conn)?;
+        attachments::link_to_messages(conn, &message_ids, &payload.attachment_ids)?;
+
+        // Writing to someone accepts their messages in return.
+        if payload.recipient_user_id != auth.user_id {
+            diesel::insert_into(accepted_conversations::table)
+                .values((
+                    accepted_conversations::user_id.eq(auth.user_id),
+                    accepted_conversations::peer_user_id.eq(payload.recipient_user_id),
+                ))
+                .on_conflict_do_nothing()
+                .execute(conn)?;
+        }
+        Ok(Ok(is_request))
+    });
+
+    match result {
+        Ok(Ok(is_request)) => {
+            // Requests wait silently until the recipient looks at them.
+            if !is_request {
+                state.push.wake(payload.messages.iter().map(|m| m.device_id).collect());
+            }
+            (StatusCode::OK, Json(json!({"success": true})))
+        }
+        Ok(Err(response)) => response,
+        Err(e) => {
+            tracing::error!("Failed to queue messages: {e}");
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({
+                "message": "Something went wrong",
+                "status": 500,
+            })))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct AckMessages {
+    message_ids: Vec<i64>,
+}
+
+/// Marks messages as delivered to this device, so they are no longer returned and their
+/// attachments can be deleted once every recipient has them.
+pub async fn ack_messages(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    Json(payload): Json<AckMessages>,
+) -> (StatusCode, Json<Value>) {
+    if payload.message_ids.len() > 100 {
+        return (StatusCode::BAD_REQUEST, Json(json!({
+            "message": "At most 100 messages per request",
+            "status": 400,
+        })));
+    }
+
+    let mut conn = state.db.get().unwrap();
+    diesel::update(messages::table
+        .filter(messages::id.eq_any(&payload.message_ids))
+        .filter(messages::recipient_device_id.eq(auth.device_id))
+        .filter(messages::delivered_at.is_null()))
+        .set(messages::delivered_at.eq(Utc::now().naive_utc()))
+        .execute(&mut conn)
+        .unwrap();
+
+    (StatusCode::OK, Json(json!({"success": true})))
+}
+
+#[derive(Deserialize)]
+pub struct AcceptMessageRequests {
+    sender_user_ids: Vec<Uuid>,
+}
+
+/// Accepts the conversations with `sender_user_ids`, moving their pending requests to the inbox.
+pub async fn accept_message_requests(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    Json(payload): Json<AcceptMessageRequests>,
+) -> (StatusCode, Json<Value>) {
+    if payload.sender_user_ids.is_empty() || payload.sender_user_ids.len() > MAX_SENDERS_PER_UPDATE {
+        return (StatusCode::BAD_REQUEST, Json(json!({
+            "message": format!("Between 1 and {MAX_SENDERS_PER_UPDATE} senders per request"),
+            "status": 400,
+        })));
+    }
+
+    let mut conn = state.db.get().unwrap();
+    let result = conn.transaction::<_, diesel::result::Error, _>(|conn| {
+        let senders = users::table
+            .filter(users::id.eq_any(&payload.sender_user_ids))
+            .filter(users::id.ne(auth.user_id))
+            .select(users::id)
+            .load::<Uuid>(conn)?;
+
+        diesel::insert_into(accepted_conversations::table)
+            .values(senders
+                .iter()
+                .map(|sender| (
+                    accepted_conversations::user_id.eq(auth.user_id),
+                    accepted_conversations::peer_user_id.eq(*sender),
+                ))
+                .collect::<Vec<_>>())
+            .on_conflict_do_nothing()
+            .execute(conn)?;
+
+        diesel::update(messages::table
+            .filter(messages::recipient_user_id.eq(auth.user_id))
+            .filter(messages::sender_user_id.eq_any(&senders))
+            .filter(messages::is_request.eq(true)))
+            .set(messages::is_request.eq(false))
+            .execute(conn)
+    });
+
+    match result {
+        Ok(_) => (StatusCode::OK, Json(json!({"success": true}))),
+        Err(e) => {
+            tracing::error!("Failed to accept message requests: {e}");
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({
+                "message": "Something went wrong",
+                "status": 500,
+            })))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RejectMessageRequests {
+    sender_user_ids: Vec<Uuid>,
+    /// Also block the senders, so they can't send further requests.
+    #[serde(default)]
+    block: bool,
+}
+
+/// Drops the pending requests from `sender_user_ids`, optionally blocking them.
+pub async fn reject_message_requests(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    Json(payload): Json<RejectMessageRequests>,
+) -> (StatusCode, Json<Value>) {
+    if payload.sender_user_ids.is_empty() || payload.sender_user_ids.len() > MAX_SENDERS_PER_UPDATE {
+        return (StatusCode::BAD_REQUEST, Json(json!({
+            "message": format!("Between 1 and {MAX_SENDERS_PER_UPDATE} senders per request"),
+            "status": 400,
+        })));
+    }
+
+    let mut conn = state.db.get().unwrap();
+    let result = conn.transaction::<_, diesel::result::Error, _>(|conn| {
+        diesel::delete(messages::table
+            .filter(messages::recipient_user_id.eq(auth.user_id))
+            .filter(messages::sender_user_id.eq_any(&payload.sender_user_ids))
+            .filter(messages::is_request.eq(true)))
+            .execute(conn)?;
+
+        if payload.block {
+            let senders = users::table
+                .filter(users::id.eq_any(&payload.sender_user_ids))
+                .filter(users::id.ne(auth.user_id))
+                .select(users::id)
+                .load::<Uuid>(conn)?;
+
+            let inserted = diesel::insert_into(blocks::table)
+                .values(senders
+                    .iter()
+                    .map(|sender| (
+                        blocks::blocker_user_id.eq(auth.user_id),
+                        blocks::blocked_user_id.eq(*sender),
+                    ))
+                    .collect::<Vec<_>>())
+                .on_conflict_do_nothing()
+                .execute(conn)?;
+            if inserted > 0 {
+                return sync_block_list(conn, &auth);
+            }
+        }
+        Ok(vec![])
+    });
+
+    match result {
+        Ok(woken) => {
+            state.push.wake(woken);
+            (StatusCode::OK, Json(json!({"success": true})))
+        }
+        Err(e) => {
+            tracing::error!("Failed to reject message requests: {e}");
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({
+                "message": "Something went wrong",
+                "status": 500,
+            })))
+        }
+    }
+}
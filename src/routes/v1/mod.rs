@@ -1,4 +1,13 @@
+pub mod account;
+pub mod attachments;
+pub mod blocks;
+pub mod channels;
+pub mod contacts;
 pub mod messages;
 pub mod register;
+pub mod reports;
 pub mod keys;
-pub mod devices;
\ No newline at end of file
+pub mod devices;
+pub mod groups;
+pub mod profile;
+pub mod username;
\ No newline at end of file
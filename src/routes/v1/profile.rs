@@ -0,0 +1,339 @@
+use crate::{AppState, AuthUser};
+use axum::extract::Path;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Redirect, Response};
+use axum::{Extension, Json};
+use base64::Engine;
+use diesel::connection::{AnsiTransactionManager, TransactionManager};
+use diesel::prelude::*;
+use diesel::r2d2::{ConnectionManager, Pool};
+use diesel::sql_types::Text;
+use e2ee_back::models::Profile;
+use e2ee_back::schema::{legacy_avatars, profiles};
+use e2ee_back::storage::BlobStore;
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use uuid::Uuid;
+
+const MAX_PROFILE_FIELD_SIZE: usize = 2048;
+
+fn profile_avatar_key(hash: &str) -> String {
+    format!("profile-avatars/{hash}")
+}
+
+/// Plaintext avatars, from before profiles were encrypted.
+fn legacy_avatar_key(hash: &str) -> String {
+    format!("avatars/{hash}")
+}
+
+fn is_valid_version(version: &str) -> bool {
+    !version.is_empty()
+        && version.len() <= 64
+        && version.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f'))
+}
+
+/// Runs `f` in a transaction holding a lock on the blob `key`.
+///
+/// Shared blobs are only deleted, and only start being referenced, under this lock, so a blob
+/// found to exist can't be deleted before the new reference to it is committed.
+async fn with_blob_lock<T>(
+    conn: &mut PgConnection,
+    key: &str,
+    f: impl AsyncFnOnce(&mut PgConnection) -> Result<T, String>,
+) -> Result<T, String> {
+    AnsiTransactionManager::begin_transaction(conn).map_err(|e| e.to_string())?;
+    let result = match diesel::sql_query("SELECT pg_advisory_xact_lock(hashtext($1))")
+        .bind::<Text, _>(key)
+        .execute(conn)
+    {
+        Ok(_) => f(conn).await,
+        Err(e) => Err(e.to_string()),
+    };
+
+    let ended = match result {
+        Ok(_) => AnsiTransactionManager::commit_transaction(conn),
+        Err(_) => AnsiTransactionManager::rollback_transaction(conn),
+    };
+    ended.map_err(|e| e.to_string())?;
+    result
+}
+
+/// Stores `data` under `key` unless it is already there. Must run under [`with_blob_lock`].
+async fn store_blob(state: &AppState, key: &str, data: Vec<u8>) -> Result<(), String> {
+    if !state.storage.exists(key).await.map_err(|e| e.to_string())? {
+        state.storage.put(key, data).await.map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+/// Deletes the encrypted avatar blob `hash` once no profile references it anymore.
+pub async fn release_profile_avatar(state: &AppState, conn: &mut PgConnection, hash: &str) {
+    let key = profile_avatar_key(hash);
+    let result = with_blob_lock(conn, &key, async |conn| {
+        let still_used = profiles::table
+            .filter(profiles::avatar_hash.eq(hash))
+            .count()
+            .get_result::<i64>(conn)
+            .map_err(|e| e.to_string())? > 0;
+        if !still_used {
+            state.storage.delete(&key).await.map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }).await;
+
+    if let Err(e) = result {
+        tracing::error!("Failed to release profile avatar: {e}");
+    }
+}
+
+/// Deletes the plaintext avatar blobs listed in `legacy_avatars` when the plaintext profiles
+/// were dropped, returning how many were deleted. Run once at startup, until the list is empty.
+pub async fn delete_legacy_avatars(
+    pool: &Pool<ConnectionManager<PgConnection>>,
+    storage: &dyn BlobStore,
+) -> Result<usize, String> {
+    let mut deleted = 0;
+    loop {
+        let hashes = legacy_avatars::table
+            .select(legacy_avatars::hash)
+            .limit(1000)
+            .load::<String>(&mut pool.get().map_err(|e| e.to_string())?)
+            .map_err(|e| e.to_string())?;
+        if hashes.is_empty() {
+            return Ok(deleted);
+        }
+
+        for hash in &hashes {
+            storage.delete(&legacy_avatar_key(hash)).await.map_err(|e| e.to_string())?;
+        }
+        diesel::delete(legacy_avatars::table.filter(legacy_avatars::hash.eq_any(&hashes)))
+            .execute(&mut pool.get().map_err(|e| e.to_string())?)
+            .map_err(|e| e.to_string())?;
+        deleted += hashes.len();
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SetEncryptedProfile {
+    /// Base64 ciphertexts, encrypted by the client with its profile key.
+    name: String,
+    about: Option<String>,
+    avatar: Option<String>,
+    /// Base64 access key derived from the profile key, which contacts present to fetch the profile.
+    access_key: String,
+}
+
+/// Stores the encrypted profile for `version` and drops every other version, so contacts holding
+/// a rotated-out profile key can no longer fetch it.
+pub async fn set_encrypted_profile(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    Path(version): Path<String>,
+    Json(payload): Json<SetEncryptedProfile>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    if !is_valid_version(&version) {
+        return (StatusCode::BAD_REQUEST, Json(json!({
+            "message": "Invalid profile version",
+            "status": 400,
+        })));
+    }
+
+    let decode = |value: &str| base64::engine::general_purpose::STANDARD.decode(value).ok();
+    let (Some(name), Some(access_key)) = (decode(&payload.name), decode(&payload.access_key)) else {
+        return (StatusCode::BAD_REQUEST, Json(json!({
+            "message": "Invalid base64",
+            "status": 400,
+        })));
+    };
+    let about = match payload.about.as_deref().map(decode) {
+        Some(None) => return (StatusCode::BAD_REQUEST, Json(json!({
+            "message": "Invalid base64",
+            "status": 400,
+        }))),
+        Some(a) => a,
+        None => None,
+    };
+    let avatar = match payload.avatar.as_deref().map(decode) {
+        Some(None) => return (StatusCode::BAD_REQUEST, Json(json!({
+            "message": "Invalid base64",
+            "status": 400,
+        }))),
+        Some(a) => a,
+        None => None,
+    };
+
+    if name.is_empty()
+        || name.len() > MAX_PROFILE_FIELD_SIZE
+        || about.as_ref().is_some_and(|a| a.len() > MAX_PROFILE_FIELD_SIZE)
+        || !(16..=64).contains(&access_key.len())
+    {
+        return (StatusCode::BAD_REQUEST, Json(json!({
+            "message": "Invalid profile",
+            "status": 400,
+        })));
+    }
+
+    let avatar_hash = avatar.as_ref().map(|avatar| format!("{:x}", Sha256::digest(avatar)));
+    let replace_profile = |conn: &mut PgConnection| conn.transaction::<_, diesel::result::Error, _>(|conn| {
+        let replaced = profiles::table
+            .filter(profiles::user_id.eq(auth.user_id))
+            .select(profiles::avatar_hash)
+            .load::<Option<String>>(conn)?;
+
+        diesel::delete(profiles::table.filter(profiles::user_id.eq(auth.user_id))).execute(conn)?;
+        diesel::insert_into(profiles::table)
+            .values((
+                profiles::user_id.eq(auth.user_id),
+                profiles::version.eq(&version),
+                profiles::name.eq(&name),
+                profiles::about.eq(&about),
+                profiles::avatar_hash.eq(&avatar_hash),
+                profiles::access_key_hash.eq(Sha256::digest(&access_key).to_vec()),
+            ))
+            .execute(conn)?;
+
+        Ok(replaced)
+    });
+
+    let mut conn = state.db.get().unwrap();
+    let result = match (avatar, &avatar_hash) {
+        (Some(avatar), Some(hash)) => {
+            let key = profile_avatar_key(hash);
+            with_blob_lock(&mut conn, &key, async |conn| {
+                store_blob(&state, &key, avatar).await?;
+                replace_profile(conn).map_err(|e| e.to_string())
+            }).await
+        }
+        _ => replace_profile(&mut conn).map_err(|e| e.to_string()),
+    };
+
+    let replaced = match result {
+        Ok(r) => r,
+        Err(e) => {
+            tracing::error!("Failed to store profile: {e}");
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({
+                "message": "Something went wrong",
+                "status": 500,
+            })));
+        }
+    };
+    for hash in replaced.into_iter().flatten() {
+        if Some(&hash) != avatar_hash.as_ref() {
+            release_profile_avatar(&state, &mut conn, &hash).await;
+        }
+    }
+
+    (StatusCode::OK, Json(json!({
+        "success": true,
+        "version": version,
+        "avatar_hash": avatar_hash,
+    })))
+}
+
+/// Loads the profile `version` of `user_id`, checking the `X-Profile-Access-Key` header unless
+/// the requester is the owner.
+fn load_encrypted_profile(
+    conn: &mut PgConnection,
+    auth: &AuthUser,
+    headers: &HeaderMap,
+    user_id: Uuid,
+    version: &str,
+) -> Result<Profile, (StatusCode, Json<serde_json::Value>)> {
+    let profile = profiles::table
+        .find((user_id, version))
+        .first::<Profile>(conn)
+        .optional()
+        .unwrap()
+        .ok_or((StatusCode::NOT_FOUND, Json(json!({
+            "message": "Profile not found",
+            "status": 404,
+        }))))?;
+
+    if auth.user_id != user_id {
+        let access_key = headers
+            .get("X-Profile-Access-Key")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| base64::engine::general_purpose::STANDARD.decode(v).ok())
+            .unwrap_or_default();
+        let matches: bool = Sha256::digest(&access_key)
+            .as_slice()
+            .ct_eq(&profile.access_key_hash)
+            .into();
+        if !matches {
+            return Err((StatusCode::UNAUTHORIZED, Json(json!({
+                "message": "Unauthorized",
+                "status": 401,
+            }))));
+        }
+    }
+
+    Ok(profile)
+}
+
+pub async fn get_encrypted_profile(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    headers: HeaderMap,
+    Path((user_id, version)): Path<(Uuid, String)>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let mut conn = state.db.get().unwrap();
+    let profile = match load_encrypted_profile(&mut conn, &auth, &headers, user_id, &version) {
+        Ok(p) => p,
+        Err(e) => return e,
+    };
+
+    let engine = base64::engine::general_purpose::STANDARD;
+    (StatusCode::OK, Json(json!({
+        "user_id": profile.user_id,
+        "version": profile.version,
+        "name": engine.encode(&profile.name),
+        "about": profile.about.map(|a| engine.encode(a)),
+        "avatar_hash": profile.avatar_hash,
+    })))
+}
+
+pub async fn get_encrypted_profile_avatar(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    headers: HeaderMap,
+    Path((user_id, version)): Path<(Uuid, String)>,
+) -> Response {
+    let mut conn = state.db.get().unwrap();
+    let profile = match load_encrypted_profile(&mut conn, &auth, &headers, user_id, &version) {
+        Ok(p) => p,
+        Err(e) => return e.into_response(),
+    };
+    let Some(hash) = profile.avatar_hash else {
+        return (StatusCode::NOT_FOUND, Json(json!({
+            "message": "Avatar not found",
+            "status": 404,
+        }))).into_response();
+    };
+
+    match state.storage.presigned_get_url(&profile_avatar_key(&hash)) {
+        Ok(Some(url)) => return Redirect::temporary(&url).into_response(),
+        Ok(None) => {}
+        Err(e) => tracing::error!("Failed to presign profile avatar download: {e}"),
+    }
+
+    match state.storage.get(&profile_avatar_key(&hash)).await {
+        Ok(Some(data)) => (
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, "application/octet-stream")],
+            data,
+        ).into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, Json(json!({
+            "message": "Avatar not found",
+            "status": 404,
+        }))).into_response(),
+        Err(e) => {
+            tracing::error!("Failed to read profile avatar: {e}");
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({
+                "message": "Something went wrong",
+                "status": 500,
+            }))).into_response()
+        }
+    }
+}
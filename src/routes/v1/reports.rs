@@ -0,0 +1,108 @@
+use crate::{AppState, AuthUser};
+use axum::http::StatusCode;
+use axum::{Extension, Json};
+use base64::Engine;
+use chrono::DateTime;
+use diesel::prelude::*;
+use e2ee_back::franking::{verify_commitment, verify_tag, FRANKING_SIZE};
+use e2ee_back::rate_limit::Limit;
+use e2ee_back::schema::{reports, users};
+use serde::Deserialize;
+use serde_json::json;
+use uuid::Uuid;
+
+const MAX_PLAINTEXT_SIZE: usize = 64 * 1024;
+const MAX_REASON_LENGTH: usize = 1000;
+
+#[derive(Deserialize)]
+pub struct ReportMessage {
+    sender_user_id: Uuid,
+    /// `created_at` of the reported message, in milliseconds since the epoch.
+    timestamp: i64,
+    /// Base64 values, as received with the message.
+    franking_commitment: String,
+    franking_tag: String,
+    /// Base64 key and plaintext, as decrypted by the recipient.
+    franking_key: String,
+    plaintext: String,
+    reason: Option<String>,
+}
+
+/// Queues a reported message for moderation.
+///
+/// The franking tag proves that `sender_user_id` sent the commitment to the reporter at
+/// `timestamp`, and the commitment proves the plaintext, so a report can't be forged from content
+/// the sender never sent, nor filed by anyone the message wasn't sent to.
+pub async fn report_message(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    Json(payload): Json<ReportMessage>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    if let Err(e) = state.rate_limits
+        .acquire(&format!("report:user:{}", auth.user_id), Limit::new(20, 3600), 1)
+        .await
+    {
+        return e.to_json("Too many requests, please retry later");
+    }
+
+    let engine = base64::engine::general_purpose::STANDARD;
+    let decoded = (
+        engine.decode(&payload.franking_commitment).ok().filter(|c| c.len() == FRANKING_SIZE),
+        engine.decode(&payload.franking_tag).ok().filter(|t| t.len() == FRANKING_SIZE),
+        engine.decode(&payload.franking_key).ok().filter(|k| !k.is_empty()),
+        engine.decode(&payload.plaintext).ok().filter(|p| p.len() <= MAX_PLAINTEXT_SIZE),
+        DateTime::from_timestamp_millis(payload.timestamp),
+    );
+    let (Some(commitment), Some(tag), Some(key), Some(plaintext), Some(sent_at)) = decoded else {
+        return (StatusCode::BAD_REQUEST, Json(json!({
+            "message": "Invalid report",
+            "status": 400,
+        })));
+    };
+    if payload.reason.as_ref().is_some_and(|r| r.chars().count() > MAX_REASON_LENGTH) {
+        return (StatusCode::BAD_REQUEST, Json(json!({
+            "message": format!("Reasons are limited to {MAX_REASON_LENGTH} characters"),
+            "status": 400,
+        })));
+    }
+
+    if !verify_tag(&state.franking_secret, &commitment, payload.sender_user_id, auth.user_id, payload.timestamp, &tag)
+        || !verify_commitment(&key, &plaintext, &commitment)
+    {
+        return (StatusCode::UNPROCESSABLE_ENTITY, Json(json!({
+            "message": "The report could not be verified",
+            "status": 422,
+        })));
+    }
+
+    let mut conn = state.db.get().unwrap();
+    // The sender may have deleted their account since; the report is kept regardless.
+    let reported_user_id = users::table
+        .find(payload.sender_user_id)
+        .select(users::id)
+        .first::<Uuid>(&mut conn)
+        .optional()
+        .unwrap();
+
+    let result = diesel::insert_into(reports::table)
+        .values((
+            reports::reporter_user_id.eq(auth.user_id),
+            reports::reported_user_id.eq(reported_user_id),
+            reports::plaintext.eq(&plaintext),
+            reports::reason.eq(&payload.reason),
+            reports::message_sent_at.eq(sent_at),
+        ))
+        .returning(reports::id)
+        .get_result::<i64>(&mut conn);
+
+    match result {
+        Ok(id) => (StatusCode::OK, Json(json!({"success": true, "report_id": id}))),
+        Err(e) => {
+            tracing::error!("Failed to store report: {e}");
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({
+                "message": "Something went wrong",
+                "status": 500,
+            })))
+        }
+    }
+}
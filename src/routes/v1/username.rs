@@ -0,0 +1,332 @@
+use crate::{AppState, AuthUser};
+use axum::extract::Path;
+use axum::http::StatusCode;
+use axum::{Extension, Json};
+use base64::Engine;
+use chrono::{Duration, Utc};
+use diesel::prelude::*;
+use diesel::result::DatabaseErrorKind;
+use diesel::result::Error::DatabaseError;
+use e2ee_back::rate_limit::Limit;
+use e2ee_back::schema::{username_links, usernames};
+use e2ee_back::username::{hash_username, normalize_nickname, normalize_username};
+use rand::Rng;
+use serde::Deserialize;
+use serde_json::json;
+use uuid::Uuid;
+
+const ATTEMPTS_PER_LENGTH: usize = 10;
+const MAX_ENCRYPTED_USERNAME_SIZE: usize = 128;
+
+#[derive(Deserialize)]
+pub struct ReserveUsername {
+    nickname: String,
+}
+
+/// Reserves `nickname.<random discriminator>` for 5 minutes, replacing any previous reservation.
+/// It only becomes the user's username once confirmed with [`confirm_username`].
+pub async fn reserve_username(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    Json(payload): Json<ReserveUsername>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let nickname = match normalize_nickname(&payload.nickname) {
+        Some(n) => n,
+        None => return (StatusCode::BAD_REQUEST, Json(json!({
+            "message": "Nicknames must be 3 to 32 letters, digits or underscores, starting with a letter",
+            "status": 400,
+        }))),
+    };
+
+    let mut conn = state.db.get().unwrap();
+    let now = Utc::now();
+    diesel::delete(usernames::table.filter(
+        usernames::confirmed.eq(false).and(
+            usernames::user_id.eq(auth.user_id).or(usernames::reserved_until.lt(now)),
+        ),
+    ))
+        .execute(&mut conn)
+        .unwrap();
+
+    // Short discriminators first, moving to longer ones as the nickname gets crowded.
+    for length in 2..=9u32 {
+        for _ in 0..ATTEMPTS_PER_LENGTH {
+            let discriminator = rand::rng().random_range(1..10u32.pow(length));
+            let username = format!("{nickname}.{discriminator:0width$}", width = length as usize);
+
+            let inserted = diesel::insert_into(usernames::table)
+                .values((
+                    usernames::username_hash.eq(hash_username(&state.username_secret, &username)),
+                    usernames::user_id.eq(auth.user_id),
+                    usernames::confirmed.eq(false),
+                    usernames::reserved_until.eq(now + Duration::minutes(5)),
+                ))
+                .on_conflict(usernames::username_hash)
+                .do_nothing()
+                .execute(&mut conn);
+
+            match inserted {
+                Ok(1) => return (StatusCode::OK, Json(json!({
+                    "username": username,
+                    "reserved_until": now + Duration::minutes(5),
+                }))),
+                // Taken, try another discriminator.
+                Ok(_) => {}
+                // Another reservation of the same user got in since the previous one was deleted.
+                Err(DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+                    return (StatusCode::CONFLICT, Json(json!({
+                        "message": "Another reservation is in progress",
+                        "status": 409,
+                    })));
+                }
+                Err(e) => {
+                    tracing::error!("Failed to reserve username: {e}");
+                    return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({
+                        "message": "Something went wrong",
+                        "status": 500,
+                    })));
+                }
+            }
+        }
+    }
+
+    (StatusCode::CONFLICT, Json(json!({
+        "message": "No username available for this nickname",
+        "status": 409,
+    })))
+}
+
+#[derive(Deserialize)]
+pub struct ConfirmUsername {
+    username: String,
+}
+
+/// Turns the user's pending reservation into their username, replacing the previous one and
+/// deleting the username link.
+pub async fn confirm_username(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    Json(payload): Json<ConfirmUsername>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let username = match normalize_username(&payload.username) {
+        Some(u) => u,
+        None => return (StatusCode::BAD_REQUEST, Json(json!({
+            "message": "Invalid username",
+            "status": 400,
+        }))),
+    };
+    let hash = hash_username(&state.username_secret, &username);
+
+    let mut conn = state.db.get().unwrap();
+    let confirmed = conn.transaction::<_, diesel::result::Error, _>(|conn| {
+        let reservation = usernames::table
+            .find(&hash)
+            .filter(usernames::user_id.eq(auth.user_id))
+            .filter(usernames::confirmed.eq(false))
+            .filter(usernames::reserved_until.gt(Utc::now()))
+            .select(usernames::username_hash)
+            .first::<Vec<u8>>(conn)
+            .optional()?;
+        if reservation.is_none() {
+            return Ok(false);
+        }
+
+        diesel::delete(usernames::table
+            .filter(usernames::user_id.eq(auth.user_id))
+            .filter(usernames::confirmed.eq(true)))
+            .execute(conn)?;
+        // The link still encrypts the old username, which someone else may claim from now on.
+        diesel::delete(username_links::table.find(auth.user_id)).execute(conn)?;
+        diesel::update(usernames::table.find(&hash))
+            .set((
+                usernames::confirmed.eq(true),
+                usernames::reserved_until.eq(None::<chrono::DateTime<Utc>>),
+            ))
+            .execute(conn)?;
+        Ok(true)
+    });
+
+    match confirmed {
+        Ok(true) => (StatusCode::OK, Json(json!({
+            "success": true,
+            "username": username,
+        }))),
+        Ok(false) => (StatusCode::CONFLICT, Json(json!({
+            "message": "Username not reserved, or the reservation expired",
+            "status": 409,
+        }))),
+        Err(e) => {
+            tracing::error!("Failed to confirm username: {e}");
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({
+                "message": "Something went wrong",
+                "status": 500,
+            })))
+        }
+    }
+}
+
+/// Deletes the user's username, any pending reservation and the username link.
+pub async fn delete_username(
+    state: Extension<AppState>,
+    auth: AuthUser,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let mut conn = state.db.get().unwrap();
+    diesel::delete(usernames::table.filter(usernames::user_id.eq(auth.user_id)))
+        .execute(&mut conn)
+        .unwrap();
+    diesel::delete(username_links::table.find(auth.user_id))
+        .execute(&mut conn)
+        .unwrap();
+
+    (StatusCode::OK, Json(json!({"success": true})))
+}
+
+pub async fn lookup_username(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    Path(username): Path<String>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    if let Err(e) = state.rate_limits
+        .acquire(&format!("username_lookup:user:{}", auth.user_id), Limit::new(100, 3600), 1)
+        .await
+    {
+        return e.to_json("Too many requests, please retry later");
+    }
+
+    let Some(username) = normalize_username(&username) else {
+        return (StatusCode::BAD_REQUEST, Json(json!({
+            "message": "Invalid username",
+            "status": 400,
+        })));
+    };
+
+    let mut conn = state.db.get().unwrap();
+    let user_id = usernames::table
+        .find(hash_username(&state.username_secret, &username))
+        .filter(usernames::confirmed.eq(true))
+        .select(usernames::user_id)
+        .first::<Uuid>(&mut conn)
+        .optional()
+        .unwrap();
+
+    match user_id {
+        Some(id) => (StatusCode::OK, Json(json!({"user_id": id}))),
+        None => (StatusCode::NOT_FOUND, Json(json!({
+            "message": "Username not found",
+            "status": 404,
+        }))),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SetUsernameLink {
+    /// Base64 username, encrypted by the client with a random key that only travels in the link.
+    encrypted_username: String,
+    /// Replace the handle, so previously shared links and QR codes stop resolving.
+    #[serde(default)]
+    reset: bool,
+}
+
+/// Creates or updates the user's contact link.
+///
+/// The link shared by the client (as a URL or QR code) carries the returned `handle` plus the
+/// key used to encrypt the username, which never reaches the server.
+pub async fn set_username_link(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    Json(payload): Json<SetUsernameLink>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let encrypted_username = match base64::engine::general_purpose::STANDARD.decode(&payload.encrypted_username) {
+        Ok(e) if !e.is_empty() && e.len() <= MAX_ENCRYPTED_USERNAME_SIZE => e,
+        _ => return (StatusCode::BAD_REQUEST, Json(json!({
+            "message": "Invalid encrypted username",
+            "status": 400,
+        }))),
+    };
+
+    let mut conn = state.db.get().unwrap();
+    let has_username = usernames::table
+        .filter(usernames::user_id.eq(auth.user_id))
+        .filter(usernames::confirmed.eq(true))
+        .count()
+        .get_result::<i64>(&mut conn)
+        .unwrap() > 0;
+    if !has_username {
+        return (StatusCode::CONFLICT, Json(json!({
+            "message": "Set a username first",
+            "status": 409,
+        })));
+    }
+
+    let existing = username_links::table
+        .find(auth.user_id)
+        .select(username_links::handle)
+        .first::<Uuid>(&mut conn)
+        .optional()
+        .unwrap();
+    let handle = match existing {
+        Some(handle) if !payload.reset => handle,
+        _ => Uuid::new_v4(),
+    };
+
+    diesel::insert_into(username_links::table)
+        .values((
+            username_links::user_id.eq(auth.user_id),
+            username_links::handle.eq(handle),
+            username_links::encrypted_username.eq(&encrypted_username),
+        ))
+        .on_conflict(username_links::user_id)
+        .do_update()
+        .set((
+            username_links::handle.eq(handle),
+            username_links::encrypted_username.eq(&encrypted_username),
+            username_links::created_at.eq(Utc::now()),
+        ))
+        .execute(&mut conn)
+        .unwrap();
+
+    (StatusCode::OK, Json(json!({"handle": handle})))
+}
+
+pub async fn get_username_link(
+    state: Extension<AppState>,
+    auth: AuthUser,
+    Path(handle): Path<Uuid>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    if let Err(e) = state.rate_limits
+        .acquire(&format!("username_lookup:user:{}", auth.user_id), Limit::new(100, 3600), 1)
+        .await
+    {
+        return e.to_json("Too many requests, please retry later");
+    }
+
+    let mut conn = state.db.get().unwrap();
+    let encrypted_username = username_links::table
+        .filter(username_links::handle.eq(handle))
+        .select(username_links::encrypted_username)
+        .first::<Vec<u8>>(&mut conn)
+        .optional()
+        .unwrap();
+
+    match encrypted_username {
+        Some(e) => (StatusCode::OK, Json(json!({
+            "encrypted_username": base64::engine::general_purpose::STANDARD.encode(e),
+        }))),
+        None => (StatusCode::NOT_FOUND, Json(json!({
+            "message": "Link not found",
+            "status": 404,
+        }))),
+    }
+}
+
+pub async fn delete_username_link(
+    state: Extension<AppState>,
+    auth: AuthUser,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let mut conn = state.db.get().unwrap();
+    diesel::delete(username_links::table.find(auth.user_id))
+        .execute(&mut conn)
+        .unwrap();
+
+    (StatusCode::OK, Json(json!({"success": true})))
+}
@@ -1,5 +1,82 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    accepted_conversations (user_id, peer_user_id) {
+        user_id -> Uuid,
+        peer_user_id -> Uuid,
+        created_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    attachment_uploads (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        size -> Int8,
+        received -> Int8,
+        expires_at -> Timestamptz,
+        created_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    attachments (id) {
+        id -> Uuid,
+        uploader_user_id -> Nullable<Uuid>,
+        size -> Int8,
+        download_token_hash -> Bytea,
+        created_at -> Nullable<Timestamptz>,
+        referenced -> Bool,
+        confirmed -> Bool,
+    }
+}
+
+diesel::table! {
+    blocks (blocker_user_id, blocked_user_id) {
+        blocker_user_id -> Uuid,
+        blocked_user_id -> Uuid,
+        created_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    channel_admins (channel_id, user_id) {
+        channel_id -> Uuid,
+        user_id -> Uuid,
+        created_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    channel_posts (id) {
+        id -> Int8,
+        channel_id -> Uuid,
+        author_user_id -> Nullable<Uuid>,
+        ciphertext -> Bytea,
+        created_at -> Timestamptz,
+        seq -> Int8,
+    }
+}
+
+diesel::table! {
+    channel_subscribers (channel_id, user_id) {
+        channel_id -> Uuid,
+        user_id -> Uuid,
+        subscribed_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    channels (id) {
+        id -> Uuid,
+        owner_user_id -> Uuid,
+        state -> Bytea,
+        retention_days -> Int4,
+        created_at -> Nullable<Timestamptz>,
+        last_post_seq -> Int8,
+    }
+}
+
 diesel::table! {
     devices (id) {
         id -> Uuid,
@@ -12,6 +89,65 @@ diesel::table! {
         signed_prekey_pub -> Bytea,
         signed_prekey_signature -> Bytea,
         push_token -> Nullable<Text>,
+        push_platform -> Nullable<Text>,
+        push_pending_since -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    group_join_requests (group_id, user_id) {
+        group_id -> Uuid,
+        user_id -> Uuid,
+        created_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    group_members (group_id, user_id) {
+        group_id -> Uuid,
+        user_id -> Uuid,
+        role -> Text,
+        joined_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    group_payloads (id) {
+        id -> Int8,
+        group_id -> Uuid,
+        sender_user_id -> Nullable<Uuid>,
+        sender_device_id -> Nullable<Uuid>,
+        ciphertext -> Bytea,
+        created_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    groups (id) {
+        id -> Uuid,
+        state -> Bytea,
+        version -> Int8,
+        created_at -> Nullable<Timestamptz>,
+        updated_at -> Nullable<Timestamptz>,
+        add_members_access -> Text,
+        edit_state_access -> Text,
+        send_access -> Text,
+        invite_link_token_hash -> Nullable<Bytea>,
+        invite_link_requires_approval -> Bool,
+    }
+}
+
+diesel::table! {
+    legacy_avatars (hash) {
+        #[max_length = 64]
+        hash -> Varchar,
+    }
+}
+
+diesel::table! {
+    message_attachments (message_id, attachment_id) {
+        message_id -> Int8,
+        attachment_id -> Uuid,
     }
 }
 
@@ -27,6 +163,10 @@ diesel::table! {
         protocol_version -> Int2,
         delivered_at -> Nullable<Timestamptz>,
         created_at -> Nullable<Timestamptz>,
+        is_request -> Bool,
+        franking_commitment -> Nullable<Bytea>,
+        franking_tag -> Nullable<Bytea>,
+        group_payload_id -> Nullable<Int8>,
     }
 }
 
@@ -40,35 +180,129 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    profiles (user_id, version) {
+        user_id -> Uuid,
+        #[max_length = 64]
+        version -> Varchar,
+        name -> Bytea,
+        about -> Nullable<Bytea>,
+        #[max_length = 64]
+        avatar_hash -> Nullable<Varchar>,
+        access_key_hash -> Bytea,
+        created_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    rate_limit_buckets (key) {
+        key -> Text,
+        tokens -> Float8,
+        updated_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    reports (id) {
+        id -> Int8,
+        reporter_user_id -> Nullable<Uuid>,
+        reported_user_id -> Nullable<Uuid>,
+        plaintext -> Bytea,
+        reason -> Nullable<Text>,
+        message_sent_at -> Timestamptz,
+        status -> Text,
+        created_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    username_links (user_id) {
+        user_id -> Uuid,
+        handle -> Uuid,
+        encrypted_username -> Bytea,
+        created_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    usernames (username_hash) {
+        username_hash -> Bytea,
+        user_id -> Uuid,
+        confirmed -> Bool,
+        reserved_until -> Nullable<Timestamptz>,
+        created_at -> Nullable<Timestamptz>,
+    }
+}
+
 diesel::table! {
     users (id) {
         id -> Uuid,
-        name -> Text,
         #[max_length = 100]
         phone_number -> Varchar,
-        #[max_length = 64]
-        avatar_hash -> Nullable<Varchar>,
         last_seen -> Nullable<Timestamptz>,
         created_at -> Nullable<Timestamptz>,
+        phone_number_hash -> Nullable<Bytea>,
     }
 }
 
 diesel::table! {
-    verification_codes (phone_number) {
+    verification_codes (phone_number, purpose) {
         phone_number -> Text,
         code_hash -> Text,
         expires_at -> Timestamptz,
         attempt_count -> Nullable<Int4>,
+        purpose -> Text,
     }
 }
 
+diesel::joinable!(attachment_uploads -> users (user_id));
+diesel::joinable!(attachments -> users (uploader_user_id));
+diesel::joinable!(channel_admins -> channels (channel_id));
+diesel::joinable!(channel_admins -> users (user_id));
+diesel::joinable!(channel_posts -> channels (channel_id));
+diesel::joinable!(channel_posts -> users (author_user_id));
+diesel::joinable!(channel_subscribers -> channels (channel_id));
+diesel::joinable!(channel_subscribers -> users (user_id));
+diesel::joinable!(channels -> users (owner_user_id));
 diesel::joinable!(devices -> users (user_id));
+diesel::joinable!(group_join_requests -> groups (group_id));
+diesel::joinable!(group_join_requests -> users (user_id));
+diesel::joinable!(group_members -> groups (group_id));
+diesel::joinable!(group_members -> users (user_id));
+diesel::joinable!(group_payloads -> devices (sender_device_id));
+diesel::joinable!(group_payloads -> groups (group_id));
+diesel::joinable!(group_payloads -> users (sender_user_id));
+diesel::joinable!(message_attachments -> attachments (attachment_id));
+diesel::joinable!(message_attachments -> messages (message_id));
+diesel::joinable!(messages -> group_payloads (group_payload_id));
 diesel::joinable!(one_time_prekeys -> devices (device_id));
+diesel::joinable!(profiles -> users (user_id));
+diesel::joinable!(username_links -> users (user_id));
+diesel::joinable!(usernames -> users (user_id));
 
 diesel::allow_tables_to_appear_in_same_query!(
+    accepted_conversations,
+    attachment_uploads,
+    attachments,
+    blocks,
+    channel_admins,
+    channel_posts,
+    channel_subscribers,
+    channels,
     devices,
+    group_join_requests,
+    group_members,
+    group_payloads,
+    groups,
+    legacy_avatars,
+    message_attachments,
     messages,
     one_time_prekeys,
+    profiles,
+    rate_limit_buckets,
+    reports,
+    username_links,
+    usernames,
     users,
     verification_codes,
 );
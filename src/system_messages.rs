@@ -0,0 +1,90 @@
+use crate::schema::{accepted_conversations, blocks, devices, group_members, messages};
+use diesel::prelude::*;
+use serde_json::Value;
+use uuid::Uuid;
+
+/// `messages.message_type` of server-generated messages. Their ciphertext is a plaintext JSON event.
+pub const SYSTEM_MESSAGE_TYPE: i16 = 2;
+
+/// Queues `payload` for every active device of `user_ids`. Returns the devices it was queued for,
+/// to wake once the transaction commits.
+pub fn queue_for_users(conn: &mut PgConnection, user_ids: &[Uuid], payload: &Value) -> QueryResult<Vec<Uuid>> {
+    let targets = devices::table
+        .filter(devices::user_id.eq_any(user_ids))
+        .filter(devices::is_revoked.is_distinct_from(true))
+        .select((devices::user_id, devices::id))
+        .load::<(Option<Uuid>, Uuid)>(conn)?;
+
+    queue_for_devices(conn, &targets, payload)
+}
+
+/// Queues `payload` for the given `(user_id, device_id)` pairs. Returns the devices, to wake once
+/// the transaction commits.
+pub fn queue_for_devices(
+    conn: &mut PgConnection,
+    targets: &[(Option<Uuid>, Uuid)],
+    payload: &Value,
+) -> QueryResult<Vec<Uuid>> {
+    let body = serde_json::to_vec(payload).expect("JSON values always serialize");
+    let rows: Vec<_> = targets
+        .iter()
+        .map(|(user_id, device_id)| (
+            messages::recipient_user_id.eq(*user_id),
+            messages::recipient_device_id.eq(*device_id),
+            messages::ciphertext.eq(&body),
+            messages::message_type.eq(SYSTEM_MESSAGE_TYPE),
+        ))
+        .collect();
+
+    diesel::insert_into(messages::table)
+        .values(&rows)
+        .execute(conn)?;
+    Ok(targets.iter().map(|(_, device_id)| *device_id).collect())
+}
+
+/// Returns the users who know `user_id`: those it has accepted conversations with (either way),
+/// shares a group with, or has queued messages with. Users who blocked `user_id` are left out.
+pub fn contacts_of(conn: &mut PgConnection, user_id: Uuid) -> QueryResult<Vec<Uuid>> {
+    let mut contacts: Vec<Uuid> = accepted_conversations::table
+        .filter(accepted_conversations::user_id.eq(user_id))
+        .select(accepted_conversations::peer_user_id)
+        .load::<Uuid>(conn)?;
+    contacts.extend(accepted_conversations::table
+        .filter(accepted_conversations::peer_user_id.eq(user_id))
+        .select(accepted_conversations::user_id)
+        .load::<Uuid>(conn)?);
+
+    let groups: Vec<Uuid> = group_members::table
+        .filter(group_members::user_id.eq(user_id))
+        .select(group_members::group_id)
+        .load(conn)?;
+    contacts.extend(group_members::table
+        .filter(group_members::group_id.eq_any(&groups))
+        .select(group_members::user_id)
+        .load::<Uuid>(conn)?);
+
+    contacts.extend(messages::table
+        .filter(messages::sender_user_id.eq(user_id))
+        .select(messages::recipient_user_id)
+        .distinct()
+        .load::<Option<Uuid>>(conn)?
+        .into_iter()
+        .flatten());
+    contacts.extend(messages::table
+        .filter(messages::recipient_user_id.eq(user_id))
+        .select(messages::sender_user_id)
+        .distinct()
+        .load::<Option<Uuid>>(conn)?
+        .into_iter()
+        .flatten());
+
+    let blocked_by: Vec<Uuid> = blocks::table
+        .filter(blocks::blocked_user_id.eq(user_id))
+        .select(blocks::blocker_user_id)
+        .load(conn)?;
+
+    contacts.retain(|id| *id != user_id && !blocked_by.contains(id));
+    contacts.sort();
+    contacts.dedup();
+    Ok(contacts)
+}
@@ -0,0 +1,950 @@
+//! Request-level tests against a real database.
+//!
+//! They need `DATABASE_URL` pointing at a migrated database, and are skipped when it isn't set.
+
+use crate::routes::v1::profile::delete_legacy_avatars;
+use crate::{app, AppState};
+use axum::body::Body;
+use axum::extract::connect_info::MockConnectInfo;
+use axum::http::{Method, Request, StatusCode};
+use axum::Router;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use diesel::prelude::*;
+use diesel::r2d2::{self, ConnectionManager};
+use e2ee_back::otp::MemoryOtpSender;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use e2ee_back::pow::ProofOfWork;
+use e2ee_back::push::{MemoryPushProvider, PushDispatcher, PushProviders};
+use e2ee_back::rate_limit::MemoryRateLimitStore;
+use e2ee_back::attachments::{self, attachment_key};
+use e2ee_back::groups;
+use e2ee_back::schema::{self, accepted_conversations, devices, group_payloads, messages, users};
+use e2ee_back::storage::{BlobStore, LocalBlobStore, StorageError};
+use e2ee_back::uploads::UploadStaging;
+use rand::Rng;
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tower::ServiceExt;
+use uuid::Uuid;
+
+pub struct TestApp {
+    pub router: Router,
+    pub state: AppState,
+    pub otp: MemoryOtpSender,
+    pub push: MemoryPushProvider,
+    dir: tempfile::TempDir,
+}
+
+impl TestApp {
+    /// Builds the app with in-memory OTP, push and rate limit backends, or returns `None` when
+    /// `DATABASE_URL` isn't set.
+    pub fn new() -> Option<Self> {
+        Self::with_debounce(Duration::from_millis(50))
+    }
+
+    pub fn with_debounce(debounce: Duration) -> Option<Self> {
+        let database_url = std::env::var("DATABASE_URL").ok()?;
+        let db = r2d2::Pool::builder()
+            .max_size(4)
+            .build(ConnectionManager::<PgConnection>::new(database_url))
+            .expect("Failed to create pool.");
+        let dir = tempfile::tempdir().unwrap();
+        let otp = MemoryOtpSender::new();
+        let push = MemoryPushProvider::new();
+
+        let state = AppState {
+            push: Arc::new(PushDispatcher::new(db.clone(), PushProviders::all(Arc::new(push.clone())), None)
+                .with_debounce(debounce)),
+            db,
+            jwt_secret: "test jwt secret".into(),
+            otp_sender: Arc::new(otp.clone()),
+            rate_limits: Arc::new(MemoryRateLimitStore::new()),
+            pow: Arc::new(ProofOfWork::new(b"test pow secret", 0, 24)),
+            storage: Arc::new(LocalBlobStore::new(dir.path().join("blobs"))),
+            username_secret: b"test username secret".to_vec(),
+            franking_secret: b"test franking secret".to_vec(),
+            discovery_quota: 5000,
+            proxy_hops: 0,
+            uploads: Arc::new(UploadStaging::new(dir.path().join("uploads"))),
+            attachment_max_size: 1024 * 1024,
+            storage_quota: 10 * 1024 * 1024,
+        };
+
+        Some(Self {
+            router: app(state.clone()).layer(MockConnectInfo(SocketAddr::from(([127, 0, 0, 1], 4000)))),
+            state,
+            otp,
+            push,
+            dir,
+        })
+    }
+
+    /// Temporary directory holding the blobs and staged uploads.
+    pub fn dir(&self) -> &std::path::Path {
+        self.dir.path()
+    }
+
+    /// Changes the state, e.g. to swap a backend, and rebuilds the routes with it.
+    pub fn with_state(mut self, change: impl FnOnce(&mut AppState)) -> Self {
+        change(&mut self.state);
+        self.router = app(self.state.clone()).layer(MockConnectInfo(SocketAddr::from(([127, 0, 0, 1], 4000))));
+        self
+    }
+
+    pub async fn request(&self, method: Method, path: &str, token: Option<&str>, body: Value) -> (StatusCode, Value) {
+        let mut request = Request::builder().method(method).uri(path);
+        if let Some(token) = token {
+            request = request.header("Authorization", format!("Bearer {token}"));
+        }
+        // `Value::Null` sends no body at all.
+        let body = if body.is_null() {
+            Body::empty()
+        } else {
+            request = request.header("Content-Type", "application/json");
+            Body::from(body.to_string())
+        };
+
+        let response = self.router
+            .clone()
+            .oneshot(request.body(body).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+
+        (status, serde_json::from_slice(&bytes).unwrap_or(Value::Null))
+    }
+
+    /// Registers a fresh phone number, returning it with the confirmation response.
+    pub async fn register(&self) -> (String, Value) {
+        let phone = random_phone();
+        let (status, _) = self.request(Method::POST, "/v1/register", None, json!({"phone_number": phone})).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+
+        let otp = self.otp.last_code(&phone).expect("a code was sent");
+        let (status, body) = self.request(Method::POST, "/v1/register/confirm", None, json!({
+            "phone_number": phone,
+            "otp": otp,
+        })).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+
+        (phone, body)
+    }
+
+    /// Registers a fresh phone number and uploads the keys of its device, returning the phone
+    /// number, user id and auth token.
+    pub async fn login(&self) -> (String, Uuid, String) {
+        let (phone, body) = self.register().await;
+        let token = body["auth_token"].as_str().unwrap().to_string();
+        let user_id = body["user_id"].as_str().unwrap().parse().unwrap();
+
+        let key = BASE64.encode([7u8; 32]);
+        let (status, body) = self.request(Method::POST, "/v1/keys/upload", Some(&token), json!({
+            "identity_key_pub": key,
+            "signed_prekey_pub": key,
+            "signed_prekey_signature": BASE64.encode([7u8; 64]),
+            "one_time_prekeys": [key],
+            "device_name": "test",
+            "push_token": "",
+        })).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+
+        (phone, user_id, token)
+    }
+
+    /// Returns the id of the (only) device of `user_id`.
+    pub fn device_of(&self, user_id: Uuid) -> Uuid {
+        let mut conn = self.state.db.get().unwrap();
+        devices::table
+            .filter(devices::user_id.eq(user_id))
+            .select(devices::id)
+            .first(&mut conn)
+            .unwrap()
+    }
+
+    pub fn delete_user(&self, phone: &str) {
+        let mut conn = self.state.db.get().unwrap();
+        diesel::delete(users::table.filter(users::phone_number.eq(phone)))
+            .execute(&mut conn)
+            .unwrap();
+    }
+}
+
+/// A random, valid German mobile number in E.164 form.
+pub fn random_phone() -> String {
+    format!("+49151{:08}", rand::rng().random_range(0..100_000_000u32))
+}
+
+#[tokio::test]
+async fn register_with_code_read_back() {
+    let Some(app) = TestApp::new() else { return };
+
+    let (phone, body) = app.register().await;
+    assert!(body["auth_token"].is_string());
+    assert_eq!(app.otp.codes(&phone).len(), 1);
+
+    // The code is single use.
+    let otp = app.otp.last_code(&phone).unwrap();
+    let (status, _) = app.request(Method::POST, "/v1/register/confirm", None, json!({
+        "phone_number": phone,
+        "otp": otp,
+    })).await;
+    assert_ne!(status, StatusCode::OK);
+
+    app.delete_user(&phone);
+}
+
+#[tokio::test]
+async fn phone_change_and_deletion_require_proof_of_work() {
+    let Some(app) = TestApp::new() else { return };
+    let (phone, _, token) = app.login().await;
+    let app = app.with_state(|state| state.pow = Arc::new(ProofOfWork::new(b"test pow secret", 4, 24)));
+
+    let (status, _) = app.request(Method::POST, "/v1/account/phone", Some(&token), json!({
+        "phone_number": random_phone(),
+    })).await;
+    assert_eq!(status, StatusCode::PRECONDITION_REQUIRED);
+    let (status, _) = app.request(Method::POST, "/v1/account/delete", Some(&token), json!({})).await;
+    assert_eq!(status, StatusCode::PRECONDITION_REQUIRED);
+    assert_eq!(app.otp.codes(&phone).len(), 1);
+
+    app.delete_user(&phone);
+}
+
+#[tokio::test]
+async fn codes_only_confirm_their_purpose() {
+    let Some(app) = TestApp::new() else { return };
+    let (phone, _, token) = app.login().await;
+
+    let (status, _) = app.request(Method::POST, "/v1/register", None, json!({"phone_number": phone})).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    let register_code = app.otp.last_code(&phone).unwrap();
+    let (status, _) = app.request(Method::DELETE, "/v1/account", Some(&token), json!({"otp": register_code})).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let (status, _) = app.request(Method::POST, "/v1/account/delete", Some(&token), json!({})).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    let delete_code = app.otp.last_code(&phone).unwrap();
+    let (status, _) = app.request(Method::DELETE, "/v1/account", Some(&token), json!({"otp": delete_code})).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn tokens_of_missing_or_revoked_devices_are_rejected() {
+    let Some(app) = TestApp::new() else { return };
+    let (phone, body) = app.register().await;
+    let token = body["auth_token"].as_str().unwrap();
+
+    // The device only exists once its keys are uploaded.
+    let (status, _) = app.request(Method::GET, "/v1/devices", Some(token), Value::Null).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let (_, user_id, token) = app.login().await;
+    let (status, _) = app.request(Method::GET, "/v1/devices", Some(&token), Value::Null).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let mut conn = app.state.db.get().unwrap();
+    diesel::update(devices::table.filter(devices::user_id.eq(user_id)))
+        .set(devices::is_revoked.eq(true))
+        .execute(&mut conn)
+        .unwrap();
+    let (status, _) = app.request(Method::GET, "/v1/devices", Some(&token), Value::Null).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    app.delete_user(&phone);
+    diesel::delete(users::table.find(user_id)).execute(&mut conn).unwrap();
+}
+
+#[tokio::test]
+async fn account_deletion_notifies_accepted_contacts() {
+    let Some(app) = TestApp::new() else { return };
+    let (phone, deleted_id, token) = app.login().await;
+    let (contact_phone, contact_id, _) = app.login().await;
+
+    // The contact accepted a conversation, but no message is queued between them any more.
+    let mut conn = app.state.db.get().unwrap();
+    diesel::insert_into(accepted_conversations::table)
+        .values((
+            accepted_conversations::user_id.eq(contact_id),
+            accepted_conversations::peer_user_id.eq(deleted_id),
+        ))
+        .execute(&mut conn)
+        .unwrap();
+
+    let (status, _) = app.request(Method::POST, "/v1/account/delete", Some(&token), Value::Null).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    let otp = app.otp.last_code(&phone).unwrap();
+    let (status, _) = app.request(Method::DELETE, "/v1/account", Some(&token), json!({"otp": otp})).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let notices = messages::table
+        .filter(messages::recipient_user_id.eq(contact_id))
+        .select(messages::ciphertext)
+        .load::<Vec<u8>>(&mut conn)
+        .unwrap();
+    assert_eq!(notices.len(), 1);
+    let notice: Value = serde_json::from_slice(&notices[0]).unwrap();
+    assert_eq!(notice, json!({"type": "account_deleted", "user_id": deleted_id}));
+
+    app.delete_user(&contact_phone);
+}
+
+#[tokio::test]
+async fn shared_avatars_are_kept_until_released_by_everyone() {
+    let Some(app) = TestApp::new() else { return };
+    let (first_phone, _, first) = app.login().await;
+    let (second_phone, _, second) = app.login().await;
+    let avatar = BASE64.encode(format!("avatar {}", Uuid::new_v4()));
+    let profile = |avatar: Option<&str>| json!({
+        "name": BASE64.encode("name"),
+        "avatar": avatar,
+        "access_key": BASE64.encode([1u8; 32]),
+    });
+
+    let (status, body) = app.request(Method::PUT, "/v1/profile/versions/01", Some(&first), profile(Some(&avatar))).await;
+    assert_eq!(status, StatusCode::OK);
+    let key = format!("profile-avatars/{}", body["avatar_hash"].as_str().unwrap());
+    let (status, _) = app.request(Method::PUT, "/v1/profile/versions/01", Some(&second), profile(Some(&avatar))).await;
+    assert_eq!(status, StatusCode::OK);
+
+    app.request(Method::PUT, "/v1/profile/versions/02", Some(&first), profile(None)).await;
+    assert!(app.state.storage.exists(&key).await.unwrap());
+
+    app.request(Method::PUT, "/v1/profile/versions/02", Some(&second), profile(None)).await;
+    assert!(!app.state.storage.exists(&key).await.unwrap());
+
+    app.delete_user(&first_phone);
+    app.delete_user(&second_phone);
+}
+
+#[tokio::test]
+async fn plaintext_profile_routes_are_gone() {
+    let Some(app) = TestApp::new() else { return };
+    let (phone, user_id, token) = app.login().await;
+
+    let (status, _) = app.request(Method::PUT, "/v1/profile/name", Some(&token), json!({"name": "Alice"})).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) = app.request(Method::GET, &format!("/v1/profile/{user_id}"), Some(&token), Value::Null).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    app.delete_user(&phone);
+}
+
+#[tokio::test]
+async fn only_the_recipient_can_report_a_message() {
+    let Some(app) = TestApp::new() else { return };
+    let (sender_phone, sender_id, sender) = app.login().await;
+    let (recipient_phone, recipient_id, recipient) = app.login().await;
+    let (other_phone, _, other) = app.login().await;
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(b"franking key").unwrap();
+    mac.update(b"hello");
+    let commitment = mac.finalize().into_bytes();
+
+    let mut conn = app.state.db.get().unwrap();
+    let device_id = app.device_of(recipient_id);
+    let (status, _) = app.request(Method::POST, "/v1/messages", Some(&sender), json!({
+        "recipient_user_id": recipient_id,
+        "messages": [{
+            "device_id": device_id,
+            "ciphertext": BASE64.encode("ciphertext"),
+            "message_type": 1,
+            "franking_commitment": BASE64.encode(commitment),
+        }],
+    })).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (tag, created_at) = messages::table
+        .filter(messages::recipient_device_id.eq(device_id))
+        .select((messages::franking_tag, messages::created_at))
+        .first::<(Option<Vec<u8>>, Option<chrono::NaiveDateTime>)>(&mut conn)
+        .unwrap();
+    let report = json!({
+        "sender_user_id": sender_id,
+        "timestamp": created_at.unwrap().and_utc().timestamp_millis(),
+        "franking_commitment": BASE64.encode(commitment),
+        "franking_tag": BASE64.encode(tag.unwrap()),
+        "franking_key": BASE64.encode("franking key"),
+        "plaintext": BASE64.encode("hello"),
+    });
+
+    let (status, _) = app.request(Method::POST, "/v1/reports", Some(&other), report.clone()).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    let (status, _) = app.request(Method::POST, "/v1/reports", Some(&recipient), report).await;
+    assert_eq!(status, StatusCode::OK);
+
+    for phone in [sender_phone, recipient_phone, other_phone] {
+        app.delete_user(&phone);
+    }
+}
+
+#[tokio::test]
+async fn delivered_group_payloads_are_pruned() {
+    let Some(app) = TestApp::new() else { return };
+    let (sender_phone, _, sender) = app.login().await;
+    let (member_phone, member_id, member) = app.login().await;
+
+    let (status, body) = app.request(Method::POST, "/v1/groups", Some(&sender), json!({
+        "state": BASE64.encode("state"),
+        "members": [member_id],
+    })).await;
+    assert_eq!(status, StatusCode::OK);
+    let group_id = body["group_id"].as_str().unwrap().to_string();
+    let (status, _) = app.request(Method::POST, &format!("/v1/groups/{group_id}/messages"), Some(&sender), json!({
+        "ciphertext": BASE64.encode("ciphertext"),
+        "headers": [{"device_id": app.device_of(member_id), "header": BASE64.encode("header"), "message_type": 1}],
+    })).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let mut conn = app.state.db.get().unwrap();
+    let payloads = |conn: &mut PgConnection| group_payloads::table
+        .filter(group_payloads::group_id.eq(group_id.parse::<Uuid>().unwrap()))
+        .count()
+        .get_result::<i64>(conn)
+        .unwrap();
+    groups::prune_delivered_payloads(&mut conn).unwrap();
+    assert_eq!(payloads(&mut conn), 1);
+
+    let (_, body) = app.request(Method::GET, "/v1/messages", Some(&member), Value::Null).await;
+    let ids: Vec<i64> = body.as_array().unwrap().iter().map(|m| m["id"].as_i64().unwrap()).collect();
+    assert!(body.as_array().unwrap().iter().any(|m| m["group_payload"].is_object()));
+    let (status, _) = app.request(Method::POST, "/v1/messages/ack", Some(&member), json!({"message_ids": ids})).await;
+    assert_eq!(status, StatusCode::OK);
+
+    groups::prune_delivered_payloads(&mut conn).unwrap();
+    assert_eq!(payloads(&mut conn), 0);
+
+    app.delete_user(&sender_phone);
+    app.delete_user(&member_phone);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn channel_posts_are_paged_by_sequence() {
+    let Some(app) = TestApp::new() else { return };
+    let app = Arc::new(app);
+    let (phone, _, token) = app.login().await;
+
+    let (status, body) = app.request(Method::POST, "/v1/channels", Some(&token), json!({
+        "state": BASE64.encode("state"),
+    })).await;
+    assert_eq!(status, StatusCode::OK);
+    let posts_path = format!("/v1/channels/{}/posts", body["channel_id"].as_str().unwrap());
+
+    let tasks: Vec<_> = (0..8).map(|i| {
+        let (app, token, posts_path) = (app.clone(), token.clone(), posts_path.clone());
+        tokio::spawn(async move {
+            let (status, body) = app.request(Method::POST, &posts_path, Some(&token), json!({
+                "ciphertext": BASE64.encode(format!("post {i}")),
+            })).await;
+            assert_eq!(status, StatusCode::OK);
+            body["post_id"].as_i64().unwrap()
+        })
+    }).collect();
+    let mut post_ids = Vec::new();
+    for task in tasks {
+        post_ids.push(task.await.unwrap());
+    }
+
+    let (mut seqs, mut paged_ids, mut cursor) = (Vec::new(), Vec::new(), None::<i64>);
+    loop {
+        let path = match cursor {
+            Some(after) => format!("{posts_path}?limit=3&after={after}"),
+            None => format!("{posts_path}?limit=3"),
+        };
+        let (status, body) = app.request(Method::GET, &path, Some(&token), Value::Null).await;
+        assert_eq!(status, StatusCode::OK);
+        let page = body["data"].as_array().unwrap();
+        if page.is_empty() {
+            break;
+        }
+        seqs.extend(page.iter().map(|p| p["seq"].as_i64().unwrap()));
+        paged_ids.extend(page.iter().map(|p| p["id"].as_i64().unwrap()));
+        cursor = body["next_cursor"].as_i64();
+    }
+
+    assert_eq!(seqs, (1..=8).collect::<Vec<_>>());
+    post_ids.sort();
+    paged_ids.sort();
+    assert_eq!(paged_ids, post_ids);
+
+    app.delete_user(&phone);
+}
+
+#[tokio::test]
+async fn staged_uploads_are_removed_with_their_row() {
+    let Some(app) = TestApp::new() else { return };
+    let (phone, _, token) = app.login().await;
+
+    let (status, body) = app.request(Method::POST, "/v1/attachments/uploads", Some(&token), json!({"size": 10})).await;
+    assert_eq!(status, StatusCode::OK);
+    let upload_id: Uuid = body["upload_id"].as_str().unwrap().parse().unwrap();
+    app.state.uploads.write_at(upload_id, 0, b"chunk").unwrap();
+    let staged = app.dir().join("uploads").join(upload_id.to_string());
+    assert!(staged.exists());
+
+    let (status, _) = app.request(Method::POST, "/v1/account/delete", Some(&token), Value::Null).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    let otp = app.otp.last_code(&phone).unwrap();
+    let (status, _) = app.request(Method::DELETE, "/v1/account", Some(&token), json!({"otp": otp})).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(!staged.exists());
+
+    // Files left behind without a row, e.g. by a crash, are purged once they are old enough.
+    let orphan_id = Uuid::new_v4();
+    app.state.uploads.write_at(orphan_id, 0, b"chunk").unwrap();
+    let orphan = app.dir().join("uploads").join(orphan_id.to_string());
+    let mut conn = app.state.db.get().unwrap();
+    app.state.uploads.purge_expired(&mut conn).unwrap();
+    assert!(orphan.exists());
+    std::fs::File::options()
+        .write(true)
+        .open(&orphan)
+        .unwrap()
+        .set_modified(std::time::SystemTime::now() - Duration::from_secs(2 * 3600))
+        .unwrap();
+    app.state.uploads.purge_expired(&mut conn).unwrap();
+    assert!(!orphan.exists());
+}
+
+/// Local store that pretends to presign uploads, which the test then writes directly.
+struct PresigningStore(LocalBlobStore);
+
+#[async_trait::async_trait]
+impl BlobStore for PresigningStore {
+    async fn put(&self, key: &str, data: Vec<u8>) -> Result<(), StorageError> {
+        self.0.put(key, data).await
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, StorageError> {
+        self.0.get(key).await
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, StorageError> {
+        self.0.exists(key).await
+    }
+
+    async fn size(&self, key: &str) -> Result<Option<u64>, StorageError> {
+        self.0.size(key).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        self.0.delete(key).await
+    }
+
+    fn presigned_put_url(&self, key: &str, _size: u64) -> Result<Option<String>, StorageError> {
+        Ok(Some(format!("https://storage.test/{key}")))
+    }
+}
+
+#[tokio::test]
+async fn presigned_uploads_are_usable_once_confirmed() {
+    let Some(app) = TestApp::new() else { return };
+    let root = app.dir().join("presigned");
+    let app = app.with_state(|state| state.storage = Arc::new(PresigningStore(LocalBlobStore::new(&root))));
+    let (phone, _, token) = app.login().await;
+
+    let presign = async || {
+        let (status, body) = app.request(Method::POST, "/v1/attachments/presigned", Some(&token), json!({"size": 10})).await;
+        assert_eq!(status, StatusCode::OK);
+        let id: Uuid = body["attachment_id"].as_str().unwrap().parse().unwrap();
+        (id, body["download_token"].as_str().unwrap().to_string())
+    };
+    let download = async |id: Uuid, download_token: &str| {
+        let request = Request::get(format!("/v1/attachments/{id}"))
+            .header("Authorization", format!("Bearer {token}"))
+            .header("X-Attachment-Token", download_token)
+            .body(Body::empty())
+            .unwrap();
+        app.router.clone().oneshot(request).await.unwrap().status()
+    };
+
+    // A blob of another size is discarded along with its attachment.
+    let (id, _) = presign().await;
+    app.state.storage.put(&attachment_key(id), b"too short".to_vec()).await.unwrap();
+    let (status, _) = app.request(Method::POST, &format!("/v1/attachments/{id}/confirm"), Some(&token), Value::Null).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert!(!app.state.storage.exists(&attachment_key(id)).await.unwrap());
+
+    let (id, download_token) = presign().await;
+    let mut conn = app.state.db.get().unwrap();
+    assert_eq!(download(id, &download_token).await, StatusCode::NOT_FOUND);
+    assert!(!attachments::lock_all(&mut conn, &[id]).unwrap());
+    let (status, _) = app.request(Method::POST, &format!("/v1/attachments/{id}/confirm"), Some(&token), Value::Null).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+
+    app.state.storage.put(&attachment_key(id), b"ciphertext".to_vec()).await.unwrap();
+    let (status, _) = app.request(Method::POST, &format!("/v1/attachments/{id}/confirm"), Some(&token), Value::Null).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(download(id, &download_token).await, StatusCode::OK);
+    assert!(attachments::lock_all(&mut conn, &[id]).unwrap());
+
+    // Unconfirmed uploads expire after an hour.
+    let (unconfirmed, _) = presign().await;
+    let expired = || attachments::expired_attachments(&mut app.state.db.get().unwrap(), chrono::Duration::days(30)).unwrap();
+    assert!(!expired().contains(&unconfirmed));
+    diesel::update(schema::attachments::table.find(unconfirmed))
+        .set(schema::attachments::created_at.eq(chrono::Utc::now() - chrono::Duration::hours(2)))
+        .execute(&mut conn)
+        .unwrap();
+    assert!(expired().contains(&unconfirmed));
+    assert!(!expired().contains(&id));
+
+    diesel::delete(schema::attachments::table.filter(schema::attachments::id.eq_any([id, unconfirmed])))
+        .execute(&mut conn)
+        .unwrap();
+    app.delete_user(&phone);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_uploads_cant_overrun_the_quota() {
+    let Some(app) = TestApp::new() else { return };
+    let app = Arc::new(app.with_state(|state| state.storage_quota = 30));
+    let (phone, user_id, token) = app.login().await;
+
+    // Each body is the 12 bytes of a JSON string, so only two fit.
+    let tasks: Vec<_> = (0..6).map(|_| {
+        let (app, token) = (app.clone(), token.clone());
+        tokio::spawn(async move {
+            app.request(Method::POST, "/v1/attachments", Some(&token), json!("0123456789")).await.0
+        })
+    }).collect();
+    let mut stored = 0;
+    for task in tasks {
+        match task.await.unwrap() {
+            StatusCode::OK => stored += 1,
+            status => assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE),
+        }
+    }
+    assert_eq!(stored, 2);
+
+    let mut conn = app.state.db.get().unwrap();
+    diesel::delete(schema::attachments::table.filter(schema::attachments::uploader_user_id.eq(user_id)))
+        .execute(&mut conn)
+        .unwrap();
+    app.delete_user(&phone);
+}
+
+#[tokio::test]
+async fn attachments_being_sent_are_not_swept() {
+    let Some(app) = TestApp::new() else { return };
+    let retention = chrono::Duration::days(30);
+    let insert_expired = |conn: &mut PgConnection| diesel::insert_into(schema::attachments::table)
+        .values((
+            schema::attachments::size.eq(10),
+            schema::attachments::download_token_hash.eq(vec![0u8; 32]),
+            schema::attachments::created_at.eq(chrono::Utc::now() - chrono::Duration::days(31)),
+        ))
+        .returning(schema::attachments::id)
+        .get_result::<Uuid>(conn)
+        .unwrap();
+
+    let mut sender = app.state.db.get().unwrap();
+    let mut sweeper = app.state.db.get().unwrap();
+    let (locked, free) = (insert_expired(&mut sender), insert_expired(&mut sender));
+    for id in [locked, free] {
+        app.state.storage.put(&attachment_key(id), b"ciphertext".to_vec()).await.unwrap();
+    }
+
+    // A sender holds its attachment until its messages are linked, so the sweeper skips it.
+    sender.transaction::<_, diesel::result::Error, _>(|sender| {
+        assert!(attachments::lock_all(sender, &[locked])?);
+        let deleted = attachments::delete_expired(&mut sweeper, retention)?;
+        assert!(deleted.contains(&free));
+        assert!(!deleted.contains(&locked));
+        Ok(())
+    }).unwrap();
+    assert!(!attachments::lock_all(&mut sender, &[free]).unwrap());
+
+    drop((sender, sweeper));
+    attachments::sweep(&app.state.db, app.state.storage.as_ref(), retention).await.unwrap();
+    assert!(!attachments::lock_all(&mut app.state.db.get().unwrap(), &[locked]).unwrap());
+    assert!(!app.state.storage.exists(&attachment_key(locked)).await.unwrap());
+}
+
+#[tokio::test]
+async fn system_messages_wake_their_recipients() {
+    let Some(app) = TestApp::new() else { return };
+    let (phone, deleted_id, token) = app.login().await;
+    let (contact_phone, contact_id, contact) = app.login().await;
+    let push_token = format!("token-{contact_id}");
+    let (status, _) = app.request(Method::PUT, "/v1/devices/push", Some(&contact), json!({
+        "platform": "fcm",
+        "token": push_token,
+    })).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let mut conn = app.state.db.get().unwrap();
+    diesel::insert_into(accepted_conversations::table)
+        .values((
+            accepted_conversations::user_id.eq(contact_id),
+            accepted_conversations::peer_user_id.eq(deleted_id),
+        ))
+        .execute(&mut conn)
+        .unwrap();
+
+    let (status, _) = app.request(Method::POST, "/v1/account/delete", Some(&token), Value::Null).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    let otp = app.otp.last_code(&phone).unwrap();
+    let (status, _) = app.request(Method::DELETE, "/v1/account", Some(&token), json!({"otp": otp})).await;
+    assert_eq!(status, StatusCode::OK);
+
+    for _ in 0..100 {
+        if app.push.sent().contains(&push_token) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(app.push.sent().contains(&push_token));
+
+    app.delete_user(&contact_phone);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn wake_ups_are_coalesced_and_dropped_once_fetched() {
+    let debounce = Duration::from_millis(300);
+    let Some(app) = TestApp::with_debounce(debounce) else { return };
+    let (sender_phone, sender_id, sender) = app.login().await;
+    let (recipient_phone, recipient_id, recipient) = app.login().await;
+    let push_token = format!("token-{recipient_id}");
+    let (status, _) = app.request(Method::PUT, "/v1/devices/push", Some(&recipient), json!({
+        "platform": "fcm",
+        "token": push_token,
+    })).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let mut conn = app.state.db.get().unwrap();
+    diesel::insert_into(accepted_conversations::table)
+        .values((
+            accepted_conversations::user_id.eq(recipient_id),
+            accepted_conversations::peer_user_id.eq(sender_id),
+        ))
+        .execute(&mut conn)
+        .unwrap();
+
+    let device_id = app.device_of(recipient_id);
+    let send = async || {
+        let (status, _) = app.request(Method::POST, "/v1/messages", Some(&sender), json!({
+            "recipient_user_id": recipient_id,
+            "messages": [{
+                "device_id": device_id,
+                "ciphertext": BASE64.encode("ciphertext"),
+                "message_type": 1,
+            }],
+        })).await;
+        assert_eq!(status, StatusCode::OK);
+    };
+    let pushes = || app.push.sent().iter().filter(|token| **token == push_token).count();
+
+    for _ in 0..3 {
+        send().await;
+    }
+    tokio::time::sleep(debounce * 3).await;
+    assert_eq!(pushes(), 1);
+
+    // Fetching within the window drops the wake-up it would have sent.
+    let (status, _) = app.request(Method::GET, "/v1/messages", Some(&recipient), Value::Null).await;
+    assert_eq!(status, StatusCode::OK);
+    send().await;
+    let (status, _) = app.request(Method::GET, "/v1/messages", Some(&recipient), Value::Null).await;
+    assert_eq!(status, StatusCode::OK);
+    tokio::time::sleep(debounce * 3).await;
+    assert_eq!(pushes(), 1);
+
+    send().await;
+    tokio::time::sleep(debounce * 3).await;
+    assert_eq!(pushes(), 2);
+
+    app.delete_user(&sender_phone);
+    app.delete_user(&recipient_phone);
+}
+
+#[tokio::test]
+async fn leaving_a_group_wakes_the_remaining_members() {
+    let Some(app) = TestApp::new() else { return };
+    let (owner_phone, _, owner) = app.login().await;
+    let (member_phone, member_id, member) = app.login().await;
+
+    let (status, body) = app.request(Method::POST, "/v1/groups", Some(&owner), json!({
+        "state": BASE64.encode("state"),
+        "members": [member_id],
+    })).await;
+    assert_eq!(status, StatusCode::OK);
+    let group_id = body["group_id"].as_str().unwrap().to_string();
+    // Let the wake-up of the group's creation pass before the member can be pushed.
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let push_token = format!("token-{member_id}");
+    let (status, _) = app.request(Method::PUT, "/v1/devices/push", Some(&member), json!({
+        "platform": "fcm",
+        "token": push_token,
+    })).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, body) = app.request(Method::POST, &format!("/v1/groups/{group_id}/leave"), Some(&owner), json!({
+        "state": BASE64.encode("state after leaving"),
+        "version": 1,
+    })).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    assert_eq!(body["version"], 2);
+
+    for _ in 0..100 {
+        if app.push.sent().contains(&push_token) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(app.push.sent().contains(&push_token));
+
+    app.delete_user(&owner_phone);
+    app.delete_user(&member_phone);
+}
+
+#[tokio::test]
+async fn discovery_batches_must_fit_the_quota() {
+    let Some(app) = TestApp::new() else { return };
+    let app = app.with_state(|state| state.discovery_quota = 3);
+    let (phone, _, token) = app.login().await;
+    let hashes = |n: u8| (0..n).map(|i| BASE64.encode([i; 10])).collect::<Vec<_>>();
+
+    let (status, _) = app.request(Method::POST, "/v1/contacts/discover", Some(&token), json!({"hashes": hashes(4)})).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = app.request(Method::POST, "/v1/contacts/discover", Some(&token), json!({"hashes": hashes(3)})).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = app.request(Method::POST, "/v1/contacts/discover", Some(&token), json!({"hashes": hashes(1)})).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+
+    app.delete_user(&phone);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_requests_cant_overrun_the_pending_cap() {
+    let Some(app) = TestApp::new() else { return };
+    let app = Arc::new(app);
+    let (sender_phone, _, sender) = app.login().await;
+    let (recipient_phone, recipient_id, _) = app.login().await;
+    let device_id = app.device_of(recipient_id);
+
+    let tasks: Vec<_> = (0..12).map(|_| {
+        let (app, sender) = (app.clone(), sender.clone());
+        tokio::spawn(async move {
+            app.request(Method::POST, "/v1/messages", Some(&sender), json!({
+                "recipient_user_id": recipient_id,
+                "messages": [{
+                    "device_id": device_id,
+                    "ciphertext": BASE64.encode("ciphertext"),
+                    "message_type": 1,
+                }],
+            })).await.0
+        })
+    }).collect();
+    let mut sent = 0;
+    for task in tasks {
+        match task.await.unwrap() {
+            StatusCode::OK => sent += 1,
+            status => assert_eq!(status, StatusCode::TOO_MANY_REQUESTS),
+        }
+    }
+    assert_eq!(sent, 5);
+
+    let mut conn = app.state.db.get().unwrap();
+    let pending = messages::table
+        .filter(messages::recipient_device_id.eq(device_id))
+        .filter(messages::is_request.eq(true))
+        .count()
+        .get_result::<i64>(&mut conn)
+        .unwrap();
+    assert_eq!(pending, 5);
+
+    app.delete_user(&sender_phone);
+    app.delete_user(&recipient_phone);
+}
+
+#[tokio::test]
+async fn changing_the_username_deletes_its_link() {
+    let Some(app) = TestApp::new() else { return };
+    let (phone, _, token) = app.login().await;
+    let set_username = async |nickname: &str| {
+        let (status, body) = app.request(Method::POST, "/v1/username/reserve", Some(&token), json!({"nickname": nickname})).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let (status, body) = app.request(Method::POST, "/v1/username/confirm", Some(&token), json!({"username": body["username"]})).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+    };
+
+    set_username("first").await;
+    let (status, body) = app.request(Method::PUT, "/v1/username/link", Some(&token), json!({
+        "encrypted_username": BASE64.encode("encrypted first"),
+    })).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    let link = format!("/v1/username/link/{}", body["handle"].as_str().unwrap());
+    let (status, _) = app.request(Method::GET, &link, Some(&token), Value::Null).await;
+    assert_eq!(status, StatusCode::OK);
+
+    set_username("second").await;
+    let (status, _) = app.request(Method::GET, &link, Some(&token), Value::Null).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    app.delete_user(&phone);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn failed_attempts_outlive_resent_codes() {
+    let Some(app) = TestApp::new() else { return };
+    let app = Arc::new(app);
+    let phone = random_phone();
+    let (status, _) = app.request(Method::POST, "/v1/register", None, json!({"phone_number": phone})).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+
+    // Parallel guesses are each counted.
+    let tasks: Vec<_> = (0..3).map(|_| {
+        let (app, phone) = (app.clone(), phone.clone());
+        tokio::spawn(async move {
+            app.request(Method::POST, "/v1/register/confirm", None, json!({"phone_number": phone, "otp": "000000"})).await.0
+        })
+    }).collect();
+    for task in tasks {
+        assert_eq!(task.await.unwrap(), StatusCode::UNAUTHORIZED);
+    }
+
+    let (status, _) = app.request(Method::POST, "/v1/register", None, json!({"phone_number": phone})).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    for _ in 0..2 {
+        let (status, _) = app.request(Method::POST, "/v1/register/confirm", None, json!({"phone_number": phone, "otp": "000000"})).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    let otp = app.otp.last_code(&phone).unwrap();
+    let (status, _) = app.request(Method::POST, "/v1/register/confirm", None, json!({"phone_number": phone, "otp": otp})).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let mut conn = app.state.db.get().unwrap();
+    diesel::delete(schema::verification_codes::table.filter(schema::verification_codes::phone_number.eq(&phone)))
+        .execute(&mut conn)
+        .unwrap();
+}
+
+#[tokio::test]
+async fn plaintext_avatars_are_deleted() {
+    let Some(app) = TestApp::new() else { return };
+    let hash = format!("{:x}", Sha256::digest(Uuid::new_v4().as_bytes()));
+    let key = format!("avatars/{hash}");
+    app.state.storage.put(&key, b"plaintext avatar".to_vec()).await.unwrap();
+    let mut conn = app.state.db.get().unwrap();
+    diesel::insert_into(schema::legacy_avatars::table)
+        .values(schema::legacy_avatars::hash.eq(&hash))
+        .execute(&mut conn)
+        .unwrap();
+
+    let deleted = delete_legacy_avatars(&app.state.db, app.state.storage.as_ref()).await.unwrap();
+    assert!(deleted >= 1);
+    assert!(!app.state.storage.exists(&key).await.unwrap());
+    let remaining = schema::legacy_avatars::table
+        .find(&hash)
+        .count()
+        .get_result::<i64>(&mut conn)
+        .unwrap();
+    assert_eq!(remaining, 0);
+}
@@ -0,0 +1,106 @@
+use crate::schema::attachment_uploads;
+use chrono::Utc;
+use std::collections::HashSet;
+use diesel::prelude::*;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+use uuid::Uuid;
+
+/// Local directory holding resumable uploads until they are finalized into the blob store.
+///
+/// Staged chunks are only visible to the instance that wrote them, so deployments with several
+/// instances must share the directory (e.g. over NFS) or route every request of an upload to the
+/// same instance.
+pub struct UploadStaging {
+    root: PathBuf,
+}
+
+impl UploadStaging {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    /// Reads `ATTACHMENT_STAGING_PATH`, defaulting to `data/uploads`.
+    pub fn from_env() -> Self {
+        Self::new(std::env::var("ATTACHMENT_STAGING_PATH").unwrap_or_else(|_| "data/uploads".into()))
+    }
+
+    fn path(&self, id: Uuid) -> PathBuf {
+        self.root.join(id.to_string())
+    }
+
+    /// Writes `data` at `offset`, so a chunk retried after a lost response overwrites itself.
+    pub fn write_at(&self, id: Uuid, offset: u64, data: &[u8]) -> std::io::Result<()> {
+        std::fs::create_dir_all(&self.root)?;
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.path(id))?;
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(data)
+    }
+
+    pub fn read(&self, id: Uuid) -> std::io::Result<Vec<u8>> {
+        std::fs::read(self.path(id))
+    }
+
+    /// Removing a missing upload is not an error.
+    pub fn remove(&self, id: Uuid) -> std::io::Result<()> {
+        match std::fs::remove_file(self.path(id)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Deletes the uploads that expired before being finalized. Returns how many were deleted.
+    pub fn purge_expired(&self, conn: &mut PgConnection) -> QueryResult<usize> {
+        let expired = diesel::delete(attachment_uploads::table.filter(attachment_uploads::expires_at.lt(Utc::now())))
+            .returning(attachment_uploads::id)
+            .get_results::<Uuid>(conn)?;
+
+        for id in &expired {
+            if let Err(e) = self.remove(*id) {
+                tracing::error!("Failed to remove staged upload {id}: {e}");
+            }
+        }
+        self.purge_orphans(conn)?;
+        Ok(expired.len())
+    }
+
+    /// Deletes the staged files whose upload row is gone, e.g. cascaded with a deleted user.
+    /// Recently written files are kept, as a finalize may still be reading them.
+    fn purge_orphans(&self, conn: &mut PgConnection) -> QueryResult<()> {
+        let entries = match std::fs::read_dir(&self.root) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => {
+                tracing::error!("Failed to list staged uploads: {e}");
+                return Ok(());
+            }
+        };
+        let cutoff = SystemTime::now() - Duration::from_secs(3600);
+        let stale: Vec<Uuid> = entries
+            .filter_map(Result::ok)
+            .filter(|entry| entry.metadata().and_then(|m| m.modified()).is_ok_and(|t| t < cutoff))
+            .filter_map(|entry| entry.file_name().to_str()?.parse().ok())
+            .collect();
+        if stale.is_empty() {
+            return Ok(());
+        }
+
+        let live: HashSet<Uuid> = attachment_uploads::table
+            .filter(attachment_uploads::id.eq_any(&stale))
+            .select(attachment_uploads::id)
+            .load(conn)?
+            .into_iter()
+            .collect();
+        for id in stale.into_iter().filter(|id| !live.contains(id)) {
+            if let Err(e) = self.remove(id) {
+                tracing::error!("Failed to remove staged upload {id}: {e}");
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,48 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+pub const MIN_NICKNAME_LENGTH: usize = 3;
+pub const MAX_NICKNAME_LENGTH: usize = 32;
+
+/// Returns the canonical (lowercase) form of a nickname, or `None` if it isn't
+/// 3 to 32 ASCII letters, digits or underscores starting with a letter.
+pub fn normalize_nickname(nickname: &str) -> Option<String> {
+    let nickname = nickname.trim().to_ascii_lowercase();
+
+    if !(MIN_NICKNAME_LENGTH..=MAX_NICKNAME_LENGTH).contains(&nickname.len()) {
+        return None;
+    }
+    if !nickname.starts_with(|c: char| c.is_ascii_lowercase()) {
+        return None;
+    }
+    if !nickname.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_') {
+        return None;
+    }
+
+    Some(nickname)
+}
+
+/// Returns the canonical form of a full `nickname.discriminator` username, or `None` if invalid.
+///
+/// Discriminators are at least two digits, and `00` is never handed out.
+pub fn normalize_username(username: &str) -> Option<String> {
+    let (nickname, discriminator) = username.trim().rsplit_once('.')?;
+    let nickname = normalize_nickname(nickname)?;
+
+    if discriminator.len() < 2
+        || discriminator.len() > 9
+        || !discriminator.chars().all(|c| c.is_ascii_digit())
+        || discriminator.chars().all(|c| c == '0')
+    {
+        return None;
+    }
+
+    Some(format!("{nickname}.{discriminator}"))
+}
+
+/// Keyed hash stored in place of the username.
+pub fn hash_username(secret: &[u8], username: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(username.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
@@ -0,0 +1,186 @@
+use crate::AppState;
+use argon2::{Argon2, PasswordHasher};
+use axum::http::StatusCode;
+use axum::Json;
+use chrono::{Duration, Utc};
+use diesel::dsl::sql;
+use diesel::prelude::*;
+use diesel::sql_types::{Integer, Nullable};
+use e2ee_back::pow::PowError;
+use e2ee_back::schema::verification_codes;
+use password_hash::{PasswordHash, PasswordVerifier, SaltString};
+use rand::Rng;
+use serde_json::{json, Value};
+use std::net::IpAddr;
+
+/// Action a code is requested for; a code only confirms the action it was sent for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CodePurpose {
+    Register,
+    DeleteAccount,
+    ChangePhone,
+}
+
+impl CodePurpose {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Register => "register",
+            Self::DeleteAccount => "delete_account",
+            Self::ChangePhone => "change_phone",
+        }
+    }
+}
+
+/// Counts a code request for `phone` from `ip` and, once it is abusive enough to need one, checks
+/// the proof of work solving a challenge from `/v1/register/challenge`.
+pub fn require_pow(
+    state: &AppState,
+    phone: &str,
+    ip: Option<IpAddr>,
+    challenge: Option<&str>,
+    solution: Option<&str>,
+) -> Result<(), (StatusCode, Json<Value>)> {
+    state.pow.record_request(phone, ip);
+    let difficulty = state.pow.difficulty_for(phone, ip);
+    if difficulty == 0 {
+        return Ok(());
+    }
+
+    let (Some(challenge), Some(solution)) = (challenge, solution) else {
+        return Err((StatusCode::PRECONDITION_REQUIRED, Json(json!({
+            "message": "Proof of work required",
+            "status": 428,
+            "difficulty": difficulty,
+        }))));
+    };
+
+    state.pow.verify(challenge, solution, phone, difficulty).map_err(|e| {
+        let message = match e {
+            PowError::Expired => "Challenge expired",
+            PowError::DifficultyTooLow => "Challenge difficulty too low",
+            PowError::InvalidChallenge | PowError::InvalidSolution => "Invalid proof of work",
+        };
+        (StatusCode::PRECONDITION_REQUIRED, Json(json!({
+            "message": message,
+            "status": 428,
+            "difficulty": difficulty,
+        })))
+    })
+}
+
+/// Generates a new code for `phone` and `purpose`, replacing any pending one, and sends it through
+/// the OTP sender.
+pub async fn send_code(state: &AppState, phone: &str, purpose: CodePurpose) -> Result<(), (StatusCode, Json<Value>)> {
+    let otp = rand::rng().random_range(100000..999999);
+
+    let hasher = Argon2::default();
+    let hashed = hasher
+        .hash_password(otp.to_string().as_bytes(), &SaltString::generate())
+        .unwrap()
+        .to_string();
+    let exp = Utc::now() + Duration::minutes(5);
+    let mut conn = state.db.get().unwrap();
+
+    diesel::insert_into(verification_codes::table)
+        .values((
+            verification_codes::phone_number.eq(phone),
+            verification_codes::code_hash.eq(&hashed),
+            verification_codes::expires_at.eq(exp),
+            verification_codes::attempt_count.eq(0),
+            verification_codes::purpose.eq(purpose.as_str()),
+        ))
+        .on_conflict((verification_codes::phone_number, verification_codes::purpose))
+        .do_update()
+        .set((
+            verification_codes::code_hash.eq(&hashed),
+            verification_codes::expires_at.eq(exp),
+            // Failed attempts carry over to the new code unless the previous one expired, so
+            // requesting codes doesn't buy more guesses.
+            verification_codes::attempt_count.eq(sql::<Nullable<Integer>>(
+                "CASE WHEN verification_codes.expires_at < now() THEN 0 ELSE verification_codes.attempt_count END",
+            )),
+        ))
+        .execute(&mut conn)
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({
+            "message": "Something went wrong",
+            "status": 500,
+        }))))?;
+
+    if let Err(e) = state.otp_sender.send(phone, &otp.to_string()).await {
+        tracing::error!("Failed to send OTP code: {e}");
+        return Err((StatusCode::BAD_GATEWAY, Json(json!({
+            "message": "Failed to send verification code",
+            "status": 502,
+        }))));
+    }
+
+    Ok(())
+}
+
+/// Checks `otp` against the pending code of `phone` for `purpose`, counting every attempt.
+///
+/// The code is consumed on success.
+pub fn check_code(
+    conn: &mut PgConnection,
+    phone: &str,
+    purpose: CodePurpose,
+    otp: &str,
+) -> Result<(), (StatusCode, Json<Value>)> {
+    let code = verification_codes::table
+        .filter(verification_codes::phone_number.eq(phone))
+        .filter(verification_codes::purpose.eq(purpose.as_str()));
+    // Every attempt is counted before the code is checked, so parallel guesses can't share one.
+    let entry = match diesel::update(code.filter(verification_codes::expires_at.gt(Utc::now())))
+        .set(verification_codes::attempt_count.eq(sql::<Nullable<Integer>>("COALESCE(attempt_count, 0) + 1")))
+        .returning((verification_codes::code_hash, verification_codes::attempt_count))
+        .get_result::<(String, Option<i32>)>(conn)
+        .optional()
+    {
+        Ok(e) => e,
+        Err(_) => {
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, Json(json!({
+                "message": "Something went wrong",
+                "status": 500,
+            }))))
+        }
+    };
+
+    let Some((code_hash_db, attempts)) = entry else {
+        return Err((StatusCode::UNAUTHORIZED, Json(json!({
+            "message": "Unauthorized",
+            "status": 401,
+        }))));
+    };
+
+    if attempts.unwrap_or(0) > 5 {
+        return Err((StatusCode::FORBIDDEN, Json(json!({
+            "message": "Your account has been blocked for security reasons, please retry later.",
+            "status": 403,
+        }))));
+    }
+
+    let parsed = match PasswordHash::new(&code_hash_db) {
+        Ok(p) => p,
+        Err(_) => {
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, Json(json!({
+                "message": "Internal Server Error",
+                "status": 500,
+            }))))
+        }
+    };
+
+    if Argon2::default()
+        .verify_password(otp.as_bytes(), &parsed)
+        .is_err() {
+        return Err((StatusCode::UNAUTHORIZED, Json(json!({
+            "message": "Unauthorized",
+            "status": 401,
+        }))));
+    }
+
+    diesel::delete(code)
+        .execute(conn)
+        .unwrap();
+
+    Ok(())
+}